@@ -0,0 +1,388 @@
+//! Reads the command line flags into `Options`
+
+use super::{
+    get_random_seed, log, parse_duration, parse_list, parse_signal, should_use_color, ColorChoice,
+    MatchPolicy, Options,
+};
+use std::io::IsTerminal;
+
+/// Reads the command line flags
+pub fn parse_args(args: Vec<String>) -> Options {
+    let mut options = Options::default();
+
+    let mut color = ColorChoice::Auto;
+
+    let mut explicit_jobs = None;
+    let mut concurrency_auto = false;
+
+    let mut args = args.into_iter().peekable();
+
+    while let Some(arg) = args.next() {
+        // Everything after -- is passed on to the tasks
+        if arg == "--" {
+            options.task_args = args.by_ref().collect();
+            break;
+        }
+
+        if arg.starts_with("-powershell") {
+            options.use_powershell = true;
+        }
+
+        if arg.starts_with("-async") {
+            options.is_async = true;
+        }
+
+        if arg == "--shuffle" {
+            // The seed is optional, so only consume the next argument if it is a number
+            let seed = match args.peek().and_then(|a| a.parse().ok()) {
+                Some(seed) => {
+                    args.next();
+                    seed
+                }
+                None => get_random_seed(),
+            };
+
+            options.shuffle_seed = Some(seed);
+        }
+
+        if arg == "--affected" && options.affected.is_none() {
+            options.affected = Some(String::from("HEAD"));
+        }
+
+        if arg == "--since" {
+            options.affected = args.next();
+        }
+
+        if arg == "--env-prefix" {
+            options.env_prefix = args.next();
+        }
+
+        if arg == "--tail-on-failure" {
+            options.tail_on_failure = args.next().and_then(|a| a.parse().ok());
+        }
+
+        if arg == "--print-duration-json" {
+            options.print_duration_json = true;
+        }
+
+        if arg == "--quiet-on-success" {
+            options.quiet_on_success = true;
+        }
+
+        // -q also hides info lines, on top of what --quiet-on-success hides
+        if arg == "-q" || arg == "--quiet" {
+            options.quiet_on_success = true;
+            options.log_level = log::Level::Quiet;
+        }
+
+        // -v -v is the same as -vv
+        if arg == "-v" || arg == "--verbose" {
+            options.log_level = match options.log_level {
+                log::Level::Verbose | log::Level::Debug => log::Level::Debug,
+                _ => log::Level::Verbose,
+            };
+        }
+
+        if arg == "-vv" {
+            options.log_level = log::Level::Debug;
+        }
+
+        if arg == "--export-script" {
+            options.export_script = args.next();
+        }
+
+        if arg == "--explicit-env" {
+            options.explicit_env = true;
+        }
+
+        if arg == "--profile-memory" {
+            options.profile_memory = true;
+        }
+
+        if arg == "--echo" {
+            options.echo = true;
+        }
+
+        if arg == "--config-dir" {
+            options.config_dir = args.next();
+        }
+
+        if arg == "--clean" {
+            options.clean = args.next();
+        }
+
+        if arg == "--clean-all" {
+            options.clean_all = true;
+        }
+
+        if arg == "--report-unused-vars" {
+            options.report_unused_vars = true;
+        }
+
+        if arg == "--strict" {
+            options.strict = true;
+        }
+
+        if arg == "--pipe" {
+            // Every following argument that isn't a flag is part of the pipeline
+            while let Some(name) = args.next_if(|a| !a.starts_with('-')) {
+                options.pipe.push(name);
+            }
+        }
+
+        if arg == "--config" {
+            options.config = args.next();
+        }
+
+        if arg == "--list" {
+            options.list = true;
+        }
+
+        // Completion scripts read the names, so nothing else is printed
+        if arg == "--list-names" {
+            options.list_names = true;
+            options.quiet_on_success = true;
+            options.log_level = log::Level::Quiet;
+        }
+
+        if arg == "--dry-run" || arg == "-n" {
+            options.dry_run = true;
+        }
+
+        if arg == "--check-programs" {
+            options.check_programs = true;
+        }
+
+        if arg == "--first-match" {
+            options.match_policy = MatchPolicy::First;
+        }
+
+        if arg == "--last-match" {
+            options.match_policy = MatchPolicy::Last;
+        }
+
+        if arg == "--watch" {
+            options.watch = true;
+        }
+
+        if arg == "--timeout" {
+            match args.next().as_deref().and_then(parse_duration) {
+                Some(timeout) => options.timeout = Some(timeout),
+                None => warn!("--timeout expects a duration like 120s"),
+            }
+        }
+
+        if arg == "--timeout-signal" {
+            match args.next().as_deref().and_then(parse_signal) {
+                Some(signal) => options.timeout_signal = signal,
+                None => warn!("--timeout-signal expects a signal like TERM or KILL"),
+            }
+        }
+
+        if arg == "--timeout-grace" {
+            match args.next().as_deref().and_then(parse_duration) {
+                Some(grace) => options.timeout_grace = grace,
+                None => warn!("--timeout-grace expects a duration like 5s"),
+            }
+        }
+
+        if arg == "--fail-on-stderr" {
+            options.fail_on_stderr = true;
+        }
+
+        if arg == "--merge-streams" {
+            options.merge_streams = true;
+        }
+
+        if arg == "--keep-going" {
+            options.keep_going = true;
+        }
+
+        if arg == "--force" {
+            options.force = true;
+        }
+
+        if arg == "--format" {
+            if let Some(format) = args.next() {
+                options.graph_format = format;
+            }
+        }
+
+        if arg == "--print-tree" {
+            options.print_tree = args.next();
+        }
+
+        if arg == "--log-file" {
+            options.transcript = args.next();
+        }
+
+        if arg == "--cache-dir" {
+            options.cache_dir = args.next();
+        }
+
+        if arg == "--client" {
+            options.client = true;
+        }
+
+        if arg == "--wait" {
+            options.wait_for_lock = true;
+        }
+
+        if arg == "--no-lock" {
+            options.no_lock = true;
+        }
+
+        if arg == "--keep-temp" {
+            options.keep_temp = true;
+        }
+
+        if arg == "--require-all-vars" {
+            options.require_all_vars = true;
+        }
+
+        if arg == "--run" {
+            if let Some(names) = args.next() {
+                options.run = parse_list(&names);
+            }
+        }
+
+        if arg == "--no-color" {
+            color = ColorChoice::Never;
+        }
+
+        if let Some(choice) = arg.strip_prefix("--color=") {
+            match choice {
+                "auto" => color = ColorChoice::Auto,
+                "always" => color = ColorChoice::Always,
+                "never" => color = ColorChoice::Never,
+                _ => warn!("--color expects auto, always or never"),
+            }
+        }
+
+        if arg == "--output" {
+            match args.next().as_deref() {
+                Some("json") => options.json_output = true,
+                Some("text") => options.json_output = false,
+                _ => warn!("--output expects json or text"),
+            }
+        }
+
+        if arg == "--concurrency-auto" {
+            concurrency_auto = true;
+        }
+
+        if arg == "--profile" {
+            options.profile = args.next();
+
+            if options.profile.is_none() {
+                warn!("--profile expects a profile name");
+            }
+        }
+
+        if arg == "--set" {
+            match args.next().as_deref().and_then(|pair| pair.split_once('=')) {
+                Some((name, value)) => {
+                    let name = format!("${}", name.trim().trim_start_matches('$'));
+                    options.overrides.push((name, String::from(value)));
+                }
+                None => warn!("--set expects NAME=value"),
+            }
+        }
+
+        if arg == "-j" || arg == "--jobs" {
+            match args.next().as_deref() {
+                Some("auto") => explicit_jobs = Some(get_cpu_count()),
+                Some(jobs) => match jobs.parse() {
+                    Ok(jobs) if jobs > 0 => explicit_jobs = Some(jobs),
+                    _ => warn!("{} expects a positive number or auto", arg),
+                },
+                None => warn!("{} expects a positive number or auto", arg),
+            }
+        }
+
+        // Anything else that isn't a flag is a task to run, unless
+        // the first one is a subcommand
+        if arg == "init" && options.tasks.is_empty() && !options.init {
+            options.init = true;
+        } else if arg == "completions" && options.tasks.is_empty() && options.completions.is_none()
+        {
+            options.completions = Some(args.next().unwrap_or_default());
+        } else if arg == "graph" && options.tasks.is_empty() && !options.graph {
+            options.graph = true;
+        } else if arg == "daemon" && options.tasks.is_empty() && options.daemon.is_none() {
+            let action = args.next_if(|next| !next.starts_with('-'));
+            options.daemon = Some(action.unwrap_or_else(|| String::from("start")));
+        } else if arg == "cache" && options.tasks.is_empty() && options.cache_command.is_none() {
+            options.cache_command = Some(args.next().unwrap_or_default());
+        } else if arg == "export" && options.tasks.is_empty() && options.export.is_none() {
+            options.export = Some(args.next().unwrap_or_default());
+        } else if arg == "import" && options.tasks.is_empty() && options.import.is_none() {
+            let makefile = args.next_if(|next| !next.starts_with('-'));
+            options.import = Some(makefile.unwrap_or_else(|| String::from("Makefile")));
+        } else if !arg.starts_with('-') {
+            options.tasks.push(arg);
+        }
+    }
+
+    // NO_COLOR counts when it is set to anything but an empty string
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    options.color = should_use_color(color, no_color, std::io::stdout().is_terminal());
+
+    // Output is shown as it arrives unless it has to be held back
+    options.stream_output = !options.quiet_on_success && options.tail_on_failure.is_none();
+
+    // JSON output events need the output as it arrives
+    if options.json_output {
+        options.stream_output = true;
+    }
+
+    // An explicit -j always wins over --concurrency-auto
+    options.jobs = explicit_jobs.or_else(|| concurrency_auto.then(get_cpu_count));
+
+    // Limiting the number of jobs only makes sense when running async
+    if options.jobs.is_some() {
+        options.is_async = true;
+    }
+
+    options
+}
+
+/// Returns the number of logical CPUs, or 1 if it can't be detected
+pub fn get_cpu_count() -> usize {
+    std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+
+    #[test]
+    fn concurrency_auto_uses_the_cpus_unless_jobs_are_given() {
+        let options = parse_args(get_args(&["--concurrency-auto"]));
+        assert!(options.jobs.is_some_and(|jobs| jobs > 0));
+        assert!(options.is_async);
+
+        let options = parse_args(get_args(&["-j", "3", "--concurrency-auto"]));
+        assert_eq!(options.jobs, Some(3));
+
+        let options = parse_args(get_args(&["--concurrency-auto", "--jobs", "1"]));
+        assert_eq!(options.jobs, Some(1));
+
+        assert_eq!(parse_args(Vec::new()).jobs, None);
+    }
+
+    #[test]
+    fn color_flags_override_each_other() {
+        assert!(parse_args(get_args(&["--color=always"])).color);
+        assert!(!parse_args(get_args(&["--no-color"])).color);
+        assert!(!parse_args(get_args(&["--color=always", "--no-color"])).color);
+        assert!(parse_args(get_args(&["--no-color", "--color=always"])).color);
+        assert!(!parse_args(get_args(&["--color=never"])).color);
+    }
+}
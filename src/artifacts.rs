@@ -0,0 +1,132 @@
+//! The artifact cache set with `cache_dir`, which can be shared between
+//! machines. The outputs of a task that succeeds are saved under the
+//! hash of its command and inputs, and restored instead of running the
+//! task when another run has the same hash.
+
+use super::builtins::{copy, remove};
+use super::{expand_glob, Task};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Restores a task's outputs from the entry for its hash. Returns false
+/// if there is no entry
+pub fn restore(dir: &Path, task: &Task, hash: u64) -> io::Result<bool> {
+    let entry = dir.join(get_key(hash));
+
+    if !entry.is_dir() {
+        return Ok(false);
+    }
+
+    for file in fs::read_dir(&entry)? {
+        let file = file?;
+        copy(&file.path(), &task.base_dir.join(file.file_name()))?;
+    }
+
+    Ok(true)
+}
+
+/// Saves a task's outputs as the entry for its hash. Outputs outside the
+/// config's directory can't be restored, so tasks with them aren't saved
+pub fn store(dir: &Path, task: &Task, hash: u64) -> io::Result<()> {
+    let entry = dir.join(get_key(hash));
+
+    if entry.is_dir() {
+        return Ok(());
+    }
+
+    let mut outputs = Vec::new();
+
+    for pattern in &task.outputs {
+        for file in expand_glob(&task.base_dir, pattern) {
+            let relative = match file.strip_prefix(&task.base_dir) {
+                Ok(relative) if is_inside(relative) => relative.to_path_buf(),
+                _ => return Ok(()),
+            };
+            outputs.push((file, relative));
+        }
+    }
+
+    // The entry is written next to its place and renamed when complete,
+    // so other machines never restore half of it
+    let partial = dir.join(format!("{}.partial-{}", get_key(hash), std::process::id()));
+
+    if let Err(e) = copy_outputs(&outputs, &partial) {
+        let _ = remove(&partial);
+        return Err(e);
+    }
+
+    // Another run may have saved the same entry in the meantime
+    if fs::rename(&partial, &entry).is_err() {
+        remove(&partial)?;
+    }
+
+    Ok(())
+}
+
+fn copy_outputs(outputs: &[(PathBuf, PathBuf)], entry: &Path) -> io::Result<()> {
+    for (file, relative) in outputs {
+        let target = entry.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        copy(file, &target)?;
+    }
+
+    Ok(())
+}
+
+/// Removes every entry, returning how many there were
+pub fn clear(dir: &Path) -> io::Result<usize> {
+    let entries = get_entries(dir)?;
+
+    for entry in &entries {
+        remove(entry)?;
+    }
+
+    Ok(entries.len())
+}
+
+/// The number of entries and their total size in bytes
+pub fn stats(dir: &Path) -> io::Result<(usize, u64)> {
+    let entries = get_entries(dir)?;
+    let size = entries.iter().map(|entry| get_size(entry)).sum();
+    Ok((entries.len(), size))
+}
+
+fn get_key(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// The entry directories, or none if the cache doesn't exist yet
+fn get_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let files = match fs::read_dir(dir) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+
+    for file in files {
+        let path = file?.path();
+        if path.is_dir() {
+            entries.push(path);
+        }
+    }
+
+    Ok(entries)
+}
+
+fn get_size(path: &Path) -> u64 {
+    match fs::read_dir(path) {
+        Ok(files) => files.flatten().map(|file| get_size(&file.path())).sum(),
+        Err(_) => fs::metadata(path).map_or(0, |metadata| metadata.len()),
+    }
+}
+
+/// Whether a relative path stays inside the directory it's relative to
+fn is_inside(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
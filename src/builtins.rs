@@ -0,0 +1,147 @@
+//! File operations that commands like `@copy a b` run portably, without
+//! depending on `cp` or `copy` being available. Tasks run them through
+//! the tool's own binary with `--builtin`, so they can be timed out,
+//! captured and retried like any other command.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The command names that start a builtin after an `@`
+pub const NAMES: &[&str] = &["copy", "mkdir", "rm", "move", "touch"];
+
+/// Runs a builtin, returning its exit code. Errors are written to
+/// stderr like other programs do
+pub fn run(name: &str, args: &[String]) -> i32 {
+    let result = match name {
+        "copy" => copy_paths(args),
+        "mkdir" => args.iter().try_for_each(fs::create_dir_all),
+        "rm" => args.iter().try_for_each(|path| remove(Path::new(path))),
+        "move" => move_paths(args),
+        "touch" => args.iter().try_for_each(|path| touch(Path::new(path))),
+        _ => {
+            eprintln!("unknown builtin `{}`", name);
+            return 2;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            1
+        }
+    }
+}
+
+/// Splits `SRC... DEST` arguments, telling whether the sources go into
+/// `DEST` rather than replacing it
+fn split_destination(args: &[String]) -> io::Result<(&[String], &Path, bool)> {
+    let (dest, sources) = match args.split_last() {
+        Some((dest, sources)) if !sources.is_empty() => (Path::new(dest), sources),
+        _ => {
+            let message = "expected at least one source and a destination";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+    };
+
+    let into = sources.len() > 1 || dest.is_dir() || dest.to_string_lossy().ends_with('/');
+
+    if into {
+        fs::create_dir_all(dest)?;
+    } else if let Some(parent) = dest.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    Ok((sources, dest, into))
+}
+
+/// The path a source ends up at
+fn get_target(source: &Path, dest: &Path, into: bool) -> io::Result<std::path::PathBuf> {
+    if !into {
+        return Ok(dest.to_path_buf());
+    }
+
+    match source.file_name() {
+        Some(name) => Ok(dest.join(name)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no file name", source.display()),
+        )),
+    }
+}
+
+/// Copies files, and directories with everything in them
+fn copy_paths(args: &[String]) -> io::Result<()> {
+    let (sources, dest, into) = split_destination(args)?;
+
+    for source in sources {
+        let source = Path::new(source);
+        copy(source, &get_target(source, dest, into)?)?;
+    }
+
+    Ok(())
+}
+
+/// Copies a file, or a directory with everything in it
+pub fn copy(source: &Path, target: &Path) -> io::Result<()> {
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", source.display(), e));
+
+    if !source.is_dir() {
+        return fs::copy(source, target).map(|_| ()).map_err(with_path);
+    }
+
+    fs::create_dir_all(target)?;
+
+    for entry in fs::read_dir(source).map_err(with_path)? {
+        let entry = entry?;
+        copy(&entry.path(), &target.join(entry.file_name()))?;
+    }
+
+    Ok(())
+}
+
+/// Moves files and directories, copying them when they are on another
+/// drive and can't just be renamed
+fn move_paths(args: &[String]) -> io::Result<()> {
+    let (sources, dest, into) = split_destination(args)?;
+
+    for source in sources {
+        let source = Path::new(source);
+        let target = get_target(source, dest, into)?;
+
+        if fs::rename(source, &target).is_err() {
+            copy(source, &target)?;
+            remove(source)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a file or a directory with everything in it. Paths that
+/// don't exist are ignored, like with `rm -rf`
+pub fn remove(path: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Creates an empty file, or updates the modification time of one
+fn touch(path: &Path) -> io::Result<()> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+    file.set_modified(SystemTime::now())
+}
@@ -0,0 +1,167 @@
+//! Writes the completion scripts for `rust-build-tool completions SHELL`.
+//! Flags are completed from a fixed list, and task names by asking the
+//! tool for the tasks of the config in the current directory.
+
+/// Every flag the command line understands
+const FLAGS: &[&str] = &[
+    "-async",
+    "-powershell",
+    "-j",
+    "-n",
+    "-q",
+    "-v",
+    "-vv",
+    "--affected",
+    "--cache-dir",
+    "--check-programs",
+    "--clean",
+    "--clean-all",
+    "--client",
+    "--color=auto",
+    "--color=always",
+    "--color=never",
+    "--concurrency-auto",
+    "--config",
+    "--config-dir",
+    "--dry-run",
+    "--echo",
+    "--env-prefix",
+    "--explicit-env",
+    "--export-script",
+    "--fail-on-stderr",
+    "--first-match",
+    "--force",
+    "--format",
+    "--jobs",
+    "--keep-going",
+    "--keep-temp",
+    "--last-match",
+    "--list",
+    "--list-names",
+    "--log-file",
+    "--merge-streams",
+    "--no-color",
+    "--no-lock",
+    "--output",
+    "--pipe",
+    "--print-duration-json",
+    "--print-tree",
+    "--profile",
+    "--profile-memory",
+    "--quiet",
+    "--quiet-on-success",
+    "--report-unused-vars",
+    "--require-all-vars",
+    "--run",
+    "--set",
+    "--shuffle",
+    "--since",
+    "--strict",
+    "--tail-on-failure",
+    "--timeout",
+    "--timeout-grace",
+    "--timeout-signal",
+    "--verbose",
+    "--wait",
+    "--watch",
+];
+
+/// The subcommands, completed along with the task names
+const SUBCOMMANDS: &[&str] = &[
+    "init",
+    "import",
+    "export",
+    "graph",
+    "cache",
+    "daemon",
+    "completions",
+];
+
+/// The shells there are completion scripts for
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish", "powershell"];
+
+/// The completion script for a shell, or None for an unknown shell
+pub fn get_script(shell: &str) -> Option<String> {
+    let flags = FLAGS.join(" ");
+    let subcommands = SUBCOMMANDS.join(" ");
+
+    let script = match shell {
+        "bash" => format!(
+            r#"_rust_build_tool() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [[ "$cur" == -* ]]; then
+        COMPREPLY=($(compgen -W "{flags}" -- "$cur"))
+    else
+        COMPREPLY=($(compgen -W "{subcommands} $(rust-build-tool --list-names 2>/dev/null)" -- "$cur"))
+    fi
+}}
+complete -F _rust_build_tool rust-build-tool
+"#,
+            flags = flags,
+            subcommands = subcommands
+        ),
+        "zsh" => format!(
+            r#"#compdef rust-build-tool
+_rust_build_tool() {{
+    if [[ "$PREFIX" == -* ]]; then
+        compadd -- {flags}
+    else
+        compadd -- {subcommands} ${{(f)"$(rust-build-tool --list-names 2>/dev/null)"}}
+    fi
+}}
+compdef _rust_build_tool rust-build-tool
+"#,
+            flags = flags,
+            subcommands = subcommands
+        ),
+        "fish" => {
+            let mut script = format!(
+                "complete -c rust-build-tool -f -a '{} (rust-build-tool --list-names 2>/dev/null)'\n",
+                subcommands
+            );
+
+            // fish completes long, short and old style single dash flags differently
+            for flag in FLAGS {
+                let option = match flag.strip_prefix("--") {
+                    Some(long) => format!("-l {}", long.split('=').next().unwrap_or(long)),
+                    None if flag.len() == 2 => format!("-s {}", &flag[1..]),
+                    None => format!("-o {}", &flag[1..]),
+                };
+
+                let line = format!("complete -c rust-build-tool {}\n", option);
+                if !script.contains(&line) {
+                    script += &line;
+                }
+            }
+
+            script
+        }
+        "powershell" => {
+            let quoted: Vec<String> = FLAGS.iter().map(|flag| format!("'{}'", flag)).collect();
+            let subcommands: Vec<String> = SUBCOMMANDS
+                .iter()
+                .map(|name| format!("'{}'", name))
+                .collect();
+
+            format!(
+                r#"Register-ArgumentCompleter -Native -CommandName rust-build-tool -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    if ($wordToComplete -like '-*') {{
+        $words = @({flags})
+    }} else {{
+        $words = @({subcommands}) + @(rust-build-tool --list-names 2>$null)
+    }}
+    $words | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+"#,
+                flags = quoted.join(", "),
+                subcommands = subcommands.join(", ")
+            )
+        }
+        _ => return None,
+    };
+
+    Some(script)
+}
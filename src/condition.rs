@@ -0,0 +1,112 @@
+//! Evaluates a task's `when` condition, like `exists(Cargo.lock)`,
+//! `env(CI) == 'true'` or `os == linux && !exists(target)`
+
+use std::path::Path;
+
+/// Evaluates a condition, with paths relative to `base`. `||` binds
+/// looser than `&&`, and `!` negates one check
+pub fn evaluate(condition: &str, base: &Path) -> Result<bool, String> {
+    let mut any = false;
+
+    for alternative in split_outside_quotes(condition, "||") {
+        let mut all = true;
+
+        for check in split_outside_quotes(alternative, "&&") {
+            all &= evaluate_check(check.trim(), base)?;
+        }
+
+        any |= all;
+    }
+
+    Ok(any)
+}
+
+/// Evaluates one check like `exists(path)` or `a == b`
+fn evaluate_check(check: &str, base: &Path) -> Result<bool, String> {
+    if let Some(negated) = check.strip_prefix('!') {
+        return evaluate_check(negated.trim(), base).map(|result| !result);
+    }
+
+    if check.is_empty() {
+        return Err(String::from("expected a check"));
+    }
+
+    for (operator, equal) in [("==", true), ("!=", false)] {
+        if let [left, right] = split_outside_quotes(check, operator).as_slice() {
+            let matches = get_value(left.trim())? == get_value(right.trim())?;
+            return Ok(matches == equal);
+        }
+    }
+
+    if let Some(path) = get_call(check, "exists")? {
+        return Ok(base.join(path).exists());
+    }
+
+    // A value on its own is true unless it is empty, false or 0
+    let value = get_value(check)?;
+    Ok(!["", "false", "0"].contains(&value.as_str()))
+}
+
+/// The value of one side of a comparison
+fn get_value(text: &str) -> Result<String, String> {
+    if let Some(name) = get_call(text, "env")? {
+        return Ok(std::env::var(name).unwrap_or_default());
+    }
+
+    match text {
+        "os" => return Ok(String::from(std::env::consts::OS)),
+        "arch" => return Ok(String::from(std::env::consts::ARCH)),
+        _ => {}
+    }
+
+    for quote in ['\'', '"'] {
+        if let Some(inner) = text.strip_prefix(quote) {
+            return match inner.strip_suffix(quote) {
+                Some(value) => Ok(String::from(value)),
+                None => Err(format!("missing closing quote in {}", text)),
+            };
+        }
+    }
+
+    if text.contains(['(', ')']) {
+        return Err(format!("unknown check {}", text));
+    }
+
+    Ok(String::from(text))
+}
+
+/// The argument of a call like `exists(path)`, if `text` is a call to `name`
+fn get_call<'a>(text: &'a str, name: &str) -> Result<Option<&'a str>, String> {
+    let argument = match text.strip_prefix(name).map(str::trim_start) {
+        Some(rest) if rest.starts_with('(') => &rest[1..],
+        _ => return Ok(None),
+    };
+
+    match argument.strip_suffix(')') {
+        Some(argument) => Ok(Some(argument.trim().trim_matches(['\'', '"']))),
+        None => Err(format!("expected `)` to close {}(", name)),
+    }
+}
+
+/// Splits at a separator that isn't inside quotes
+fn split_outside_quotes<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if text[i..].starts_with(separator) && i >= start => {
+                parts.push(&text[start..i]);
+                start = i + separator.len();
+            }
+            None => {}
+        }
+    }
+
+    parts.push(&text[start..]);
+    parts
+}
@@ -0,0 +1,1138 @@
+//! Reads the parts of a config: the files it extends and includes, its
+//! variables, tasks, settings, exit codes, profiles, groups and execute
+//! queues. Tasks with a `matrix` are expanded into one per combination.
+
+use super::{
+    interpolate_variables, parser, references_variable, split_builtin, toml, Config, ExitCategory,
+    MatchPolicy, Task,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Returns the `extends = path` value found before the first section
+pub fn get_extends_path(config: &str) -> Option<&str> {
+    for line in config.lines() {
+        let trimmed = line.trim();
+
+        if get_section_header(trimmed).is_some() {
+            break;
+        }
+
+        if let Some(("extends", path)) = get_line_key_value(trimmed) {
+            return Some(path);
+        }
+    }
+
+    None
+}
+
+/// Returns the files included with `include = path` or `@include path`
+/// before the first section, in order
+pub fn get_include_paths(config: &str) -> Vec<&str> {
+    let mut paths = Vec::new();
+
+    for line in config.lines() {
+        let trimmed = line.trim();
+
+        if get_section_header(trimmed).is_some() {
+            break;
+        }
+
+        if let Some(path) = trimmed.strip_prefix("@include ") {
+            paths.push(path.trim().trim_matches('"'));
+        } else if let Some(("include", path)) = get_line_key_value(trimmed) {
+            paths.push(path);
+        }
+    }
+
+    paths
+}
+
+/// Parses a config and every config it extends or includes. `chain`
+/// collects every file that was loaded, and `stack` holds the files
+/// currently being loaded so that cycles can be reported.
+pub fn load_config(
+    path: &Path,
+    config: &str,
+    chain: &mut Vec<PathBuf>,
+    stack: &[PathBuf],
+    policy: MatchPolicy,
+) -> Result<Config, String> {
+    let config = &if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::to_cfg(config).map_err(|e| format!("{}: {}", path.display(), e))?
+    } else {
+        let diagnostics = parser::check_config(config);
+
+        for diagnostic in &diagnostics {
+            let level = if diagnostic.is_error { "error" } else { "warn" };
+            println!("{}: {}:{}", level, path.display(), diagnostic);
+        }
+
+        if diagnostics.iter().any(|d| d.is_error) {
+            return Err(format!("failed to parse {}", path.display()));
+        }
+
+        parser::strip_comments(config)
+    };
+
+    let mut local = Config::parse_with_policy(config, policy);
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    // Task directories are relative to the config they are defined in
+    for task in &mut local.tasks {
+        task.base_dir = dir.to_path_buf();
+    }
+
+    // The base comes first, then the includes, and the current file
+    // overrides all of them
+    let mut merged = match get_extends_path(config) {
+        Some(base_path) => Some(load_linked_config(
+            &dir.join(base_path),
+            "extend",
+            chain,
+            stack,
+            policy,
+        )?),
+        None => None,
+    };
+
+    for include_path in get_include_paths(config) {
+        let included =
+            load_linked_config(&dir.join(include_path), "include", chain, stack, policy)?;
+
+        merged = Some(match merged {
+            Some(merged) => merged.merge(included, policy),
+            None => included,
+        });
+    }
+
+    Ok(match merged {
+        Some(merged) => merged.merge(local, policy),
+        None => local,
+    })
+}
+
+/// Loads a config that another one extends or includes
+pub fn load_linked_config(
+    path: &Path,
+    verb: &str,
+    chain: &mut Vec<PathBuf>,
+    stack: &[PathBuf],
+    policy: MatchPolicy,
+) -> Result<Config, String> {
+    let canonical = match path.canonicalize() {
+        Ok(canonical) => canonical,
+        Err(e) => return Err(format!("failed to {} {}: {}", verb, path.display(), e)),
+    };
+
+    if let Some(start) = stack.iter().position(|file| *file == canonical) {
+        let cycle: Vec<String> = stack[start..]
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|file| file.display().to_string())
+            .collect();
+        return Err(format!("{} cycle: {}", verb, cycle.join(" -> ")));
+    }
+
+    // The same file can be included by more than one config
+    if !chain.contains(&canonical) {
+        chain.push(canonical.clone());
+    }
+
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => return Err(format!("failed to read {}: {}", path.display(), e)),
+    };
+
+    let mut stack = stack.to_vec();
+    stack.push(canonical);
+
+    load_config(path, &text, chain, &stack, policy)
+}
+
+/// Splits a line by an = and reads it as a key and value pair
+pub fn get_line_key_value(line: &str) -> Option<(&str, &str)> {
+    // Only the first = separates the key, values may contain more
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim().trim_matches('\"');
+    let value = value.trim();
+
+    // Quotes around the whole value are removed, inner quotes are kept
+    let value = match value.strip_prefix('\"').and_then(|v| v.strip_suffix('\"')) {
+        Some(unquoted) => unquoted,
+        None => value,
+    };
+
+    Some((key, value))
+}
+
+/// This extract the variables from the config file.
+/// Variables start with a $ and are assigned with an =
+pub fn get_variables_map(config: &str) -> HashMap<String, String> {
+    let lines = config.lines();
+    let mut variables = HashMap::new();
+    let mut in_profile = false;
+
+    for line in lines {
+        let trimmed = line.trim();
+
+        // Profile variables are only used when the profile is picked
+        if let Some(name) = get_section_header(trimmed) {
+            in_profile = name.starts_with("profile.");
+            continue;
+        }
+
+        // We need to make sure the line starts with a $
+        if in_profile || !trimmed.starts_with('$') {
+            continue;
+        }
+
+        if let Some((key, value)) = get_line_key_value(trimmed) {
+            variables.insert(String::from(key), String::from(value));
+        }
+    }
+
+    variables
+}
+
+/// Replaces variables whose value is another variable, like `$a = $b`,
+/// with that variable's value. This repeats until nothing changes, so the
+/// order variables are defined in doesn't matter. References to undefined
+/// variables and reference cycles are reported
+pub fn resolve_variable_references(
+    variables: &mut HashMap<String, String>,
+    skip: &HashSet<String>,
+) {
+    // Each pass resolves at least one more step of every chain, so a chain
+    // can't be longer than the number of variables unless it's a cycle
+    for _ in 0..=variables.len() {
+        let resolved: Vec<(String, String)> = variables
+            .iter()
+            .filter(|(key, _)| !skip.contains(*key))
+            .filter(|(key, value)| {
+                !value.starts_with("$(") && !references_variable(value, &key[1..])
+            })
+            .filter_map(|(key, value)| {
+                let (resolved, _) = interpolate_variables(value, variables, false);
+                (resolved != *value).then(|| (key.clone(), resolved))
+            })
+            .collect();
+
+        if resolved.is_empty() {
+            break;
+        }
+
+        for (key, value) in resolved {
+            variables.insert(key, value);
+        }
+    }
+
+    let mut names: Vec<&String> = variables
+        .keys()
+        .filter(|key| !skip.contains(*key))
+        .collect();
+    names.sort();
+
+    for name in names {
+        let value = &variables[name];
+
+        if value.starts_with("$(") {
+            continue;
+        }
+
+        let (resolved, unresolved) = interpolate_variables(value, variables, false);
+
+        if resolved != *value || references_variable(value, &name[1..]) {
+            warn!("variable {} is part of a reference cycle", name);
+            continue;
+        }
+
+        if let Some(reference) = unresolved {
+            warn!(
+                "variable {} references undefined variable {}",
+                name, reference
+            );
+        }
+    }
+}
+
+/// Makes every environment variable available as `${env:NAME}`, and as
+/// `$NAME` when the config doesn't define it unless `fallback` is off.
+/// Returns the names that were added
+pub fn add_env_fallbacks(
+    variables: &mut HashMap<String, String>,
+    fallback: bool,
+) -> HashSet<String> {
+    let mut added = HashSet::new();
+
+    for (key, value) in std::env::vars() {
+        let mut names = vec![format!("$env:{}", key)];
+        if fallback {
+            names.push(format!("${}", key));
+        }
+
+        for name in names {
+            if !variables.contains_key(&name) {
+                variables.insert(name.clone(), value.clone());
+                added.insert(name);
+            }
+        }
+    }
+
+    added
+}
+
+/// Imports the environment variables in `env` starting with `prefix` as
+/// variables, with the prefix removed. `BUILD_VERSION` becomes `$VERSION`
+pub fn import_env_variables(
+    variables: &mut HashMap<String, String>,
+    env: impl Iterator<Item = (String, String)>,
+    prefix: &str,
+) {
+    for (key, value) in env {
+        let name = match key.strip_prefix(prefix) {
+            Some(name) if !name.is_empty() => format!("${}", name),
+            _ => continue,
+        };
+
+        // Variables from the config take precedence
+        if variables.contains_key(&name) {
+            warn!(
+                "env var {} collides with variable {}, keeping the config value",
+                key, name
+            );
+            continue;
+        }
+
+        variables.insert(name, value);
+    }
+}
+
+/// Returns the section name if the line is a `[header]`
+pub fn get_section_header(line: &str) -> Option<&str> {
+    // Headers start with an open bracket and end with a close bracket
+    if line.starts_with('[') && line.ends_with(']') {
+        return Some(line.trim_matches(|c| c == '[' || c == ']'));
+    }
+
+    None
+}
+
+/// Extracts the tasks from config file.
+pub fn get_user_tasks(config: &str) -> Vec<Task> {
+    let lines = config.lines();
+    let mut tasks = Vec::new();
+    let mut current: Option<Task> = None;
+
+    for line in lines {
+        let trimmed = line.trim();
+
+        // Ignore empty lines
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        // A new header finishes the task we were reading
+        if let Some(name) = get_section_header(trimmed) {
+            if let Some(task) = current.take() {
+                push_user_task(&mut tasks, task);
+            }
+
+            let is_reserved = ["execute", "settings", "exit_codes"].contains(&name)
+                || name.starts_with("execute:")
+                || name.starts_with("profile.")
+                || name.starts_with("group.");
+
+            if !is_reserved {
+                current = Some(Task {
+                    name: String::from(name),
+                    description: None,
+                    commands: Vec::new(),
+                    platform_commands: false,
+                    other_platform_commands: false,
+                    platforms: Vec::new(),
+                    filter: None,
+                    retries: None,
+                    retry_delay: None,
+                    clean: Vec::new(),
+                    success_if: None,
+                    max_memory: None,
+                    max_cpu: None,
+                    priority: None,
+                    cpu_affinity: Vec::new(),
+                    timeout: None,
+                    path: None,
+                    fail_on_stderr: None,
+                    discard_stdout: false,
+                    discard_stderr: false,
+                    no_echo: false,
+                    command_file: None,
+                    script: None,
+                    log_file: None,
+                    capture: None,
+                    when: None,
+                    runs_on: None,
+                    container: None,
+                    depends: Vec::new(),
+                    aliases: Vec::new(),
+                    before: Vec::new(),
+                    after: Vec::new(),
+                    shell: None,
+                    glob: true,
+                    cwd: None,
+                    env: Vec::new(),
+                    watch: Vec::new(),
+                    inputs: Vec::new(),
+                    outputs: Vec::new(),
+                    allow_failure: false,
+                    interactive: false,
+                    on_interrupt: false,
+                    base_dir: PathBuf::new(),
+                    matrix: Vec::new(),
+                });
+            }
+
+            continue;
+        }
+
+        let task = match current.as_mut() {
+            Some(task) => task,
+            None => continue,
+        };
+
+        if let Some((key, value)) = get_line_key_value(trimmed) {
+            // `command.linux` replaces the task's commands on Linux and
+            // is ignored elsewhere
+            let key = match key.strip_prefix("command.") {
+                Some(platform) if !is_current_platform(platform) => {
+                    task.other_platform_commands = true;
+                    continue;
+                }
+                Some(_) if !task.platform_commands => {
+                    task.commands.clear();
+                    task.platform_commands = true;
+                    "command"
+                }
+                Some(_) => "command",
+                None if key == "command" && task.platform_commands => continue,
+                None => key,
+            };
+
+            // New options also have to be added to parser::TASK_KEYS
+            match key {
+                // A leading @ stops the command from being echoed, like in
+                // make, unless it starts a builtin like @copy
+                "command" => match value.strip_prefix('@') {
+                    Some(_) if split_builtin(value).is_some() => {
+                        task.commands.push(String::from(value))
+                    }
+                    Some(command) => {
+                        task.commands.push(String::from(command.trim_start()));
+                        task.no_echo = true;
+                    }
+                    None => task.commands.push(String::from(value)),
+                },
+                "platform" => task.platforms = parse_list(value),
+                "description" if !value.is_empty() => task.description = Some(String::from(value)),
+                "filter" if !value.is_empty() => task.filter = Some(String::from(value)),
+                "retries" => task.retries = parse_setting(&task.name, key, value),
+                "retry_delay" => task.retry_delay = parse_duration_setting(&task.name, key, value),
+                "clean" => task.clean = parse_list(value),
+                "depends" => task.depends = parse_list(value),
+                "alias" => task.aliases = parse_list(value),
+                "before" => task.before = parse_list(value),
+                "after" => task.after = parse_list(value),
+                "watch" => task.watch = parse_list(value),
+                "inputs" => task.inputs = parse_list(value),
+                "outputs" => task.outputs = parse_list(value),
+                "interactive" => {
+                    if let Some(interactive) = parse_setting(&task.name, key, value) {
+                        task.interactive = interactive;
+                    }
+                }
+                "allow_failure" => {
+                    if let Some(allow) = parse_setting(&task.name, key, value) {
+                        task.allow_failure = allow;
+                    }
+                }
+                "on_interrupt" => {
+                    if let Some(on_interrupt) = parse_setting(&task.name, key, value) {
+                        task.on_interrupt = on_interrupt;
+                    }
+                }
+                "shell" => task.shell = parse_setting(&task.name, key, value),
+                "glob" => {
+                    if let Some(glob) = parse_setting(&task.name, key, value) {
+                        task.glob = glob;
+                    }
+                }
+                "cwd" if !value.is_empty() => task.cwd = Some(String::from(value)),
+                "max_memory" => match parse_size(value) {
+                    Some(size) => task.max_memory = Some(size),
+                    None => warn!("{}: invalid value for {}: {}", task.name, key, value),
+                },
+                "max_cpu" => task.max_cpu = parse_setting(&task.name, key, value),
+                "priority" => match parse_priority(value) {
+                    Some(priority) => task.priority = Some(priority),
+                    None => warn!("{}: invalid value for {}: {}", task.name, key, value),
+                },
+                "cpu_affinity" => match parse_cpu_list(value) {
+                    Some(cpus) => task.cpu_affinity = cpus,
+                    None => warn!("{}: invalid value for {}: {}", task.name, key, value),
+                },
+                "timeout" => task.timeout = parse_duration_setting(&task.name, key, value),
+                "success_if" if !value.is_empty() => task.success_if = Some(String::from(value)),
+                "path" if !value.is_empty() => task.path = Some(String::from(value)),
+                "log_file" if !value.is_empty() => task.log_file = Some(String::from(value)),
+                "when" if !value.is_empty() => task.when = Some(String::from(value)),
+                "runs_on" if !value.is_empty() => task.runs_on = Some(String::from(value)),
+                "container" if !value.is_empty() => task.container = Some(String::from(value)),
+                "capture" if !value.is_empty() => {
+                    task.capture = Some(format!("${}", value.trim_start_matches('$')))
+                }
+                "command_file" if !value.is_empty() => {
+                    task.command_file = Some(String::from(value))
+                }
+                "fail_on_stderr" => task.fail_on_stderr = parse_setting(&task.name, key, value),
+                "stdout" | "stderr" if value != "null" => {
+                    warn!("{}: {} can only be null", task.name, key)
+                }
+                "stdout" => task.discard_stdout = true,
+                "stderr" => task.discard_stderr = true,
+                "silent" => {
+                    if let Some(silent) = parse_setting(&task.name, key, value) {
+                        task.discard_stdout = silent;
+                        task.discard_stderr = silent;
+                    }
+                }
+                _ if key.starts_with("env.") && key.len() > 4 => {
+                    task.env.push((key[4..].to_string(), String::from(value)))
+                }
+                _ if key.starts_with("matrix.") && key.len() > 7 => {
+                    let values = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|v| !v.is_empty())
+                        .map(String::from)
+                        .collect();
+                    task.matrix.push((key[7..].to_string(), values));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(task) = current {
+        push_user_task(&mut tasks, task);
+    }
+
+    tasks
+}
+
+/// Parses a numeric task option, warning if it is invalid
+pub fn parse_setting<T: std::str::FromStr>(owner: &str, key: &str, value: &str) -> Option<T> {
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("{}: invalid value for {}: {}", owner, key, value);
+            None
+        }
+    }
+}
+
+/// Parses a duration setting, warning if it is invalid
+pub fn parse_duration_setting(owner: &str, key: &str, value: &str) -> Option<Duration> {
+    let duration = parse_duration(value);
+    if duration.is_none() {
+        warn!("{}: invalid value for {}: {}", owner, key, value);
+    }
+    duration
+}
+
+/// Parses a duration like `120s`, `500ms`, `2m` or `1h`. A plain number
+/// is in seconds
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    let (number, multiplier) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 60.0)
+    } else if let Some(number) = value.strip_suffix('h') {
+        (number, 3600.0)
+    } else {
+        (value, 1.0)
+    };
+
+    let seconds = number.trim().parse::<f64>().ok()? * multiplier;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_ascii_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+
+    let (number, multiplier) = match value.chars().last()? {
+        'K' => (&value[..value.len() - 1], 1 << 10),
+        'M' => (&value[..value.len() - 1], 1 << 20),
+        'G' => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Parses a priority, `low`, `normal`, `high` or a nice value from -20
+/// to 19, into a nice value
+pub fn parse_priority(value: &str) -> Option<i32> {
+    match value.trim() {
+        "low" => Some(10),
+        "normal" => Some(0),
+        "high" => Some(-10),
+        value => value.parse().ok().filter(|nice| (-20..=19).contains(nice)),
+    }
+}
+
+/// Parses a list of CPUs like `0-3,6` into their numbers
+pub fn parse_cpu_list(value: &str) -> Option<Vec<usize>> {
+    // The most CPUs an affinity mask holds
+    const MAX_CPUS: usize = 1024;
+
+    let mut cpus = Vec::new();
+
+    for item in parse_list(value) {
+        let (first, last): (usize, usize) = match item.split_once('-') {
+            Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+            None => {
+                let cpu = item.parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+
+        if first > last || last >= MAX_CPUS {
+            return None;
+        }
+
+        cpus.extend(first..=last);
+    }
+
+    (!cpus.is_empty()).then_some(cpus)
+}
+
+/// Splits a comma separated value into its trimmed, non-empty items
+pub fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Adds a suffix to the name of a file, before its extension:
+/// `logs/build.log` becomes `logs/build-SUFFIX.log`
+pub fn add_file_suffix(path: &str, suffix: &str) -> String {
+    let name_start = path.rfind(['/', '\\']).map_or(0, |i| i + 1);
+
+    match path[name_start..].rfind('.').filter(|&i| i > 0) {
+        Some(dot) => {
+            let (stem, extension) = path.split_at(name_start + dot);
+            format!("{}-{}{}", stem, suffix, extension)
+        }
+        None => format!("{}-{}", path, suffix),
+    }
+}
+
+/// Whether a platform name like `linux`, `macos`, `windows` or `unix`
+/// matches the one the tool is running on
+pub fn is_current_platform(platform: &str) -> bool {
+    platform == std::env::consts::OS || platform == std::env::consts::FAMILY
+}
+
+/// Adds a parsed task to the list if it has a command. A task for
+/// another platform, or with only `command.OS` lines for others, is
+/// kept so it can be skipped when it's queued
+pub fn push_user_task(tasks: &mut Vec<Task>, task: Task) {
+    let has_command = task.commands.iter().any(|c| !c.is_empty()) || task.command_file.is_some();
+
+    if has_command || !task.platforms.is_empty() || task.other_platform_commands {
+        tasks.push(task);
+    }
+}
+
+/// Reads the key and value pairs of the `[settings]` section
+pub fn get_settings(config: &str) -> HashMap<String, String> {
+    get_section_pairs(config, "settings").into_iter().collect()
+}
+
+/// Reads the key and value pairs of a section, in order
+pub fn get_section_pairs(config: &str, section: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut in_section = false;
+
+    for line in config.lines() {
+        let trimmed = line.trim();
+
+        if let Some(name) = get_section_header(trimmed) {
+            in_section = name == section;
+            continue;
+        }
+
+        if !in_section {
+            continue;
+        }
+
+        if let Some((key, value)) = get_line_key_value(trimmed) {
+            pairs.push((String::from(key), String::from(value)));
+        }
+    }
+
+    pairs
+}
+
+/// Reads the `[profile.NAME]` sections, which override variables when
+/// `--profile NAME` is used. The `$` can be left out in them
+pub fn get_profiles(config: &str) -> HashMap<String, HashMap<String, String>> {
+    let mut profiles: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut current = None;
+
+    for line in config.lines() {
+        let trimmed = line.trim();
+
+        if let Some(name) = get_section_header(trimmed) {
+            current = name.strip_prefix("profile.").map(String::from);
+
+            if let Some(name) = &current {
+                profiles.entry(name.clone()).or_default();
+            }
+            continue;
+        }
+
+        let profile = match &current {
+            Some(name) => profiles.entry(name.clone()).or_default(),
+            None => continue,
+        };
+
+        if let Some((key, value)) = get_line_key_value(trimmed) {
+            let name = format!("${}", key.trim_start_matches('$'));
+            profile.insert(name, String::from(value));
+        }
+    }
+
+    profiles
+}
+
+/// Reads the `[exit_codes]` section, which maps exit codes like `0`
+/// or ranges like `64..=78` to a category name
+pub fn get_exit_categories(config: &str) -> Vec<ExitCategory> {
+    let mut categories = Vec::new();
+
+    for (key, value) in get_section_pairs(config, "exit_codes") {
+        // `64..=78 = usage` is split at the first =, so put the range back together
+        let (codes, name) = match (key.strip_suffix(".."), value.split_once('=')) {
+            (Some(start), Some((end, name))) => (
+                format!("{}..={}", start, end.trim()),
+                name.trim().to_string(),
+            ),
+            _ => (key, value),
+        };
+
+        let range = match codes.split_once("..=") {
+            Some((start, end)) => start
+                .trim()
+                .parse()
+                .and_then(|start| end.trim().parse().map(|end| start..=end)),
+            None => codes.parse().map(|code| code..=code),
+        };
+
+        match range {
+            Ok(codes) => categories.push(ExitCategory { codes, name }),
+            Err(_) => warn!("exit_codes: invalid exit code {}", codes),
+        }
+    }
+
+    categories
+}
+
+/// Parses a value from the `[settings]` section
+pub fn get_setting<T: std::str::FromStr>(
+    settings: &HashMap<String, String>,
+    key: &str,
+) -> Option<T> {
+    settings
+        .get(key)
+        .and_then(|value| parse_setting("settings", key, value))
+}
+
+/// This retrieves the execution task queue from the config file.
+pub fn get_execute_queue(config: &str) -> VecDeque<String> {
+    get_queue(config, "[execute]")
+}
+
+/// Reads the task names listed under a header, up to the first empty line
+pub fn get_queue(config: &str, header: &str) -> VecDeque<String> {
+    let lines = config.lines();
+    let mut queue = VecDeque::new();
+    let mut in_execute_task = false;
+
+    for line in lines {
+        if line.starts_with(header) {
+            in_execute_task = true;
+            continue;
+        }
+
+        let line = line.trim();
+
+        // The list ends at an empty line or the next section
+        if in_execute_task && (line.is_empty() || get_section_header(line).is_some()) {
+            break;
+        }
+
+        // Lines like `parallel = true` are options, not tasks
+        if in_execute_task && !line.contains('=') {
+            queue.push_back(String::from(line));
+        }
+    }
+
+    queue
+}
+
+/// Retrieves the named `[execute:name]` queues from the config file.
+pub fn get_named_queues(config: &str) -> HashMap<String, VecDeque<String>> {
+    let mut queues = HashMap::new();
+
+    for line in config.lines() {
+        let name = match get_section_header(line.trim()) {
+            Some(header) => match header.strip_prefix("execute:") {
+                Some(name) => name,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        let queue = get_queue(config, &format!("[execute:{}]", name));
+        queues.insert(String::from(name), queue);
+    }
+
+    queues
+}
+
+/// Replaces each task with a `matrix` by a task for every combination
+/// of its values, named like `build:x86_64:linux`, with
+/// `${matrix.KEY}` replaced in its options. The task's own name becomes
+/// a group of them all
+pub fn expand_matrices(
+    tasks: Vec<Task>,
+    groups: &mut HashMap<String, VecDeque<String>>,
+) -> Vec<Task> {
+    let mut expanded = Vec::new();
+
+    for task in tasks {
+        if task.matrix.is_empty() {
+            expanded.push(task);
+            continue;
+        }
+
+        let mut combinations: Vec<Vec<(&str, &str)>> = vec![Vec::new()];
+        for (key, values) in &task.matrix {
+            combinations = combinations
+                .iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.push((key.as_str(), value.as_str()));
+                        combination
+                    })
+                })
+                .collect();
+        }
+
+        let mut members = VecDeque::new();
+
+        for combination in combinations {
+            let replace = |text: &str| {
+                combination
+                    .iter()
+                    .fold(String::from(text), |text, (key, value)| {
+                        text.replace(&format!("${{matrix.{}}}", key), value)
+                    })
+            };
+            let replace_all = |texts: &mut Vec<String>| {
+                for text in texts.iter_mut() {
+                    *text = replace(text);
+                }
+            };
+
+            let mut copy = task.clone();
+            let values: Vec<&str> = combination.iter().map(|(_, value)| *value).collect();
+            copy.name = format!("{}:{}", task.name, values.join(":"));
+            copy.matrix.clear();
+            // Aliases would name every copy, so they stay with the group
+            copy.aliases.clear();
+
+            replace_all(&mut copy.commands);
+            replace_all(&mut copy.depends);
+            replace_all(&mut copy.before);
+            replace_all(&mut copy.after);
+            replace_all(&mut copy.inputs);
+            replace_all(&mut copy.outputs);
+            replace_all(&mut copy.clean);
+
+            for text in vec![
+                &mut copy.description,
+                &mut copy.command_file,
+                &mut copy.cwd,
+                &mut copy.when,
+                &mut copy.runs_on,
+                &mut copy.container,
+                &mut copy.log_file,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                *text = replace(text);
+            }
+
+            for (_, value) in &mut copy.env {
+                *value = replace(value);
+            }
+
+            // Copies sharing a log_file would overwrite each other's logs
+            if let Some(log_file) = &mut copy.log_file {
+                if task.log_file.as_ref() == Some(log_file)
+                    && !references_variable(log_file, "TASK")
+                {
+                    *log_file = add_file_suffix(log_file, &values.join("-"));
+                }
+            }
+
+            members.push_back(copy.name.clone());
+            expanded.push(copy);
+        }
+
+        for alias in &task.aliases {
+            groups.insert(alias.clone(), members.clone());
+        }
+        groups.insert(task.name.clone(), members);
+    }
+
+    expanded
+}
+
+/// Retrieves the `[group.NAME]` sections, which list tasks to run
+/// together by the group's name
+pub fn get_groups(config: &str) -> HashMap<String, VecDeque<String>> {
+    let mut groups = HashMap::new();
+
+    for line in config.lines() {
+        let name = match get_section_header(line.trim()) {
+            Some(header) => match header.strip_prefix("group.") {
+                Some(name) => name,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        let members = get_queue(config, &format!("[group.{}]", name));
+        groups.insert(String::from(name), members);
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::substitute_variables;
+
+    fn get_variables(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect()
+    }
+
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rbt-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (path, text) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+
+        dir
+    }
+
+    fn load_file(path: &Path, policy: MatchPolicy) -> Config {
+        let text = std::fs::read_to_string(path).unwrap();
+        let mut chain = vec![path.canonicalize().unwrap()];
+        let stack = chain.clone();
+        load_config(path, &text, &mut chain, &stack, policy).unwrap()
+    }
+
+    fn get_commands(config: &Config, name: &str) -> Vec<String> {
+        config.task(name).unwrap().commands().to_vec()
+    }
+
+    #[test]
+    fn extending_configs_override_base_tasks() {
+        let dir = write_files(
+            "extends",
+            &[
+                (
+                    "base.cfg",
+                    "$mode = debug\n[build]\ncommand = echo base\n[lint]\ncommand = echo lint\n",
+                ),
+                (
+                    "app/build.cfg",
+                    "extends = ../base.cfg\n[build]\ncommand = echo local $mode\n",
+                ),
+            ],
+        );
+
+        let config = load_file(&dir.join("app/build.cfg"), MatchPolicy::Last);
+
+        assert_eq!(get_commands(&config, "build"), vec!["echo local $mode"]);
+        assert_eq!(get_commands(&config, "lint"), vec!["echo lint"]);
+        assert_eq!(config.variables["$mode"], "debug");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn match_policies_pick_the_first_or_last_definition() {
+        let dir = write_files(
+            "match",
+            &[
+                ("base.cfg", "[build]\ncommand = echo base\n"),
+                (
+                    "build.cfg",
+                    "extends = base.cfg\n[build]\ncommand = echo local\n[test]\ncommand = echo test\n",
+                ),
+            ],
+        );
+        let path = dir.join("build.cfg");
+
+        let first = load_file(&path, MatchPolicy::First);
+        let last = load_file(&path, MatchPolicy::Last);
+
+        assert_eq!(get_commands(&first, "build"), vec!["echo base"]);
+        assert_eq!(get_commands(&last, "build"), vec!["echo local"]);
+        assert_eq!(get_commands(&first, "test"), vec!["echo test"]);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let twice = "[t]\ncommand = echo one\n[t]\ncommand = echo two\n";
+        let first = Config::parse_with_policy(twice, MatchPolicy::First);
+        let last = Config::parse_with_policy(twice, MatchPolicy::Last);
+        assert_eq!(get_commands(&first, "t"), vec!["echo one"]);
+        assert_eq!(get_commands(&last, "t"), vec!["echo two"]);
+    }
+
+    #[test]
+    fn prefixed_env_vars_become_variables() {
+        let env = get_variables(&[
+            ("BUILD_VERSION", "1.2.3"),
+            ("BUILD_NAME", "from env"),
+            ("BUILD_", "no name"),
+            ("HOME", "/home/me"),
+        ]);
+        let mut variables = get_variables(&[("$NAME", "from config")]);
+
+        import_env_variables(&mut variables, env.into_iter(), "BUILD_");
+
+        assert_eq!(variables["$VERSION"], "1.2.3");
+        assert_eq!(variables["$NAME"], "from config");
+        assert_eq!(variables.len(), 2);
+    }
+
+    #[test]
+    fn variables_resolve_references_in_any_order() {
+        let mut variables = get_variables(&[
+            ("$out", "$bin/app"),
+            ("$bin", "${root}/bin"),
+            ("$root", "/srv"),
+        ]);
+
+        resolve_variable_references(&mut variables, &HashSet::new());
+
+        assert_eq!(variables["$bin"], "/srv/bin");
+        assert_eq!(variables["$out"], "/srv/bin/app");
+    }
+
+    #[test]
+    fn variables_can_use_variables_defined_later() {
+        let config = Config::parse(
+            "$bin = $out/bin\n$lib = ${missing}/lib\n\
+             [install]\ncommand = cp app $bin\n\
+             $out = build\n",
+        )
+        .unwrap();
+        let mut variables = config.variables.clone();
+
+        resolve_variable_references(&mut variables, &HashSet::new());
+
+        let command = &config.task("install").unwrap().commands()[0];
+        assert_eq!(
+            substitute_variables(command, &variables),
+            "cp app build/bin"
+        );
+
+        let (lib, unresolved) = interpolate_variables(&variables["$lib"], &variables, false);
+        assert_eq!(lib, "${missing}/lib");
+        assert_eq!(unresolved.as_deref(), Some("${missing}"));
+    }
+
+    #[test]
+    fn variable_reference_cycles_stop() {
+        let mut variables = get_variables(&[
+            ("$a", "$b/a"),
+            ("$b", "$a/b"),
+            ("$self", "$self/x"),
+            ("$root", "/srv"),
+            ("$out", "$root/out"),
+        ]);
+
+        resolve_variable_references(&mut variables, &HashSet::new());
+
+        assert!(references_variable(&variables["$a"], "a"));
+        assert!(references_variable(&variables["$b"], "b"));
+        assert_eq!(variables["$self"], "$self/x");
+        assert_eq!(variables["$out"], "/srv/out");
+    }
+
+    #[test]
+    fn matrix_tasks_expand_into_every_combination() {
+        let config = Config::parse(
+            "[fetch:x86_64]\ncommand = echo x86_64\n\
+             [fetch:aarch64]\ncommand = echo aarch64\n\
+             [build]\nalias = b\nmatrix.target = x86_64, aarch64\nmatrix.os = linux, macos\n\
+             depends = fetch:${matrix.target}\n\
+             command = cargo build --target ${matrix.target}-${matrix.os}\n",
+        )
+        .unwrap();
+        let names = [
+            "build:x86_64:linux",
+            "build:x86_64:macos",
+            "build:aarch64:linux",
+            "build:aarch64:macos",
+        ];
+
+        assert_eq!(config.groups["build"], names);
+        assert_eq!(config.groups["b"], names);
+        assert!(config.task("build").is_none());
+
+        let task = config.task("build:aarch64:macos").unwrap();
+        assert!(task.aliases.is_empty() && task.matrix.is_empty());
+        assert_eq!(task.depends, vec!["fetch:aarch64"]);
+        assert_eq!(
+            get_commands(&config, "build:x86_64:linux"),
+            vec!["cargo build --target x86_64-linux"]
+        );
+    }
+}
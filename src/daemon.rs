@@ -0,0 +1,515 @@
+//! `rust-build-tool daemon` keeps the config loaded in a background
+//! process listening on a socket in the config's directory. Runs with
+//! `--client` send it their command line instead of reading the config
+//! again, and get the output and exit code back. The config is only
+//! read again when one of its files changes.
+
+use super::Options;
+use std::path::Path;
+
+/// The socket the daemon listens on, in the config's directory
+pub const SOCKET: &str = ".build.sock";
+
+/// Where a daemon started in the background writes its own messages
+pub const LOG: &str = ".build.daemon.log";
+
+/// Runs `daemon start`, `daemon run` or `daemon stop` in the config's
+/// directory
+#[cfg(unix)]
+pub fn run(action: &str, options: &Options) -> i32 {
+    match action {
+        "start" => start(options),
+        "run" => serve(options),
+        "stop" => send("stop", &[]),
+        _ => {
+            error!(
+                "unknown daemon command `{}`, expected start, run or stop",
+                action
+            );
+            2
+        }
+    }
+}
+
+/// The command line a client sends, without `--client`. The config's
+/// path is made absolute since the daemon runs in another directory
+pub fn get_request(args: &[String]) -> Vec<String> {
+    let dir = std::env::current_dir().unwrap_or_default();
+    let mut request = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--client" => continue,
+            "--config" | "--config-dir" => {
+                request.push(arg.clone());
+                request.extend(
+                    args.next()
+                        .map(|path| dir.join(Path::new(path)).to_string_lossy().into_owned()),
+                );
+            }
+            _ => request.push(arg.clone()),
+        }
+    }
+
+    request
+}
+
+#[cfg(not(unix))]
+pub fn run(_action: &str, _options: &Options) -> i32 {
+    error!("the daemon needs Unix sockets, which this platform doesn't have");
+    1
+}
+
+#[cfg(not(unix))]
+pub fn send(_action: &str, _args: &[String]) -> i32 {
+    error!("--client needs Unix sockets, which this platform doesn't have");
+    1
+}
+
+#[cfg(unix)]
+pub use self::unix::send;
+#[cfg(unix)]
+use self::unix::{serve, start};
+
+#[cfg(unix)]
+mod unix {
+    use super::super::{
+        get_modified_times, is_interrupted, load_cli_config, locate_config, log, parse_args,
+        run_loaded_config, Config, MatchPolicy, Options, INTERRUPTED,
+    };
+    use super::{LOG, SOCKET};
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::os::unix::process::CommandExt;
+    use std::path::PathBuf;
+    use std::process::{Command, Stdio};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    /// Ends the output sent back to a client, followed by the exit code
+    const END: u8 = 0;
+
+    /// A config the daemon read, with what it needs to tell if it changed
+    struct Loaded {
+        /// The options that pick which config is read
+        key: (Option<String>, Option<String>, bool),
+        config: Config,
+        chain: Vec<PathBuf>,
+        modified: Vec<Option<SystemTime>>,
+    }
+
+    /// Starts `daemon run` in the background, in its own process group so
+    /// Ctrl-C in the console doesn't stop it, and waits until it listens
+    pub fn start(options: &Options) -> i32 {
+        if UnixStream::connect(SOCKET).is_ok() {
+            error!("a daemon is already running in this directory");
+            return 1;
+        }
+
+        let spawned = File::create(LOG).and_then(|log| {
+            let program = std::env::current_exe()?;
+            Command::new(program)
+                .args(["daemon", "run"])
+                .stdin(Stdio::null())
+                .stdout(log.try_clone()?)
+                .stderr(log)
+                .process_group(0)
+                .spawn()
+        });
+
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                error!("failed to start the daemon: {}", e);
+                return 1;
+            }
+        };
+
+        let start = Instant::now();
+
+        while start.elapsed() < Duration::from_secs(10) {
+            if UnixStream::connect(SOCKET).is_ok() {
+                if !options.quiet_on_success {
+                    info!(
+                        "daemon {} listening on {}, stop it with `rust-build-tool daemon stop`",
+                        child.id(),
+                        SOCKET
+                    );
+                }
+                return 0;
+            }
+
+            if let Ok(Some(_)) = child.try_wait() {
+                break;
+            }
+
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        error!("the daemon didn't start, see {}", LOG);
+        1
+    }
+
+    /// Listens for runs until `daemon stop` or Ctrl-C, handling one
+    /// at a time
+    pub fn serve(options: &Options) -> i32 {
+        if UnixStream::connect(SOCKET).is_ok() {
+            error!("a daemon is already running in this directory");
+            return 1;
+        }
+
+        // A socket left behind by a daemon that didn't stop cleanly
+        let _ = std::fs::remove_file(SOCKET);
+
+        let listener = match UnixListener::bind(SOCKET).and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        }) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("failed to listen on {}: {}", SOCKET, e);
+                return 1;
+            }
+        };
+
+        if !options.quiet_on_success {
+            info!(
+                "daemon listening on {}, stop it with `rust-build-tool daemon stop`",
+                SOCKET
+            );
+        }
+
+        let mut loaded = None;
+        let dir = std::env::current_dir().unwrap_or_default();
+
+        // Accepting is polled so Ctrl-C can stop the daemon
+        while !is_interrupted() {
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+                Err(e) => {
+                    warn!("failed to accept a client: {}", e);
+                    continue;
+                }
+            };
+
+            let mut args = match read_request(&mut stream) {
+                Ok(args) => args,
+                Err(e) => {
+                    warn!("failed to read a request: {}", e);
+                    continue;
+                }
+            };
+
+            // Connections that send nothing, like from a daemon checking
+            // if one is running, are ignored
+            match args.first().map(String::as_str) {
+                Some("run") => {
+                    args.remove(0);
+                }
+                Some("stop") => {
+                    let _ = stream.write_all(&[END, b'0']);
+                    break;
+                }
+                _ => continue,
+            }
+
+            let interrupt = match forward_interrupt(&stream) {
+                Ok(interrupt) => interrupt,
+                Err(e) => {
+                    warn!("failed to watch the client: {}", e);
+                    continue;
+                }
+            };
+
+            let code = with_output_to(&stream, || handle_request(args, &mut loaded));
+
+            // A run with `--config` may have changed to another directory
+            let _ = std::env::set_current_dir(&dir);
+
+            // Ctrl-C in the client only stops its run, not the daemon
+            if interrupt.finish(&stream) {
+                INTERRUPTED.store(false, Ordering::SeqCst);
+            }
+
+            let _ = stream.write_all(&[END]);
+            let _ = stream.write_all(code.to_string().as_bytes());
+        }
+
+        let _ = std::fs::remove_file(SOCKET);
+        0
+    }
+
+    /// Reads what a client sent: `run` or `stop`, the number of arguments
+    /// and the arguments to run with, each ending with a zero byte
+    fn read_request(stream: &mut UnixStream) -> io::Result<Vec<String>> {
+        stream.set_nonblocking(false)?;
+
+        // One byte at a time, since anything after the request is for
+        // the thread watching for an interrupt
+        let mut read_item = || -> io::Result<Option<String>> {
+            let mut item = Vec::new();
+            let mut byte = [0];
+
+            loop {
+                match stream.read(&mut byte)? {
+                    0 => return Ok(None),
+                    _ if byte[0] == 0 => return Ok(Some(String::from_utf8_lossy(&item).into())),
+                    _ => item.push(byte[0]),
+                }
+            }
+        };
+
+        let mut request = Vec::new();
+        let action = match read_item()? {
+            Some(action) => action,
+            None => return Ok(request),
+        };
+
+        let count: usize = read_item()?
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0);
+        request.push(action);
+
+        for _ in 0..count {
+            match read_item()? {
+                Some(arg) => request.push(arg),
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// The thread that interrupts the run when its client sends anything
+    /// or goes away
+    struct InterruptWatch {
+        thread: std::thread::JoinHandle<()>,
+        done: Arc<AtomicBool>,
+        interrupted: Arc<AtomicBool>,
+    }
+
+    impl InterruptWatch {
+        /// Stops watching after the run, returning whether the client
+        /// interrupted it
+        fn finish(self, stream: &UnixStream) -> bool {
+            self.done.store(true, Ordering::SeqCst);
+            let _ = stream.shutdown(Shutdown::Read);
+            let _ = self.thread.join();
+            self.interrupted.load(Ordering::SeqCst)
+        }
+    }
+
+    fn forward_interrupt(stream: &UnixStream) -> io::Result<InterruptWatch> {
+        let mut watched = stream.try_clone()?;
+        let done = Arc::new(AtomicBool::new(false));
+        let interrupted = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let done = Arc::clone(&done);
+            let interrupted = Arc::clone(&interrupted);
+
+            std::thread::spawn(move || {
+                let _ = watched.read(&mut [0]);
+
+                if !done.load(Ordering::SeqCst) {
+                    interrupted.store(true, Ordering::SeqCst);
+                    INTERRUPTED.store(true, Ordering::SeqCst);
+                }
+            })
+        };
+
+        Ok(InterruptWatch {
+            thread,
+            done,
+            interrupted,
+        })
+    }
+
+    /// Runs a command line with the config, reading it again first if it
+    /// changed or another one is asked for
+    fn handle_request(args: Vec<String>, loaded: &mut Option<Loaded>) -> i32 {
+        let mut options = parse_args(args);
+        log::set_level(options.log_level);
+        log::set_color(options.color);
+        INTERRUPTED.store(false, Ordering::SeqCst);
+
+        // The config is found the same way as without the daemon
+        if options.config_dir.is_none() {
+            if let Err(e) = locate_config(&mut options) {
+                error!("{}", e);
+                return 1;
+            }
+        }
+
+        // The run would keep the daemon busy until the client stopped it
+        if options.watch {
+            error!("--watch can't run in the daemon, run it without --client");
+            return 2;
+        }
+
+        let key = (
+            options.config.clone(),
+            options.config_dir.clone(),
+            matches!(options.match_policy, MatchPolicy::First),
+        );
+
+        let is_current = loaded.as_ref().is_some_and(|loaded| {
+            loaded.key == key && get_modified_times(&loaded.chain) == loaded.modified
+        });
+
+        if !is_current {
+            *loaded = match load_cli_config(&options) {
+                Ok(Some((config, chain))) => Some(Loaded {
+                    key,
+                    modified: get_modified_times(&chain),
+                    config,
+                    chain,
+                }),
+                Ok(None) => return 0,
+                Err(e) => {
+                    error!("{}", e);
+                    return 1;
+                }
+            };
+        }
+
+        match loaded {
+            Some(loaded) => {
+                run_loaded_config(loaded.config.clone(), loaded.chain.clone(), &options)
+            }
+            None => 0,
+        }
+    }
+
+    /// Runs `f` with stdout and stderr going to the client, so tasks
+    /// print to it like they would to a console
+    fn with_output_to(stream: &UnixStream, f: impl FnOnce() -> i32) -> i32 {
+        extern "C" {
+            fn dup(fd: i32) -> i32;
+            fn dup2(fd: i32, new_fd: i32) -> i32;
+        }
+
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+
+        let (stdout, stderr) = unsafe { (dup(1), dup(2)) };
+        if stdout < 0 || stderr < 0 {
+            warn!("failed to send the output to the client");
+            return 1;
+        }
+
+        unsafe {
+            dup2(stream.as_raw_fd(), 1);
+            dup2(stream.as_raw_fd(), 2);
+        }
+
+        // Printing panics once a client goes away, which only ends its run
+        let code = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(1);
+
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+
+        unsafe {
+            dup2(stdout, 1);
+            dup2(stderr, 2);
+            drop(File::from_raw_fd(stdout));
+            drop(File::from_raw_fd(stderr));
+        }
+
+        code
+    }
+
+    /// Sends `run` with a command line or `stop` to the daemon, printing
+    /// the output as it arrives. Returns the exit code of the run
+    pub fn send(action: &str, args: &[String]) -> i32 {
+        let mut stream = match UnixStream::connect(SOCKET) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!(
+                    "no daemon is listening on {} ({}), start one with `rust-build-tool daemon`",
+                    SOCKET, e
+                );
+                return 1;
+            }
+        };
+
+        let count = args.len().to_string();
+        let request: String = [action, &count]
+            .iter()
+            .copied()
+            .chain(args.iter().map(String::as_str))
+            .map(|arg| format!("{}\0", arg))
+            .collect();
+
+        // Reads time out so Ctrl-C can be passed on to the daemon
+        let sent = stream
+            .write_all(request.as_bytes())
+            .and_then(|_| stream.set_read_timeout(Some(Duration::from_millis(100))));
+
+        if let Err(e) = sent {
+            error!("failed to send the request: {}", e);
+            return 1;
+        }
+
+        let mut stdout = io::stdout();
+        let mut pending = Vec::new();
+        let mut buffer = [0; 8192];
+        let mut interrupt_sent = false;
+
+        loop {
+            let read = match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if is_timeout(&e) => {
+                    if is_interrupted() && !interrupt_sent {
+                        let _ = stream.write_all(&[END]);
+                        interrupt_sent = true;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    error!("lost the connection to the daemon: {}", e);
+                    return 1;
+                }
+            };
+
+            pending.extend_from_slice(&buffer[..read]);
+
+            // What follows the last zero byte may be the exit code, so
+            // it is held back until more arrives
+            let end = pending
+                .iter()
+                .rposition(|&byte| byte == END)
+                .unwrap_or(pending.len());
+            let _ = stdout.write_all(&pending[..end]);
+            let _ = stdout.flush();
+            pending.drain(..end);
+        }
+
+        match pending.split_first() {
+            Some((&END, code)) => String::from_utf8_lossy(code).parse().unwrap_or(1),
+            _ => {
+                let _ = stdout.write_all(&pending);
+                error!("the daemon stopped before the run finished");
+                1
+            }
+        }
+    }
+
+    /// Whether a read only stopped for the timeout or a signal
+    fn is_timeout(e: &io::Error) -> bool {
+        matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+        )
+    }
+}
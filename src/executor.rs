@@ -0,0 +1,2376 @@
+//! Executes a planned run: starts each task once the tasks it needs
+//! succeeded, one at a time or with async tasks at the same time,
+//! reports their results and the summary at the end.
+
+use super::plan::{get_prerequisites, waits_for};
+use super::{
+    artifacts, build_command, condition, expand_glob, format_duration, get_cache_dir,
+    get_command_line, get_container_error, get_date, get_durations_json, get_echo_line,
+    get_setting, get_transcript_command, get_transcript_output, is_interrupted, json_string, log,
+    matches_pattern, paint, parse_duration_setting, print_command_details, print_event, progress,
+    resolve_command_substitutions, run_config, substitute_variables, write_transcript, Config,
+    ExitCategory, Options, Task, BUILD_CACHE, CYAN, DIM, FNV_OFFSET, GREEN, INTERRUPTED,
+    INTERRUPT_EXIT_CODE, LINE_OPEN, RED, SIGKILL, SIGTERM, TIMEOUT_EXIT_CODE,
+};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// A run of a planned queue: the tasks and options it runs with, the
+/// async tasks still running and what happened so far
+pub struct Run<'a> {
+    tasks: &'a HashMap<String, Task>,
+    settings: &'a HashMap<String, String>,
+    exit_categories: &'a [ExitCategory],
+    options: &'a Options,
+    /// Grows as tasks finish with `capture` and run `$(command)`s
+    variables: HashMap<String, String>,
+    children: Vec<RunningTask>,
+    state: RunState,
+    /// Whether commands are printed before they run
+    echo: bool,
+}
+
+impl<'a> Run<'a> {
+    pub fn new(
+        tasks: &'a HashMap<String, Task>,
+        variables: HashMap<String, String>,
+        settings: &'a HashMap<String, String>,
+        exit_categories: &'a [ExitCategory],
+        options: &'a Options,
+    ) -> Run<'a> {
+        Run {
+            tasks,
+            settings,
+            exit_categories,
+            options,
+            variables,
+            children: Vec::new(),
+            state: RunState::new(get_cache_dir(settings, options), options),
+            echo: options.echo || get_setting(settings, "echo").unwrap_or(false),
+        }
+    }
+
+    /// Runs the queue and reports how the run went. Returns the exit
+    /// code: the first failed task's code when one fails
+    pub fn execute(mut self, mut queue: VecDeque<String>, interrupt_tasks: &[String]) -> i32 {
+        let tasks = self.tasks;
+        let options = self.options;
+
+        if let Some(jobs) = options.jobs {
+            if !options.quiet_on_success {
+                info!("running up to {} task(s) at once", jobs);
+            }
+        }
+
+        // The cache is only touched when a task declares inputs or outputs
+        let tracks_files = tasks
+            .values()
+            .any(|task| !task.inputs.is_empty() || !task.outputs.is_empty());
+
+        if tracks_files {
+            self.state.cache = read_build_cache();
+        }
+
+        let mut started: Vec<&Task> = Vec::new();
+        let mut stopped = false;
+        let run_start = Instant::now();
+
+        while let Some(task_name) = queue.pop_front() {
+            // The running tasks are stopped by the loop below
+            if is_interrupted() {
+                break;
+            }
+
+            let task = match tasks.get(&task_name) {
+                Some(task) => task,
+                None => continue,
+            };
+
+            // Nothing else is started once a task fails, unless --keep-going.
+            // The `after` tasks of tasks that started still run.
+            let is_after_hook = started.iter().any(|s| s.after.contains(&task_name));
+
+            if !options.keep_going && !is_after_hook {
+                if let Some(failed) = self.state.failed.first() {
+                    if !stopped {
+                        info!("task({}) failed, not running the rest", failed);
+                        stopped = true;
+                    }
+                    continue;
+                }
+            }
+
+            self.state.total += 1;
+
+            // Async tasks wait for the tasks they need that are still running
+            while self
+                .children
+                .iter()
+                .any(|c| waits_for(task, &tasks[&c.name]))
+            {
+                self.poll();
+            }
+
+            if !self.should_run(task) {
+                continue;
+            }
+
+            started.push(task);
+
+            let (command, temp_dir) = match self.prepare(task) {
+                Some(prepared) => prepared,
+                None => continue,
+            };
+
+            if !options.is_async || task.interactive {
+                self.run_task(task, command, queue.len());
+                drop(temp_dir);
+            } else {
+                self.spawn_task(task, command, temp_dir);
+            }
+        }
+
+        self.wait_for_children();
+
+        if tracks_files {
+            write_build_cache(&self.state.cache);
+        }
+
+        self.finish(interrupt_tasks, run_start)
+    }
+
+    /// Whether a task runs: it is skipped if a task it needs failed or
+    /// its `when` condition is false
+    fn should_run(&mut self, task: &Task) -> bool {
+        // A task whose dependency failed can't run either
+        if let Some(failed) = get_prerequisites(task).find(|d| self.state.failed.contains(d)) {
+            println!("task({}): skipped, {} failed", task.name, failed);
+            self.state.fail_as(task, 1, "skipped");
+            return false;
+        }
+
+        // Output captured by tasks that finished can be used from here
+        // on, in the condition too
+        self.variables.extend(self.state.captured.drain(..));
+
+        let when = match &task.when {
+            Some(when) => when,
+            None => return true,
+        };
+
+        match condition::evaluate(&substitute_variables(when, &self.variables), &task.base_dir) {
+            Ok(true) => true,
+            Ok(false) => {
+                if !self.options.quiet_on_success {
+                    println!("task({}): skipped, `{}` is false", task.name, when);
+                }
+                self.state.record_result(task, "skipped", None);
+                false
+            }
+            Err(e) => {
+                error!(
+                    "task({}): invalid when condition `{}`: {}",
+                    task.name, when, e
+                );
+                self.state.fail(task, 1);
+                false
+            }
+        }
+    }
+
+    /// Builds the command a task runs, with its temp dir. Returns None if
+    /// it can't be built or the task doesn't need to run, because it is up
+    /// to date or its outputs were restored from the cache
+    fn prepare(&mut self, task: &Task) -> Option<(Command, Option<TempDir>)> {
+        let options = self.options;
+        let quiet = options.quiet_on_success;
+
+        resolve_command_substitutions(task, &mut self.variables);
+
+        let temp_dir = create_temp_dir(&task.name, &mut self.variables, options);
+
+        let mut command = match build_command(task, &self.variables, options) {
+            Some(command) => command,
+            None => {
+                self.state.fail(task, 1);
+                return None;
+            }
+        };
+
+        if !task.inputs.is_empty() || !task.outputs.is_empty() {
+            let hash = hash_inputs(task, &get_command_line(&command));
+
+            if !options.force && is_up_to_date(task, hash, &self.state.cache) {
+                if !quiet {
+                    println!("task({}): up to date", task.name);
+                }
+                self.state.record_result(task, "up_to_date", None);
+                return None;
+            }
+
+            // Another run, maybe on another machine, may have built the
+            // same inputs already
+            let cached = match &self.state.artifact_cache {
+                Some(dir) if !options.force && !task.outputs.is_empty() => {
+                    artifacts::restore(dir, task, hash).unwrap_or_else(|e| {
+                        warn!(
+                            "task({}): failed to restore from the cache: {}",
+                            task.name, e
+                        );
+                        false
+                    })
+                }
+                _ => false,
+            };
+
+            if cached {
+                if !quiet {
+                    println!("task({}): restored from the cache", task.name);
+                }
+                self.state.cache.insert(task.name.clone(), hash);
+                self.state.record_result(task, "cached", None);
+                return None;
+            }
+
+            self.state.input_hashes.insert(task.name.clone(), hash);
+        }
+
+        if self.echo && !task.no_echo {
+            println!("{}", get_echo_line(&command));
+        }
+
+        set_temp_dir_env(&mut command, &temp_dir);
+        print_command_details(&task.name, &command);
+        write_transcript(&get_transcript_command(&task.name, &command));
+
+        Some((command, temp_dir))
+    }
+
+    /// Runs a task to completion, retrying it while it has retries left.
+    /// `remaining` is the number of tasks queued after it
+    fn run_task(&mut self, task: &Task, mut command: Command, remaining: usize) {
+        let options = self.options;
+        let quiet = options.quiet_on_success;
+
+        // Interactive tasks have the console to themselves, so they wait
+        // for the running tasks and run on their own
+        if task.interactive {
+            self.wait_for_children();
+        }
+
+        // The task writes to the console itself, so the line can't be
+        // left open for the result
+        if !quiet && task.interactive {
+            println!("task({}): started", task.name);
+        } else if !quiet {
+            let total = self.state.total;
+            print_started(&task.name, Some((total, total + remaining)));
+        }
+        self.state.start(&task.name);
+
+        let (retries, delay) = get_retry_policy(task, self.settings);
+        let mut attempt = 0;
+        let start = Instant::now();
+
+        loop {
+            match run_command(&mut command, task, options) {
+                Ok((_, true, _)) => {
+                    print_timed_out(task, options);
+                    self.state.fail_as(task, TIMEOUT_EXIT_CODE, "timed_out");
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    let interrupted = paint("interrupted", RED, options);
+                    println!("{}task({}): {}", line_start(), task.name, interrupted);
+                    self.state.fail_as(task, INTERRUPT_EXIT_CODE, "interrupted");
+                }
+                Err(e) => {
+                    println!(
+                        "{}task({}): failed to execute\n{}",
+                        line_start(),
+                        task.name,
+                        e
+                    );
+                    self.state.fail(task, 1);
+                }
+                Ok((output, false, peak_memory)) => {
+                    let success = is_task_success(task, &output);
+                    if !success && attempt < retries {
+                        attempt += 1;
+                        if !quiet {
+                            print_retry(task, attempt, retries, delay, &output);
+                        }
+                        std::thread::sleep(delay);
+                        continue;
+                    }
+
+                    if !success && retries > 0 {
+                        println!(
+                            "{}task({}): failed all {} attempts",
+                            line_start(),
+                            task.name,
+                            retries + 1
+                        );
+                    }
+
+                    let usage = (start.elapsed(), peak_memory);
+                    self.state
+                        .finish_task(task, output, usage, self.exit_categories, options);
+                }
+            }
+
+            break;
+        }
+
+        self.state
+            .durations
+            .insert(task.name.clone(), start.elapsed());
+    }
+
+    /// Starts an async task, once there is a free slot when the number of
+    /// jobs is limited. It is checked on by `poll`
+    fn spawn_task(&mut self, task: &Task, mut command: Command, temp_dir: Option<TempDir>) {
+        let options = self.options;
+
+        while options.jobs.is_some_and(|jobs| self.children.len() >= jobs) {
+            self.poll();
+        }
+
+        if !options.quiet_on_success {
+            println!("task({}): started", task.name);
+        }
+        self.state.start(&task.name);
+
+        match spawn_async_task(&mut command, task, options) {
+            Ok((child, reader)) => {
+                self.children.push(RunningTask {
+                    name: task.name.clone(),
+                    child,
+                    attempt: 0,
+                    start: Instant::now(),
+                    terminated_at: None,
+                    temp_dir,
+                    reader,
+                });
+            }
+            Err(e) => {
+                println!("task({}): failed to spawn\n{}", task.name, e);
+                self.state.fail(task, 1);
+            }
+        }
+    }
+
+    fn wait_for_children(&mut self) {
+        while !self.children.is_empty() {
+            self.poll();
+        }
+    }
+
+    /// Reports the end of the run, running the `on_interrupt` tasks if it
+    /// was interrupted. Returns the exit code
+    fn finish(mut self, interrupt_tasks: &[String], run_start: Instant) -> i32 {
+        let options = self.options;
+        let state = &self.state;
+
+        if is_interrupted() {
+            info!("interrupted, stopped the running tasks");
+            run_interrupt_tasks(interrupt_tasks, self.tasks, &mut self.variables, options);
+            print_done_event(&self.state, INTERRUPT_EXIT_CODE, options);
+            write_transcript(&format!(
+                "run interrupted after {}",
+                format_duration(run_start.elapsed())
+            ));
+            return INTERRUPT_EXIT_CODE;
+        }
+
+        if options.quiet_on_success && !state.failed.is_empty() {
+            println!(
+                "summary: {} of {} task(s) failed: {}",
+                state.failed.len(),
+                state.total,
+                state.failed.join(", ")
+            );
+        }
+
+        if !state.results.is_empty() && !options.quiet_on_success {
+            print_summary(state, run_start.elapsed(), options);
+        }
+
+        if options.print_duration_json {
+            println!("{}", get_durations_json(&state.durations));
+        }
+
+        let code = if state.failed.is_empty() {
+            0
+        } else {
+            state.exit_code.unwrap_or(1)
+        };
+
+        print_done_event(state, code, options);
+        write_transcript(&format!(
+            "run finished with exit code {} in {}",
+            code,
+            format_duration(run_start.elapsed())
+        ));
+        code
+    }
+
+    /// Checks every running task once, reporting the ones that finished
+    /// and spawning failed ones again while they have retries left
+    fn poll(&mut self) {
+        let tasks = self.tasks;
+        let settings = self.settings;
+        let exit_categories = self.exit_categories;
+        let options = self.options;
+        let variables = &self.variables;
+        let children = &mut self.children;
+        let state = &mut self.state;
+
+        let quiet = options.quiet_on_success;
+        let mut running = Vec::new();
+
+        for mut running_task in children.drain(..) {
+            let task_name = running_task.name.clone();
+
+            if is_interrupted() {
+                stop_child(&mut running_task.child, &tasks[&task_name], options);
+                let interrupted = paint("interrupted", RED, options);
+                println!("task({}): {}", task_name, interrupted);
+                state.fail_as(&tasks[&task_name], INTERRUPT_EXIT_CODE, "interrupted");
+                continue;
+            }
+
+            match try_wait_child(&mut running_task.child, options) {
+                Ok(Some((status, peak_memory))) => {
+                    let task = &tasks[&task_name];
+                    let (retries, delay) = get_retry_policy(task, settings);
+                    let attempt = running_task.attempt;
+                    let start = running_task.start;
+
+                    // Tasks without a reader print straight to the console
+                    let (stdout, stderr) = match running_task.reader.take() {
+                        Some(reader) => reader.join().unwrap_or_default(),
+                        None => Default::default(),
+                    };
+                    let output = Output {
+                        status,
+                        stdout,
+                        stderr,
+                    };
+
+                    // Timed out tasks aren't retried
+                    if running_task.terminated_at.is_some() {
+                        print_timed_out(task, options);
+                        state.durations.insert(task_name.clone(), start.elapsed());
+                        state.fail_as(task, TIMEOUT_EXIT_CODE, "timed_out");
+                        continue;
+                    }
+
+                    // Failed tasks are spawned again until they run out of retries
+                    let success = is_task_success(task, &output);
+                    if !success && attempt < retries {
+                        if !quiet {
+                            print_retry(task, attempt + 1, retries, delay, &output);
+                        }
+                        std::thread::sleep(delay);
+
+                        // The retry keeps using the same temp dir
+                        let temp_dir = running_task.temp_dir.take();
+                        let mut variables = variables.clone();
+
+                        if let Some(temp_dir) = &temp_dir {
+                            variables.insert(
+                                String::from("$TMPDIR_TASK"),
+                                temp_dir.path.to_string_lossy().into_owned(),
+                            );
+                        }
+
+                        let spawned =
+                            build_command(task, &variables, options).map(|mut command| {
+                                set_temp_dir_env(&mut command, &temp_dir);
+                                spawn_async_task(&mut command, task, options)
+                            });
+
+                        match spawned {
+                            Some(Ok((child, reader))) => running.push(RunningTask {
+                                name: task_name,
+                                child,
+                                attempt: attempt + 1,
+                                start,
+                                terminated_at: None,
+                                temp_dir,
+                                reader,
+                            }),
+                            Some(Err(e)) => {
+                                println!("task({}): failed to spawn\n{}", task_name, e);
+                                state.fail(task, 1);
+                            }
+                            None => {}
+                        }
+
+                        continue;
+                    }
+
+                    if !success && retries > 0 {
+                        println!("task({}): failed all {} attempts", task_name, retries + 1);
+                    }
+
+                    state.durations.insert(task_name.clone(), start.elapsed());
+
+                    let usage = (start.elapsed(), peak_memory);
+                    state.finish_task(task, output, usage, exit_categories, options);
+                }
+                Err(e) => {
+                    println!("task({}): process failed\n{}", task_name, e);
+                    state.fail(&tasks[&task_name], 1);
+                }
+                Ok(None) => {
+                    enforce_timeout(
+                        &mut running_task.child,
+                        running_task.start,
+                        &mut running_task.terminated_at,
+                        &tasks[&task_name],
+                        options,
+                    );
+                    running.push(running_task);
+                }
+            }
+        }
+
+        *children = running;
+
+        // Avoid spinning while the children are still running
+        if !children.is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Finds the category of a finished task's exit code. The first
+/// matching entry wins.
+fn get_exit_category<'a>(categories: &'a [ExitCategory], output: &Output) -> Option<&'a str> {
+    let code = output.status.code()?;
+
+    categories
+        .iter()
+        .find(|category| category.codes.contains(&code))
+        .map(|category| category.name.as_str())
+}
+
+/// Returns how many times a task is retried and the delay between
+/// attempts. Task options override the global settings.
+fn get_retry_policy(task: &Task, settings: &HashMap<String, String>) -> (u32, Duration) {
+    let retries = task.retries.or_else(|| get_setting(settings, "retries"));
+    let delay = task.retry_delay.or_else(|| {
+        settings
+            .get("retry_delay")
+            .and_then(|value| parse_duration_setting("settings", "retry_delay", value))
+    });
+
+    (retries.unwrap_or(0), delay.unwrap_or_default())
+}
+
+/// Logs a failed attempt of a task that is about to be retried
+fn print_retry(task: &Task, attempt: u32, retries: u32, delay: Duration, output: &Output) {
+    let reason = match output.status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => String::from("a signal"),
+    };
+
+    println!(
+        "{}task({}): attempt {}/{} failed with {}, retrying in {}s",
+        line_start(),
+        task.name,
+        attempt,
+        retries + 1,
+        reason,
+        delay.as_secs_f64()
+    );
+}
+
+/// Keeps only the lines of the output matching the task's filter
+fn filter_output(output: &str, filter: Option<&str>) -> String {
+    match filter {
+        Some(filter) => output
+            .lines()
+            .filter(|line| matches_pattern(filter, line))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => String::from(output),
+    }
+}
+
+/// Decides if a task succeeded. Tasks with `success_if` succeed when
+/// any line of their output matches it, regardless of the exit code.
+/// Tasks with `fail_on_stderr` fail if they wrote anything to stderr
+fn is_task_success(task: &Task, output: &Output) -> bool {
+    let wrote_stderr = output.stderr.iter().any(|b| !b.is_ascii_whitespace());
+    if task.fail_on_stderr == Some(true) && wrote_stderr {
+        return false;
+    }
+
+    match &task.success_if {
+        Some(pattern) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+
+            stdout
+                .lines()
+                .chain(stderr.lines())
+                .any(|line| matches_pattern(pattern, line))
+        }
+        None => output.status.success(),
+    }
+}
+
+/// On a terminal task results replace the progress line, when the
+/// output is redirected every line is printed on its own instead
+fn line_start() -> &'static str {
+    if LINE_OPEN.swap(false, Ordering::SeqCst) {
+        progress::CLEAR_LINE
+    } else {
+        ""
+    }
+}
+
+/// Prints that a task started. A terminal shows a progress line instead
+/// until the result replaces it, with the task's place in the queue
+fn print_started(task_name: &str, place: Option<(usize, usize)>) {
+    if std::io::stdout().is_terminal() {
+        let label = match place {
+            Some((position, total)) => format!("[{}/{}] task({})", position, total, task_name),
+            None => format!("task({})", task_name),
+        };
+        progress::start(label);
+    } else {
+        println!("task({}): started", task_name);
+    }
+}
+
+/// Converts a task's output to text. Invalid UTF-8 is replaced rather
+/// than dropped so the output is never silently lost
+fn decode_output(task: &Task, bytes: &[u8]) -> String {
+    match String::from_utf8_lossy(bytes) {
+        std::borrow::Cow::Borrowed(text) => String::from(text),
+        std::borrow::Cow::Owned(text) => {
+            warn!("task({}) produced non-UTF-8 output", task.name);
+            text
+        }
+    }
+}
+
+/// Keeps only the last `count` lines of the output
+fn tail_output(output: &str, count: usize) -> String {
+    let lines: Vec<&str> = output.lines().collect();
+    let skip = lines.len().saturating_sub(count);
+    lines[skip..].join("\n")
+}
+
+/// The output shown for a failed task, filtered and limited to the last
+/// lines with `--tail-on-failure`. That's its stderr, or everything it
+/// printed with `--quiet-on-success` since nothing was shown yet
+fn get_failed_output(task: &Task, output: &Output, options: &Options) -> String {
+    // Merged output is all in stdout
+    let bytes = if options.quiet_on_success {
+        [output.stdout.as_slice(), &output.stderr].concat()
+    } else if options.merge_streams {
+        output.stdout.clone()
+    } else {
+        output.stderr.clone()
+    };
+
+    let output = filter_output(&decode_output(task, &bytes), task.filter.as_deref());
+
+    match options.tail_on_failure {
+        Some(count) => tail_output(&output, count),
+        None => output,
+    }
+}
+
+/// Outputs the result of the task and returns whether it succeeded.
+/// How long it took and `details` like the exit category are shown next
+/// to the result. Failed output can be limited to the last lines with
+/// `--tail-on-failure`
+fn output_task_result(
+    task: &Task,
+    output: Output,
+    duration: Option<Duration>,
+    details: Option<&str>,
+    options: &Options,
+) -> bool {
+    let filter = task.filter.as_deref();
+    let success = is_task_success(task, &output);
+
+    let mut suffix = match duration {
+        Some(duration) if success => format!(" in {}", format_duration(duration)),
+        Some(duration) => format!(" after {}", format_duration(duration)),
+        None => String::new(),
+    };
+
+    if let Some(details) = details {
+        suffix.push_str(&format!(" ({})", details));
+    }
+
+    if options.quiet_on_success {
+        // Only failures are shown, along with everything they printed
+        if !success {
+            println!(
+                "task({}): {}{}",
+                task.name,
+                paint("failed", RED, options),
+                suffix
+            );
+
+            let failed = get_failed_output(task, &output, options);
+
+            if !failed.is_empty() {
+                println!("\n{}", failed);
+            }
+        }
+
+        return success;
+    }
+
+    // Streamed output was already shown as it arrived
+    if options.stream_output {
+        let result = if success {
+            paint("finished", GREEN, options)
+        } else {
+            paint("failed", RED, options)
+        };
+
+        if [&output.stdout, &output.stderr]
+            .iter()
+            .any(|bytes| std::str::from_utf8(bytes).is_err())
+        {
+            warn!("task({}) produced non-UTF-8 output", task.name);
+        }
+
+        println!("{}task({}): {}{}", line_start(), task.name, result, suffix);
+        return success;
+    }
+
+    if success {
+        println!(
+            "{}task({}): {}{}",
+            line_start(),
+            task.name,
+            paint("finished", GREEN, options),
+            suffix
+        );
+        let stdout = filter_output(&decode_output(task, &output.stdout), filter);
+        if !stdout.is_empty() {
+            println!("\n{}", stdout);
+        }
+    } else {
+        println!(
+            "{}task({}): {}{}",
+            line_start(),
+            task.name,
+            paint("failed", RED, options),
+            suffix
+        );
+        let stderr = get_failed_output(task, &output, options);
+
+        if !stderr.is_empty() {
+            println!("\n{}", stderr);
+        }
+    }
+
+    success
+}
+
+/// A scratch directory for one task, removed when it's dropped unless
+/// `--keep-temp` was passed
+struct TempDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("failed to remove temp dir {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Creates a unique temp directory for a task under the system temp
+/// location and sets `$TMPDIR_TASK` to it
+fn create_temp_dir(
+    task_name: &str,
+    variables: &mut HashMap<String, String>,
+    options: &Options,
+) -> Option<TempDir> {
+    let name: String = task_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    let mut path = PathBuf::new();
+    let mut created = Err(std::io::Error::other("no attempts"));
+
+    for attempt in 0..100 {
+        path = std::env::temp_dir().join(format!(
+            "rust-build-tool-{}-{}-{}",
+            name,
+            std::process::id(),
+            attempt
+        ));
+
+        created = std::fs::create_dir(&path);
+
+        if !created
+            .as_ref()
+            .is_err_and(|e| e.kind() == std::io::ErrorKind::AlreadyExists)
+        {
+            break;
+        }
+    }
+
+    if let Err(e) = created {
+        warn!("task({}): failed to create temp dir: {}", task_name, e);
+        variables.remove("$TMPDIR_TASK");
+        return None;
+    }
+
+    if options.keep_temp {
+        info!("task({}): temp dir {}", task_name, path.display());
+    }
+
+    variables.insert(
+        String::from("$TMPDIR_TASK"),
+        path.to_string_lossy().into_owned(),
+    );
+
+    Some(TempDir {
+        path,
+        keep: options.keep_temp,
+    })
+}
+
+/// Also exposes a task's temp dir to the child as the `TMPDIR_TASK`
+/// environment variable
+fn set_temp_dir_env(command: &mut Command, temp_dir: &Option<TempDir>) {
+    if let Some(temp_dir) = temp_dir {
+        command.env("TMPDIR_TASK", &temp_dir.path);
+    }
+}
+
+/// A task that was spawned and hasn't finished yet
+struct RunningTask {
+    name: String,
+    child: Child,
+    attempt: u32,
+    start: Instant,
+    terminated_at: Option<Instant>,
+    temp_dir: Option<TempDir>,
+    reader: Option<OutputReader>,
+}
+
+/// What happened to the tasks that have run so far
+struct RunState {
+    durations: BTreeMap<String, Duration>,
+    failed: Vec<String>,
+    categories: Vec<(String, i32, String)>,
+    total: usize,
+    /// The exit code of the first task that failed
+    exit_code: Option<i32>,
+    /// Input hashes of running tasks, saved to the cache if they succeed
+    input_hashes: HashMap<String, u64>,
+    cache: BTreeMap<String, u64>,
+    /// When each task started, for the `--output json` events
+    started_at: HashMap<String, Instant>,
+    /// How each task ended, like `finished` or `skipped`, in that order
+    results: Vec<(String, &'static str)>,
+    json_output: bool,
+    /// Variables set from the output of tasks with `capture`
+    captured: Vec<(String, String)>,
+    /// The shared artifact cache from `cache_dir`, if any
+    artifact_cache: Option<PathBuf>,
+}
+
+impl RunState {
+    fn new(artifact_cache: Option<PathBuf>, options: &Options) -> RunState {
+        RunState {
+            durations: BTreeMap::new(),
+            failed: Vec::new(),
+            categories: Vec::new(),
+            total: 0,
+            exit_code: None,
+            input_hashes: HashMap::new(),
+            cache: BTreeMap::new(),
+            started_at: HashMap::new(),
+            results: Vec::new(),
+            json_output: options.json_output,
+            captured: Vec::new(),
+            artifact_cache,
+        }
+    }
+
+    /// Records that a task started
+    fn start(&mut self, task_name: &str) {
+        self.started_at
+            .insert(String::from(task_name), Instant::now());
+
+        if self.json_output {
+            print_event(&format!(
+                r#"{{"event":"started","task":{}}}"#,
+                json_string(task_name)
+            ));
+        }
+    }
+
+    /// Records how a task ended and prints its `--output json` event
+    fn record_result(&mut self, task: &Task, status: &'static str, code: Option<i32>) {
+        self.results.push((task.name.clone(), status));
+        write_transcript(&format!("task({}): {}", task.name, status));
+
+        if self.json_output {
+            print_event(&self.get_finished_event(&task.name, status, code));
+        }
+    }
+
+    /// The `--output json` event for a task that ended, with the exit
+    /// category of its exit code
+    fn get_finished_event(&self, task_name: &str, status: &str, code: Option<i32>) -> String {
+        let duration = match self.started_at.get(task_name) {
+            Some(start) => format!("{:.3}", start.elapsed().as_secs_f64()),
+            None => String::from("null"),
+        };
+        let code = code.map_or(String::from("null"), |code| code.to_string());
+
+        format!(
+            r#"{{"event":"finished","task":{},"status":"{}","exit_code":{},"category":{},"duration":{}}}"#,
+            json_string(task_name),
+            status,
+            code,
+            self.get_category_json(task_name),
+            duration
+        )
+    }
+
+    /// The exit category a task's exit code got as JSON, `null` if none
+    fn get_category_json(&self, task_name: &str) -> String {
+        self.categories
+            .iter()
+            .find(|(name, _, _)| name == task_name)
+            .map_or(String::from("null"), |(_, _, category)| {
+                json_string(category)
+            })
+    }
+
+    /// Records a failed task and the exit code it failed with. Tasks with
+    /// `allow_failure` don't count as failed, so they don't stop the run or
+    /// skip their dependents
+    fn fail(&mut self, task: &Task, code: i32) {
+        self.fail_as(task, code, "failed");
+    }
+
+    /// Records a failed task, with the status shown for it in `--output json`,
+    /// like `timed_out`
+    fn fail_as(&mut self, task: &Task, code: i32, status: &'static str) {
+        self.record_result(task, status, Some(code));
+
+        if !task.allow_failure {
+            self.failed.push(task.name.clone());
+            self.exit_code = self.exit_code.or(Some(code));
+        }
+    }
+
+    /// Reports a finished task's output and records the result. `usage`
+    /// is how long the task took and its peak memory, if known
+    fn finish_task(
+        &mut self,
+        task: &Task,
+        output: Output,
+        usage: (Duration, Option<u64>),
+        exit_categories: &[ExitCategory],
+        options: &Options,
+    ) {
+        let code = output.status.code();
+        let category = get_exit_category(exit_categories, &output).map(String::from);
+
+        if let (Some(code), Some(category)) = (code, &category) {
+            self.categories
+                .push((task.name.clone(), code, category.clone()));
+        }
+
+        if let Some(template) = &task.log_file {
+            write_log_file(task, template, &output);
+        }
+
+        let (duration, peak_memory) = usage;
+        let mut details: Vec<String> = category.into_iter().collect();
+
+        if task.container.is_some() {
+            details.extend(code.and_then(get_container_error).map(String::from));
+        }
+
+        if options.profile_memory {
+            details.extend(peak_memory.map(|peak| format!("peak {}", format_size(peak))));
+        }
+
+        let details = (!details.is_empty()).then(|| details.join(", "));
+
+        let hash = self.input_hashes.remove(&task.name);
+        write_transcript(&get_transcript_output(task, &output, duration));
+        let captured = task.capture.as_ref().map(|name| {
+            let value = String::from_utf8_lossy(&output.stdout);
+            (name.clone(), String::from(value.trim_end()))
+        });
+
+        // Tasks can fail with 0, like when success_if doesn't match
+        let code = code.filter(|&code| code != 0).unwrap_or(1);
+
+        if !output_task_result(task, output, Some(duration), details.as_deref(), options) {
+            self.fail(task, code);
+        } else {
+            self.record_result(task, "finished", Some(0));
+
+            if let Some(hash) = hash {
+                self.cache.insert(task.name.clone(), hash);
+
+                if let Some(dir) = self
+                    .artifact_cache
+                    .as_ref()
+                    .filter(|_| !task.outputs.is_empty())
+                {
+                    if let Err(e) = artifacts::store(dir, task, hash) {
+                        warn!(
+                            "task({}): failed to save outputs to the cache: {}",
+                            task.name, e
+                        );
+                    }
+                }
+            }
+
+            self.captured.extend(captured);
+        }
+    }
+}
+
+/// Reads the input hashes saved by previous runs, one `task hash` per line
+fn read_build_cache() -> BTreeMap<String, u64> {
+    let text = std::fs::read_to_string(BUILD_CACHE).unwrap_or_default();
+
+    text.lines()
+        .filter_map(|line| {
+            let (name, hash) = line.rsplit_once(' ')?;
+            let hash = u64::from_str_radix(hash, 16).ok()?;
+            Some((String::from(name), hash))
+        })
+        .collect()
+}
+
+/// Saves the input hashes of the tasks that succeeded
+fn write_build_cache(cache: &BTreeMap<String, u64>) {
+    let text: String = cache
+        .iter()
+        .map(|(name, hash)| format!("{} {:016x}\n", name, hash))
+        .collect();
+
+    if let Err(e) = std::fs::write(BUILD_CACHE, text) {
+        warn!("failed to write {}: {}", BUILD_CACHE, e);
+    }
+}
+
+/// Hashes the contents of a task's inputs along with its command line,
+/// so changing either makes the task run again
+fn hash_inputs(task: &Task, command_line: &str) -> u64 {
+    // Each part ends with a zero byte, so moving text from one to the
+    // next changes the hash
+    let mut hash = FNV_OFFSET;
+    let mut feed = |bytes: &[u8]| hash = hash_bytes(hash_bytes(hash, bytes), &[0]);
+
+    feed(command_line.as_bytes());
+
+    let mut files: Vec<PathBuf> = task
+        .inputs
+        .iter()
+        .flat_map(|pattern| expand_glob(&task.base_dir, pattern))
+        .collect();
+    files.sort();
+
+    for file in files {
+        feed(file.to_string_lossy().as_bytes());
+        feed(&std::fs::read(&file).unwrap_or_default());
+    }
+
+    hash
+}
+
+/// Continues an FNV-1a hash with more bytes. FNV-1a is simple and stable
+/// between runs, so hashes can be saved
+pub fn hash_bytes(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A task is up to date when all of its outputs exist and its inputs
+/// are unchanged since it last succeeded, or are older than the outputs
+fn is_up_to_date(task: &Task, hash: u64, cache: &BTreeMap<String, u64>) -> bool {
+    if task.inputs.is_empty() && task.outputs.is_empty() {
+        return false;
+    }
+
+    let mut oldest_output: Option<std::time::SystemTime> = None;
+
+    for pattern in &task.outputs {
+        let files = expand_glob(&task.base_dir, pattern);
+        if files.is_empty() {
+            return false;
+        }
+
+        for file in files {
+            match std::fs::metadata(&file).and_then(|m| m.modified()) {
+                Ok(modified) => {
+                    oldest_output = Some(oldest_output.map_or(modified, |o| o.min(modified)))
+                }
+                Err(_) => return false,
+            }
+        }
+    }
+
+    if cache.get(&task.name) == Some(&hash) {
+        return true;
+    }
+
+    let oldest_output = match oldest_output {
+        Some(oldest_output) if !task.inputs.is_empty() => oldest_output,
+        _ => return false,
+    };
+
+    task.inputs
+        .iter()
+        .flat_map(|pattern| expand_glob(&task.base_dir, pattern))
+        .filter_map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .all(|modified| modified < oldest_output)
+}
+
+/// Writes a task's output to its `log_file`. `$TASK` and `$DATE` in the
+/// path are replaced with the task's name and today's date. Characters
+/// file names can't have, like the `:` in `build:x86_64`, become `-`
+fn write_log_file(task: &Task, template: &str, output: &Output) {
+    let file_name = task
+        .name
+        .replace([':', '/', '\\', '*', '?', '"', '<', '>', '|'], "-");
+
+    let mut context = HashMap::new();
+    context.insert(String::from("$TASK"), file_name);
+    context.insert(String::from("$DATE"), get_date());
+
+    let path = PathBuf::from(substitute_variables(template, &context));
+
+    let written = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| File::create(&path))
+        .and_then(|mut file| {
+            file.write_all(&output.stdout)?;
+            file.write_all(&output.stderr)
+        });
+
+    if let Err(e) = written {
+        warn!(
+            "task({}): failed to write {}: {}",
+            task.name,
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Async tasks print straight to the console, unless their output is
+/// needed to decide if they succeeded or to show it after a failure
+fn needs_async_output(task: &Task, options: &Options) -> bool {
+    options.quiet_on_success
+        || task.success_if.is_some()
+        || task.fail_on_stderr == Some(true)
+        || task.log_file.is_some()
+        || task.capture.is_some()
+}
+
+/// Spawns an async task. Its output is streamed or captured on another
+/// thread, which is returned, unless it can go straight to the console
+fn spawn_async_task(
+    command: &mut Command,
+    task: &Task,
+    options: &Options,
+) -> std::io::Result<(Child, Option<OutputReader>)> {
+    isolate_process_group(command, task, options);
+
+    if !options.stream_output && !needs_async_output(task, options) {
+        return command.spawn().map(|child| (child, None));
+    }
+
+    let (child, reader) = spawn_captured(command, task, options)?;
+    Ok((child, Some(reader)))
+}
+
+/// Whether a task's process gets its own process group, so a timeout can
+/// stop everything it started. Interactive tasks stay in the console's
+/// group so they can read from it
+fn uses_process_group(task: &Task, options: &Options) -> bool {
+    get_timeout(task, options).is_some() && !task.interactive
+}
+
+/// Reads a child's stdout and stderr on another thread, which returns them
+type OutputReader = std::thread::JoinHandle<(Vec<u8>, Vec<u8>)>;
+
+/// Spawns a command with its output read on another thread. The output
+/// is also printed as it arrives when streaming
+fn spawn_captured(
+    command: &mut Command,
+    task: &Task,
+    options: &Options,
+) -> std::io::Result<(Child, OutputReader)> {
+    if options.merge_streams {
+        return spawn_merged(command, task, options);
+    }
+
+    pipe_output(command, task);
+    let mut child = command.spawn()?;
+
+    let stdout = read_pipe(child.stdout.take(), task, options, false);
+    let stderr = read_pipe(child.stderr.take(), task, options, true);
+
+    let reader = std::thread::spawn(move || {
+        (
+            stdout.join().unwrap_or_default(),
+            stderr.join().unwrap_or_default(),
+        )
+    });
+
+    Ok((child, reader))
+}
+
+/// Reads a pipe on another thread, streaming its lines to the console
+/// with the task's name unless `--quiet-on-success` or
+/// `--tail-on-failure` need the output held back
+fn read_pipe<R: std::io::Read + Send + 'static>(
+    pipe: Option<R>,
+    task: &Task,
+    options: &Options,
+    to_stderr: bool,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    if options.json_output {
+        let name = json_string(&task.name);
+        let stream = if to_stderr { "stderr" } else { "stdout" };
+
+        return stream_lines(pipe, task.filter.clone(), move |line| {
+            print_event(&format!(
+                r#"{{"event":"output","task":{},"stream":"{}","line":{}}}"#,
+                name,
+                stream,
+                json_string(line)
+            ))
+        });
+    }
+
+    if !options.stream_output {
+        return read_in_background(pipe);
+    }
+
+    let prefix = paint(&format!("[{}]", task.name), CYAN, options) + " ";
+    stream_lines(pipe, task.filter.clone(), move |line| {
+        if to_stderr {
+            eprintln!("{}{}", prefix, line);
+        } else {
+            println!("{}{}", prefix, line);
+        }
+    })
+}
+
+/// Prints each line read from a pipe with `print_line`, on another thread.
+/// Lines not matching the filter aren't printed. Everything read is
+/// returned so the task's result can still be decided from it
+fn stream_lines<R: std::io::Read + Send + 'static>(
+    pipe: Option<R>,
+    filter: Option<String>,
+    print_line: impl Fn(&str) + Send + 'static,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    use std::io::BufRead;
+
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let mut reader = match pipe {
+            Some(pipe) => std::io::BufReader::new(pipe),
+            None => return buffer,
+        };
+
+        let mut line = Vec::new();
+
+        while let Ok(read) = reader.read_until(b'\n', &mut line) {
+            if read == 0 {
+                break;
+            }
+
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\r', '\n']);
+
+            // Output goes above the progress line, if there is one
+            if filter.as_deref().is_none_or(|f| matches_pattern(f, text))
+                && !progress::print_above(|| print_line(text))
+            {
+                print_line(text);
+            }
+
+            buffer.append(&mut line);
+        }
+
+        buffer
+    })
+}
+
+/// Captures a command's stdout and stderr, except the ones the task
+/// discards with `stdout = null` or `stderr = null`
+fn pipe_output(command: &mut Command, task: &Task) {
+    let pipe = |discard: bool| {
+        if discard {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        }
+    };
+    command
+        .stdout(pipe(task.discard_stdout))
+        .stderr(pipe(task.discard_stderr));
+}
+
+/// Spawns a command with its stdout and stderr going to the same pipe,
+/// so their lines stay in the order they were written. Everything is
+/// returned as stdout
+fn spawn_merged(
+    command: &mut Command,
+    task: &Task,
+    options: &Options,
+) -> std::io::Result<(Child, OutputReader)> {
+    let (reader, writer) = std::io::pipe()?;
+
+    let stdout = if task.discard_stdout {
+        Stdio::null()
+    } else {
+        Stdio::from(writer.try_clone()?)
+    };
+    let stderr = if task.discard_stderr {
+        Stdio::null()
+    } else {
+        Stdio::from(writer)
+    };
+    command.stdout(stdout).stderr(stderr);
+
+    let child = command.spawn();
+
+    // Drop the command's copies of the pipe so reading stops when the child exits
+    command.stdout(Stdio::null()).stderr(Stdio::null());
+
+    let merged = read_pipe(Some(reader), task, options, false);
+    let reader = std::thread::spawn(move || (merged.join().unwrap_or_default(), Vec::new()));
+
+    Ok((child?, reader))
+}
+
+/// Puts a child in its own process group when a timeout is set, so
+/// stopping it also stops anything it started
+fn isolate_process_group(command: &mut Command, task: &Task, options: &Options) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        if uses_process_group(task, options) {
+            command.process_group(0);
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (command, task, options);
+}
+
+/// A task's own timeout, or `--timeout` if it has none
+fn get_timeout(task: &Task, options: &Options) -> Option<Duration> {
+    task.timeout.or(options.timeout)
+}
+
+/// Sends a signal to a child, or to its whole process group
+#[cfg(unix)]
+fn send_signal(child: &mut Child, signal: i32, group: bool) -> std::io::Result<()> {
+    extern "C" {
+        fn kill(pid: i32, signal: i32) -> i32;
+    }
+
+    // A negative pid is the process group
+    let pid = child.id() as i32;
+    let target = if group { -pid } else { pid };
+
+    // The child hasn't been waited on yet, so its pid can't be reused
+    if unsafe { kill(target, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Signals aren't available, so the child is terminated instead
+#[cfg(not(unix))]
+fn send_signal(child: &mut Child, _signal: i32, _group: bool) -> std::io::Result<()> {
+    child.kill()
+}
+
+/// Stops a child that ran longer than its timeout. It is sent the
+/// `--timeout-signal` first and killed if it is still running after
+/// `--timeout-grace`. Returns whether the child timed out.
+fn enforce_timeout(
+    child: &mut Child,
+    start: Instant,
+    terminated_at: &mut Option<Instant>,
+    task: &Task,
+    options: &Options,
+) -> bool {
+    match get_timeout(task, options) {
+        Some(timeout) if start.elapsed() >= timeout => {}
+        _ => return false,
+    }
+
+    match terminated_at {
+        None => {
+            let group = uses_process_group(task, options);
+            if let Err(e) = send_signal(child, options.timeout_signal, group) {
+                warn!("failed to stop timed out process\n{}", e);
+            }
+
+            *terminated_at = Some(Instant::now());
+        }
+        Some(time) if time.elapsed() >= options.timeout_grace => {
+            // Still running after the grace period
+            let _ = send_signal(child, SIGKILL, uses_process_group(task, options));
+        }
+        _ => {}
+    }
+
+    true
+}
+
+/// Stops a child after an interrupt. It is sent `TERM` and killed if it
+/// is still running after `--timeout-grace`
+fn stop_child(child: &mut Child, task: &Task, options: &Options) {
+    let group = uses_process_group(task, options);
+    let _ = send_signal(child, SIGTERM, group);
+
+    let start = Instant::now();
+    while let Ok(None) = child.try_wait() {
+        if start.elapsed() >= options.timeout_grace {
+            let _ = send_signal(child, SIGKILL, group);
+            let _ = child.wait();
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Runs the tasks marked `on_interrupt = true` one after another, once
+/// the running tasks were stopped. Another interrupt stops them too
+fn run_interrupt_tasks(
+    names: &[String],
+    tasks: &HashMap<String, Task>,
+    variables: &mut HashMap<String, String>,
+    options: &Options,
+) {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+
+    for name in names {
+        if is_interrupted() {
+            break;
+        }
+
+        let task = &tasks[name];
+        resolve_command_substitutions(task, variables);
+
+        let mut command = match build_command(task, variables, options) {
+            Some(command) => command,
+            None => continue,
+        };
+
+        if !options.quiet_on_success {
+            print_started(name, None);
+        }
+
+        let start = Instant::now();
+
+        match run_command(&mut command, task, options) {
+            Ok((_, true, _)) => print_timed_out(task, options),
+            Ok((output, false, _)) => {
+                output_task_result(task, output, Some(start.elapsed()), None, options);
+            }
+            Err(e) => println!("{}task({}): failed to execute\n{}", line_start(), name, e),
+        }
+    }
+
+    // The tool still exits as interrupted
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Reports a task that was stopped for running too long
+fn print_timed_out(task: &Task, options: &Options) {
+    let timeout = get_timeout(task, options).unwrap_or_default();
+    println!(
+        "{}task({}): {} after {}s",
+        line_start(),
+        task.name,
+        paint("timed out", RED, options),
+        timeout.as_secs_f64()
+    );
+}
+
+/// Reads a pipe to the end on another thread
+fn read_in_background<R: std::io::Read + Send + 'static>(
+    pipe: Option<R>,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+/// Runs a command to completion and collects its output like
+/// `Command::output`, stopping it if it runs past its timeout or the
+/// tool is interrupted.
+/// Also returns whether it timed out and, with `--profile-memory`,
+/// its peak memory use.
+fn run_command(
+    command: &mut Command,
+    task: &Task,
+    options: &Options,
+) -> std::io::Result<(Output, bool, Option<u64>)> {
+    isolate_process_group(command, task, options);
+
+    // The pipes are read on their own threads so a full pipe can't block
+    // the child. Interactive tasks use the console instead
+    let (mut child, reader) = if task.interactive {
+        command.stdin(Stdio::inherit());
+        (command.spawn()?, None)
+    } else {
+        command.stdin(Stdio::null());
+        let (child, reader) = spawn_captured(command, task, options)?;
+        (child, Some(reader))
+    };
+
+    let start = Instant::now();
+    let mut terminated_at = None;
+
+    let (status, peak_memory) = loop {
+        if let Some(exit) = try_wait_child(&mut child, options)? {
+            break exit;
+        }
+
+        if is_interrupted() {
+            stop_child(&mut child, task, options);
+            if let Some(reader) = reader {
+                let _ = reader.join();
+            }
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "interrupted",
+            ));
+        }
+
+        enforce_timeout(&mut child, start, &mut terminated_at, task, options);
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    let (stdout, stderr) = match reader {
+        Some(reader) => reader.join().unwrap_or_default(),
+        None => Default::default(),
+    };
+    let output = Output {
+        status,
+        stdout,
+        stderr,
+    };
+
+    Ok((output, terminated_at.is_some(), peak_memory))
+}
+
+/// Checks if a child exited without waiting, like `Child::try_wait`.
+/// With `--profile-memory` the child is reaped with `wait4` instead, which
+/// also gives the most memory it used, in bytes
+#[cfg(unix)]
+fn try_wait_child(
+    child: &mut Child,
+    options: &Options,
+) -> std::io::Result<Option<(ExitStatus, Option<u64>)>> {
+    use std::os::raw::c_long;
+    use std::os::unix::process::ExitStatusExt;
+
+    /// `struct rusage`: two `timeval`s, then `ru_maxrss` and 13 more counters
+    #[repr(C)]
+    struct Usage {
+        times: [c_long; 4],
+        max_rss: c_long,
+        counters: [c_long; 13],
+    }
+
+    extern "C" {
+        fn wait4(pid: i32, status: *mut i32, options: i32, usage: *mut Usage) -> i32;
+    }
+
+    const WNOHANG: i32 = 1;
+
+    if !options.profile_memory {
+        return Ok(child.try_wait()?.map(|status| (status, None)));
+    }
+
+    let mut status = 0;
+    let mut usage = Usage {
+        times: [0; 4],
+        max_rss: 0,
+        counters: [0; 13],
+    };
+
+    match unsafe { wait4(child.id() as i32, &mut status, WNOHANG, &mut usage) } {
+        0 => Ok(None),
+        -1 => match std::io::Error::last_os_error() {
+            e if e.kind() == std::io::ErrorKind::Interrupted => Ok(None),
+            e => Err(e),
+        },
+        _ => {
+            // macOS counts it in bytes, the others in kilobytes
+            let scale = if cfg!(target_os = "macos") { 1 } else { 1024 };
+            let peak = usage.max_rss as u64 * scale;
+            Ok(Some((ExitStatus::from_raw(status), Some(peak))))
+        }
+    }
+}
+
+/// Peak memory is only read on Unix
+#[cfg(not(unix))]
+fn try_wait_child(
+    child: &mut Child,
+    _options: &Options,
+) -> std::io::Result<Option<(ExitStatus, Option<u64>)>> {
+    Ok(child.try_wait()?.map(|status| (status, None)))
+}
+
+/// Formats a number of bytes like `340MB`
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1}GB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{}MB", b >> 20),
+        b => format!("{}KB", b >> 10),
+    }
+}
+
+/// Runs tasks at the same time like a shell pipeline, with the output
+/// of each one going to the input of the next. Returns whether the last
+/// task succeeded.
+pub fn run_pipeline(
+    names: &[String],
+    tasks: &HashMap<String, Task>,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> bool {
+    let mut children: Vec<(&Task, Child)> = Vec::new();
+
+    for (index, name) in names.iter().enumerate() {
+        let task = &tasks[name];
+        let mut command = match build_command(task, variables, options) {
+            Some(command) => command,
+            None => return false,
+        };
+
+        // Connect the input to the output of the previous task
+        if let Some((_, previous)) = children.last_mut() {
+            if let Some(stdout) = previous.stdout.take() {
+                command.stdin(stdout);
+            }
+        }
+
+        if index + 1 < names.len() {
+            command.stdout(Stdio::piped());
+        } else {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+
+        if !options.quiet_on_success {
+            println!("task({}): started", name);
+        }
+
+        match command.spawn() {
+            Ok(child) => children.push((task, child)),
+            Err(e) => {
+                println!("task({}): failed to spawn\n{}", name, e);
+                return false;
+            }
+        }
+    }
+
+    let (last, last_child) = match children.pop() {
+        Some(last) => last,
+        None => return false,
+    };
+
+    // The last task is waited on first so the earlier ones can't block
+    // on a full pipe that nobody reads
+    let success = match last_child.wait_with_output() {
+        Ok(output) => output_task_result(last, output, None, None, options),
+        Err(e) => {
+            println!("task({}): failed to retrieve output\n{}", last.name, e);
+            false
+        }
+    };
+
+    for (task, mut child) in children {
+        match child.wait() {
+            Ok(status) if status.success() => {
+                if !options.quiet_on_success {
+                    println!("task({}): finished", task.name);
+                }
+            }
+            Ok(_) => println!("task({}): failed", task.name),
+            Err(e) => println!("task({}): process failed\n{}", task.name, e),
+        }
+    }
+
+    success
+}
+
+/// Runs the tasks of a config the way the binary does
+pub struct Executor {
+    options: Options,
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
+
+impl Executor {
+    /// An executor with the defaults of the command line
+    pub fn new() -> Executor {
+        Executor {
+            options: Options::default(),
+        }
+    }
+
+    /// Runs these tasks instead of the execute queue, like naming them
+    /// on the command line
+    pub fn tasks(mut self, names: &[&str]) -> Executor {
+        self.options.tasks = names.iter().map(|name| String::from(*name)).collect();
+        self
+    }
+
+    /// Runs independent tasks at the same time, like `-async`
+    pub fn parallel(mut self, parallel: bool) -> Executor {
+        self.options.is_async = parallel;
+        self
+    }
+
+    /// Prints the commands instead of running them, like `--dry-run`
+    pub fn dry_run(mut self, dry_run: bool) -> Executor {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// Keeps starting tasks after one fails, like `--keep-going`
+    pub fn keep_going(mut self, keep_going: bool) -> Executor {
+        self.options.keep_going = keep_going;
+        self
+    }
+
+    /// Passes arguments to the tasks that run, like `-- ARGS`
+    pub fn args(mut self, args: &[&str]) -> Executor {
+        self.options.task_args = args.iter().map(|arg| String::from(*arg)).collect();
+        self
+    }
+
+    /// Uses the variables of a `[profile.NAME]` section, like `--profile NAME`
+    pub fn profile(mut self, name: &str) -> Executor {
+        self.options.profile = Some(String::from(name));
+        self
+    }
+
+    /// Overrides a variable, like `--set NAME=value`
+    pub fn set(mut self, name: &str, value: &str) -> Executor {
+        self.options
+            .overrides
+            .push((String::from(name), String::from(value)));
+        self
+    }
+
+    /// Runs the config and returns the exit code the binary would exit with
+    pub fn run(&self, config: Config) -> i32 {
+        log::set_level(self.options.log_level);
+        run_config(config, &self.options)
+    }
+}
+
+/// Prints every task that ran with how it ended and how long it took,
+/// slowest first, and the exit category of its exit code if it has one
+fn print_summary(state: &RunState, total: Duration, options: &Options) {
+    println!("summary:");
+    for line in get_summary_lines(state, total, options) {
+        println!("{}", line);
+    }
+}
+
+/// The rows of the summary, with the total last
+fn get_summary_lines(state: &RunState, total: Duration, options: &Options) -> Vec<String> {
+    let mut rows: Vec<(&str, &str, Option<Duration>)> = state
+        .results
+        .iter()
+        .map(|(name, status)| (name.as_str(), *status, state.durations.get(name).copied()))
+        .collect();
+
+    // Tasks that didn't run, like skipped ones, go last
+    rows.sort_by_key(|row| std::cmp::Reverse(row.2));
+
+    let name_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+    let name_width = name_width.max("total".len());
+    let status_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0);
+
+    let mut lines = Vec::new();
+    for (name, status, duration) in rows {
+        let duration = duration.map_or(String::from("-"), format_duration);
+        let category = state
+            .categories
+            .iter()
+            .find(|(task_name, _, _)| task_name == name)
+            .map_or(String::new(), |(_, code, category)| {
+                format!(" {} ({})", category, code)
+            });
+
+        let color = match status {
+            "finished" => GREEN,
+            "up_to_date" | "cached" | "skipped" => DIM,
+            _ => RED,
+        };
+        let status = format!("{:width$}", status, width = status_width);
+
+        lines.push(format!(
+            "  {:name_width$}  {}  {:>7}{}",
+            name,
+            paint(&status, color, options),
+            duration,
+            category,
+            name_width = name_width
+        ));
+    }
+
+    lines.push(format!(
+        "  {:name_width$}  {:status_width$}  {:>7}",
+        "total",
+        "",
+        format_duration(total),
+        name_width = name_width,
+        status_width = status_width
+    ));
+    lines
+}
+
+/// Prints the last `--output json` event of a run, with the tool's exit
+/// code, its exit category and the tasks that failed
+fn print_done_event(state: &RunState, code: i32, options: &Options) {
+    if options.json_output {
+        print_event(&get_done_event(state, code));
+    }
+}
+
+/// The last `--output json` event of a run. The exit code comes from the
+/// first task that failed, so the category is that task's
+fn get_done_event(state: &RunState, code: i32) -> String {
+    let failed: Vec<String> = state.failed.iter().map(|name| json_string(name)).collect();
+    let category = state
+        .failed
+        .first()
+        .map_or(String::from("null"), |name| state.get_category_json(name));
+
+    format!(
+        r#"{{"event":"done","exit_code":{},"category":{},"failed":[{}]}}"#,
+        code,
+        category,
+        failed.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_task(config: &str) -> Task {
+        Config::parse(config).unwrap().tasks.remove(0)
+    }
+
+    #[cfg(unix)]
+    fn get_output(code: i32, stdout: &str, stderr: &str) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    /// A new directory under the system temp directory
+    fn create_test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rbt-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The tasks of a config, running in `dir`
+    fn get_tasks_in(dir: &std::path::Path, config: &str) -> HashMap<String, Task> {
+        Config::parse(config)
+            .unwrap()
+            .tasks
+            .into_iter()
+            .map(|mut task| {
+                task.base_dir = dir.to_path_buf();
+                task.cwd = Some(String::from("."));
+                (task.name.clone(), task)
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pipelines_feed_each_task_into_the_next() {
+        let dir = create_test_dir("pipe");
+        let tasks = get_tasks_in(
+            &dir,
+            "[produce]\ncommand = printf \"alpha\\nbeta\\n\"\n\
+             [upper]\ncommand = tr a-z A-Z\n\
+             [save]\nshell = true\ncommand = cat > piped.txt\n",
+        );
+        let names: Vec<String> = vec!["produce", "upper", "save"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        assert!(run_pipeline(
+            &names,
+            &tasks,
+            &HashMap::new(),
+            &Options::default()
+        ));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("piped.txt")).unwrap(),
+            "ALPHA\nBETA\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn streamed_lines_have_no_carriage_returns() {
+        use std::sync::{Arc, Mutex};
+
+        let mut child = Command::new("printf")
+            .arg("50%%\\r\\ndone\\r\\nlast\\n")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let printed = lines.clone();
+
+        let reader = stream_lines(child.stdout.take(), None, move |line| {
+            printed.lock().unwrap().push(String::from(line))
+        });
+
+        assert_eq!(reader.join().unwrap(), b"50%\r\ndone\r\nlast\n");
+        assert_eq!(*lines.lock().unwrap(), vec!["50%", "done", "last"]);
+        child.wait().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_codes_are_put_in_the_first_matching_category() {
+        let config =
+            Config::parse("[exit_codes]\n0 = ok\n64..=78 = usage\n1..=255 = error\n").unwrap();
+        let category = |code| get_exit_category(&config.exit_categories, &get_output(code, "", ""));
+
+        assert_eq!(category(64), Some("usage"));
+        assert_eq!(category(78), Some("usage"));
+        assert_eq!(category(1), Some("error"));
+        assert_eq!(category(0), Some("ok"));
+        assert_eq!(get_exit_category(&[], &get_output(64, "", "")), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_categories_are_in_the_summary_and_json_events() {
+        let config = Config::parse("[exit_codes]\n0 = ok\n64..=78 = usage\n").unwrap();
+        let task = get_task("[lint]\ncommand = sh -c \"exit 64\"\n");
+        let options = Options::default();
+        let mut state = RunState::new(None, &options);
+
+        let mut command = build_command(&task, &HashMap::new(), &options).unwrap();
+        let output = command.output().unwrap();
+        state.start("lint");
+        state.finish_task(
+            &task,
+            output,
+            (Duration::ZERO, None),
+            &config.exit_categories,
+            &options,
+        );
+
+        let lines = get_summary_lines(&state, Duration::ZERO, &options);
+        assert!(lines[0].contains("lint") && lines[0].ends_with(" usage (64)"));
+
+        let event = state.get_finished_event("lint", "failed", Some(64));
+        assert!(event.contains(r#""exit_code":64,"category":"usage""#));
+        assert_eq!(
+            get_done_event(&state, 64),
+            r#"{"event":"done","exit_code":64,"category":"usage","failed":["lint"]}"#
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn timeouts_kill_tasks_that_outlive_the_grace_period() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let task = get_task("[t]\ncommand = sleep 5\n");
+        let options = Options {
+            timeout: Some(Duration::ZERO),
+            timeout_signal: SIGTERM,
+            timeout_grace: Duration::from_millis(300),
+            ..Options::default()
+        };
+
+        // Ignored signals stay ignored across exec, so only SIGKILL works
+        let mut command = Command::new("sh");
+        command.args(["-c", "trap '' TERM; exec sleep 5"]);
+        isolate_process_group(&mut command, &task, &options);
+        let mut child = command.spawn().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        let mut terminated_at = None;
+        assert!(enforce_timeout(
+            &mut child,
+            start,
+            &mut terminated_at,
+            &task,
+            &options
+        ));
+        assert!(terminated_at.is_some());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(enforce_timeout(
+            &mut child,
+            start,
+            &mut terminated_at,
+            &task,
+            &options
+        ));
+        assert!(child.try_wait().unwrap().is_none());
+
+        while child.try_wait().unwrap().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(3));
+            enforce_timeout(&mut child, start, &mut terminated_at, &task, &options);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(child.wait().unwrap().signal(), Some(SIGKILL));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn timeouts_stop_tasks_with_the_chosen_signal() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let task = get_task("[t]\ncommand = sleep 5\n");
+        let options = Options {
+            timeout: Some(Duration::ZERO),
+            timeout_signal: SIGTERM,
+            ..Options::default()
+        };
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        isolate_process_group(&mut command, &task, &options);
+        let mut child = command.spawn().unwrap();
+
+        assert!(enforce_timeout(
+            &mut child,
+            Instant::now(),
+            &mut None,
+            &task,
+            &options
+        ));
+
+        assert_eq!(child.wait().unwrap().signal(), Some(SIGTERM));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn invalid_utf8_output_is_replaced_not_dropped() {
+        let task = get_task("[t]\ncommand = printf \"caf\\351 ok\"\n");
+        let mut command = build_command(&task, &HashMap::new(), &Options::default()).unwrap();
+        let output = command.output().unwrap();
+        assert_eq!(output.stdout, b"caf\xe9 ok");
+
+        assert_eq!(decode_output(&task, &output.stdout), "caf\u{fffd} ok");
+        assert_eq!(decode_output(&task, "café ok".as_bytes()), "café ok");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn merged_streams_keep_the_order_lines_were_written() {
+        let task = get_task("[t]\ncommand = sh\n");
+        let options = Options {
+            merge_streams: true,
+            ..Options::default()
+        };
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "echo out 1; echo err 1 >&2; echo out 2; echo err 2 >&2; echo out 3",
+        ]);
+
+        let (mut child, reader) = spawn_captured(&mut command, &task, &options).unwrap();
+        child.wait().unwrap();
+        let (stdout, stderr) = reader.join().unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&stdout),
+            "out 1\nerr 1\nout 2\nerr 2\nout 3\n"
+        );
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn discarded_output_is_not_captured() {
+        let task = get_task("[t]\nstdout = null\nstderr = null\ncommand = sh\n");
+
+        for merge_streams in [false, true] {
+            let options = Options {
+                merge_streams,
+                ..Options::default()
+            };
+            let mut command = Command::new("sh");
+            command.args(["-c", "echo out; echo err >&2; exit 3"]);
+
+            let (mut child, reader) = spawn_captured(&mut command, &task, &options).unwrap();
+            let status = child.wait().unwrap();
+
+            assert_eq!(reader.join().unwrap(), (Vec::new(), Vec::new()));
+            assert_eq!(status.code(), Some(3));
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn profile_memory_reports_the_peak_a_task_used() {
+        let options = Options {
+            profile_memory: true,
+            ..Options::default()
+        };
+        let get_peak = |script: &str| {
+            let mut child = Command::new("sh").args(["-c", script]).spawn().unwrap();
+            loop {
+                match try_wait_child(&mut child, &options).unwrap() {
+                    Some((status, peak)) => {
+                        assert!(status.success());
+                        return peak.unwrap();
+                    }
+                    None => std::thread::sleep(Duration::from_millis(10)),
+                }
+            }
+        };
+
+        let small = get_peak("true");
+        let large = get_peak("x=$(head -c 50000000 /dev/zero | tr '\\0' a); test ${#x} -gt 0");
+        assert!(large > 40 << 20, "peak was {}", format_size(large));
+        assert!(large > small);
+
+        assert_eq!(format_size(340 << 20), "340MB");
+        assert_eq!(format_size(3 << 29), "1.5GB");
+        assert_eq!(format_size(2048), "2KB");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn log_files_are_named_after_each_task() {
+        let dir = create_test_dir("log-file");
+        let template = format!("{}/logs/$TASK-$DATE.log", dir.display());
+
+        for name in ["build", "test"] {
+            let task = get_task(&format!("[{}]\ncommand = true\n", name));
+            write_log_file(&task, &template, &get_output(0, name, " done\n"));
+        }
+
+        for name in ["build", "test"] {
+            let path = dir.join(format!("logs/{}-{}.log", name, get_date()));
+            let log = std::fs::read_to_string(path).unwrap();
+            assert_eq!(log, format!("{} done\n", name));
+        }
+        assert_eq!(std::fs::read_dir(dir.join("logs")).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn matrix_copies_write_separate_logs() {
+        let dir = create_test_dir("matrix-log");
+        let config = Config::parse(&format!(
+            "[build]\nmatrix.target = x86_64, aarch64\nlog_file = {0}/build.log\ncommand = true\n\
+             [test]\nmatrix.target = x86_64, aarch64\nlog_file = {0}/$TASK.log\ncommand = true\n",
+            dir.display()
+        ))
+        .unwrap();
+
+        for task in &config.tasks {
+            let output = get_output(0, &task.name, "");
+            write_log_file(task, task.log_file.as_ref().unwrap(), &output);
+        }
+
+        for (file, name) in [
+            ("build-x86_64.log", "build:x86_64"),
+            ("build-aarch64.log", "build:aarch64"),
+            ("test-x86_64.log", "test:x86_64"),
+            ("test-aarch64.log", "test:aarch64"),
+        ] {
+            assert_eq!(std::fs::read_to_string(dir.join(file)).unwrap(), name);
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn echo_is_on_with_the_flag_or_setting_except_for_at_commands() {
+        let config = Config::parse(
+            "[settings]\necho = true\n\
+             [shown]\ncommand = cc -o app \"main file.c\"\n\
+             [hidden]\ncommand = @cc -o app main.c\n\
+             [builtin]\ncommand = @copy a b\n",
+        )
+        .unwrap();
+        let tasks = HashMap::new();
+        let echo = |settings: &HashMap<String, String>, echo| {
+            let options = Options {
+                echo,
+                ..Options::default()
+            };
+            Run::new(&tasks, HashMap::new(), settings, &[], &options).echo
+        };
+
+        assert!(echo(&config.settings, false));
+        assert!(echo(&HashMap::new(), true));
+        assert!(!echo(&HashMap::new(), false));
+
+        let shown = config.task("shown").unwrap();
+        let command = build_command(shown, &HashMap::new(), &Options::default()).unwrap();
+        assert!(!shown.no_echo);
+        assert_eq!(get_echo_line(&command), "cc -o app 'main file.c'");
+
+        let hidden = config.task("hidden").unwrap();
+        assert!(hidden.no_echo);
+        assert_eq!(hidden.commands, vec!["cc -o app main.c"]);
+        assert!(!config.task("builtin").unwrap().no_echo);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn success_if_decides_instead_of_the_exit_code() {
+        let task = get_task("[t]\nsuccess_if = ^ok\ncommand = check\n");
+
+        assert!(!is_task_success(&task, &get_output(0, "not ok\n", "")));
+        assert!(is_task_success(
+            &task,
+            &get_output(1, "warming up\nok: 3 passed\n", "")
+        ));
+        assert!(is_task_success(
+            &task,
+            &get_output(2, "", "ok from stderr\n")
+        ));
+
+        let plain = get_task("[t]\ncommand = check\n");
+        assert!(is_task_success(&plain, &get_output(0, "", "")));
+        assert!(!is_task_success(&plain, &get_output(1, "ok\n", "")));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn quiet_runs_show_everything_a_failed_task_printed() {
+        let task = get_task("[t]\ncommand = make\n");
+        let output = get_output(2, "building\n", "error: no rule\n");
+        let quiet = Options {
+            quiet_on_success: true,
+            ..Options::default()
+        };
+
+        assert_eq!(
+            get_failed_output(&task, &output, &quiet),
+            "building\nerror: no rule\n"
+        );
+        assert_eq!(
+            get_failed_output(&task, &output, &Options::default()),
+            "error: no rule\n"
+        );
+
+        let tail = Options {
+            tail_on_failure: Some(1),
+            ..quiet
+        };
+        assert_eq!(get_failed_output(&task, &output, &tail), "error: no rule");
+    }
+
+    #[test]
+    fn retry_settings_apply_unless_a_task_sets_its_own() {
+        let config = Config::parse(
+            "[settings]\nretries = 3\nretry_delay = 2s\n\
+             [plain]\ncommand = echo plain\n\
+             [own]\nretries = 1\nretry_delay = 500ms\ncommand = echo own\n",
+        )
+        .unwrap();
+        let policy = |name| get_retry_policy(config.task(name).unwrap(), &config.settings);
+
+        assert_eq!(policy("plain"), (3, Duration::from_secs(2)));
+        assert_eq!(policy("own"), (1, Duration::from_millis(500)));
+        assert_eq!(
+            get_retry_policy(config.task("plain").unwrap(), &HashMap::new()),
+            (0, Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn tail_output_keeps_the_last_lines() {
+        let output: Vec<String> = (1..=100).map(|i| format!("line {}", i)).collect();
+        let output = output.join("\n");
+
+        assert_eq!(tail_output(&output, 3), "line 98\nline 99\nline 100");
+        assert_eq!(tail_output("one\ntwo", 5), "one\ntwo");
+        assert_eq!(tail_output("one\ntwo", 0), "");
+    }
+
+    #[test]
+    fn filter_output_keeps_matching_lines() {
+        let output = "compiling a\nerror: missing ;\nwarning: unused\nerror: bad type\ndone";
+
+        assert_eq!(
+            filter_output(output, Some("^error")),
+            "error: missing ;\nerror: bad type"
+        );
+        assert_eq!(filter_output(output, Some("^nothing")), "");
+        assert_eq!(filter_output(output, None), output);
+    }
+}
@@ -0,0 +1,251 @@
+//! Writes a config for other task runners with `rust-build-tool export
+//! FORMAT`: a Makefile, a justfile or a GitHub Actions workflow. Only
+//! what they can express is kept: commands, dependencies, working
+//! directories, environment variables and variables.
+
+use super::plan::{get_prerequisites, order_by_dependencies};
+use super::{
+    interpolate_variables, resolve_variable_references, shell_quote, split_builtin,
+    substitute_variables, Config, Task,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// The formats configs can be exported to
+pub const FORMATS: &[&str] = &["makefile", "justfile", "github"];
+
+/// Writes the config in a format, or None for an unknown format
+pub fn export(config: &Config, format: &str) -> Option<Result<String, String>> {
+    match format {
+        "makefile" | "make" => Some(Ok(to_makefile(config))),
+        "justfile" | "just" => Some(Ok(to_justfile(config))),
+        "github" => Some(to_github_workflow(config)),
+        _ => None,
+    }
+}
+
+fn to_makefile(config: &Config) -> String {
+    // Make needs literal $ doubled, so references are marked before
+    // escaping and turned into $(name) after
+    let convert = |text: &str| {
+        replace_references(text, config, |name| format!("\u{0}{}\u{0}", name))
+            .replace('$', "$$")
+            .split('\u{0}')
+            .enumerate()
+            .map(|(i, part)| match i % 2 {
+                1 => format!("$({})", part),
+                _ => String::from(part),
+            })
+            .collect::<String>()
+    };
+
+    let mut makefile = String::new();
+
+    for (name, value) in get_variables(config) {
+        makefile += &format!("{} = {}\n", name, convert(value));
+    }
+
+    let names: Vec<&str> = config.tasks.iter().map(|task| task.name.as_str()).collect();
+    makefile += &format!("\n.PHONY: all {}\n", names.join(" "));
+    let queue: Vec<&str> = config.queue.iter().map(|name| name.as_str()).collect();
+    makefile += &format!("\nall: {}\n", queue.join(" "));
+
+    for task in &config.tasks {
+        let prerequisites: Vec<&str> = get_prerequisites(task).map(|n| n.as_str()).collect();
+        if prerequisites.is_empty() {
+            makefile += &format!("\n{}:\n", task.name);
+        } else {
+            makefile += &format!("\n{}: {}\n", task.name, prerequisites.join(" "));
+        }
+
+        let silent = if task.no_echo { "@" } else { "" };
+        for command in get_commands(task, &convert) {
+            makefile += &format!("\t{}{}\n", silent, command);
+        }
+    }
+
+    makefile
+}
+
+fn to_justfile(config: &Config) -> String {
+    let convert = |text: &str| replace_references(text, config, |name| format!("{{{{{}}}}}", name));
+    let mut justfile = String::new();
+
+    for (name, value) in get_variables(config) {
+        justfile += &format!("{} := {}\n", name, to_just_expression(value, config));
+    }
+
+    if !config.queue.is_empty() {
+        let queue: Vec<&str> = config.queue.iter().map(|name| name.as_str()).collect();
+        justfile += &format!("\ndefault: {}\n", queue.join(" "));
+    }
+
+    for task in &config.tasks {
+        justfile += "\n";
+        if let Some(description) = &task.description {
+            justfile += &format!("# {}\n", description);
+        }
+
+        let prerequisites: Vec<&str> = get_prerequisites(task).map(|n| n.as_str()).collect();
+        if prerequisites.is_empty() {
+            justfile += &format!("{}:\n", task.name);
+        } else {
+            justfile += &format!("{}: {}\n", task.name, prerequisites.join(" "));
+        }
+
+        let silent = if task.no_echo { "@" } else { "" };
+        for command in get_commands(task, &convert) {
+            justfile += &format!("    {}{}\n", silent, command);
+        }
+    }
+
+    justfile
+}
+
+/// A variable's value as a just expression. Just only replaces `{{name}}`
+/// in recipes, so references become concatenations like `root + "/bin"`
+fn to_just_expression(value: &str, config: &Config) -> String {
+    let quote = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+
+    let parts: Vec<String> =
+        replace_references(value, config, |name| format!("\u{0}{}\u{0}", name))
+            .split('\u{0}')
+            .enumerate()
+            .filter(|(_, part)| !part.is_empty())
+            .map(|(i, part)| match i % 2 {
+                1 => String::from(part),
+                _ => quote(part),
+            })
+            .collect();
+
+    if parts.is_empty() {
+        return quote("");
+    }
+
+    parts.join(" + ")
+}
+
+/// A workflow with one step per task, in the order they would run.
+/// Variables are replaced since workflows have nothing like them
+fn to_github_workflow(config: &Config) -> Result<String, String> {
+    let mut variables = config.variables.clone();
+    resolve_variable_references(&mut variables, &HashSet::new());
+
+    let tasks: HashMap<String, Task> = config
+        .tasks
+        .iter()
+        .map(|task| (task.name.clone(), task.clone()))
+        .collect();
+
+    let queue: VecDeque<String> = if config.queue.is_empty() {
+        config.tasks.iter().map(|task| task.name.clone()).collect()
+    } else {
+        config.queue.clone()
+    };
+
+    let mut workflow = String::from(
+        "name: build\n\non:\n  push:\n  pull_request:\n\njobs:\n  build:\n    runs-on: ubuntu-latest\n    steps:\n      - uses: actions/checkout@v4\n",
+    );
+
+    for name in order_by_dependencies(&queue, &tasks)? {
+        let task = &tasks[&name];
+        let convert = |text: &str| substitute_variables(text, &variables);
+
+        workflow += &format!("      - name: {}\n", yaml_string(&name));
+        // The commands run in one shell that stops at the first failure
+        workflow += "        run: |\n";
+        for command in get_task_commands(task) {
+            workflow += &format!("          {}\n", convert(&command));
+        }
+
+        if let Some(cwd) = &task.cwd {
+            workflow += &format!(
+                "        working-directory: {}\n",
+                yaml_string(&convert(cwd))
+            );
+        }
+
+        if !task.env.is_empty() {
+            workflow += "        env:\n";
+            for (key, value) in &task.env {
+                workflow += &format!("          {}: {}\n", key, yaml_string(&convert(value)));
+            }
+        }
+
+        if task.allow_failure {
+            workflow += "        continue-on-error: true\n";
+        }
+    }
+
+    Ok(workflow)
+}
+
+/// The variables sorted by name, without their `$`
+fn get_variables(config: &Config) -> Vec<(&str, &String)> {
+    let mut variables: Vec<(&str, &String)> = config
+        .variables
+        .iter()
+        .map(|(name, value)| (name.trim_start_matches('$'), value))
+        .collect();
+    variables.sort();
+    variables
+}
+
+/// A task's commands for a shell, with its working directory and
+/// environment variables added to each
+fn get_commands(task: &Task, convert: &impl Fn(&str) -> String) -> Vec<String> {
+    let mut prefix = String::new();
+
+    if let Some(cwd) = &task.cwd {
+        prefix += &format!("cd {} && ", convert(cwd));
+    }
+
+    for (key, value) in &task.env {
+        prefix += &format!("{}={} ", key, convert(&shell_quote(value)));
+    }
+
+    get_task_commands(task)
+        .iter()
+        .map(|command| format!("{}{}", prefix, convert(command)))
+        .collect()
+}
+
+/// A task's commands as shell commands, running builtins like `@copy`
+/// with the usual Unix programs
+fn get_task_commands(task: &Task) -> Vec<String> {
+    if let Some(file) = &task.command_file {
+        return vec![format!("sh -e {}", file)];
+    }
+
+    task.commands
+        .iter()
+        .map(|command| match split_builtin(command) {
+            Some((name, rest)) => {
+                let program = match name {
+                    "copy" => "cp -R",
+                    "move" => "mv",
+                    "mkdir" => "mkdir -p",
+                    "rm" => "rm -rf",
+                    _ => name,
+                };
+                format!("{} {}", program, rest)
+            }
+            None => command.clone(),
+        })
+        .collect()
+}
+
+/// Rewrites the references to config variables, leaving anything else
+fn replace_references(text: &str, config: &Config, format: impl Fn(&str) -> String) -> String {
+    let references: HashMap<String, String> = config
+        .variables
+        .keys()
+        .map(|name| (name.clone(), format(name.trim_start_matches('$'))))
+        .collect();
+
+    interpolate_variables(text, &references, true).0
+}
+
+/// Quotes a value for YAML
+fn yaml_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
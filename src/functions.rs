@@ -0,0 +1,81 @@
+//! Evaluates the functions variables can call, like `${uppercase(NAME)}`,
+//! `${date(%Y%m%d)}`, `${basename($out)}` or `${hash(Cargo.lock)}`
+
+use super::executor::hash_bytes;
+use super::{get_calendar_date, get_unix_time, substitute_variables, FNV_OFFSET};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Evaluates a call like `uppercase(NAME)`. The argument is the value of
+/// the variable it names, or else its text with variables replaced.
+/// Returns None for unknown functions and calls that fail
+pub fn call(text: &str, variables: &HashMap<String, String>) -> Option<String> {
+    let (name, argument) = text.split_once('(')?;
+    let argument = argument.strip_suffix(')')?.trim();
+
+    let argument = match variables.get(&format!("${}", argument)) {
+        Some(value) => value.clone(),
+        None => substitute_variables(argument, variables),
+    };
+
+    match name.trim() {
+        "uppercase" => Some(argument.to_uppercase()),
+        "lowercase" => Some(argument.to_lowercase()),
+        "trim" => Some(String::from(argument.trim())),
+        "basename" => get_path_part(&argument, Path::file_name),
+        "dirname" => Path::new(&argument)
+            .parent()
+            .map(|dir| dir.to_string_lossy().into_owned()),
+        "stem" => get_path_part(&argument, Path::file_stem),
+        "extension" => get_path_part(&argument, Path::extension),
+        "date" => Some(format_time(&argument, get_unix_time())),
+        "hash" => std::fs::read(&argument)
+            .ok()
+            .map(|bytes| format!("{:016x}", hash_bytes(FNV_OFFSET, &bytes))),
+        _ => None,
+    }
+}
+
+fn get_path_part(path: &str, part: impl Fn(&Path) -> Option<&std::ffi::OsStr>) -> Option<String> {
+    part(Path::new(path)).map(|part| part.to_string_lossy().into_owned())
+}
+
+/// Formats a UTC time with `%Y`, `%m`, `%d`, `%H`, `%M`, `%S` and `%s`, or as
+/// `YYYY-MM-DD` for an empty format
+fn format_time(format: &str, seconds: u64) -> String {
+    let format = if format.is_empty() {
+        "%Y-%m-%d"
+    } else {
+        format
+    };
+    let (year, month, day) = get_calendar_date(seconds);
+    let time = seconds % 86400;
+
+    let mut result = String::new();
+    let mut chars = format.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('Y') => result += &format!("{:04}", year),
+            Some('m') => result += &format!("{:02}", month),
+            Some('d') => result += &format!("{:02}", day),
+            Some('H') => result += &format!("{:02}", time / 3600),
+            Some('M') => result += &format!("{:02}", time / 60 % 60),
+            Some('S') => result += &format!("{:02}", time % 60),
+            Some('s') => result += &seconds.to_string(),
+            Some('%') => result.push('%'),
+            Some(other) => {
+                result.push('%');
+                result.push(other);
+            }
+            None => result.push('%'),
+        }
+    }
+
+    result
+}
@@ -0,0 +1,211 @@
+//! Draws the task graph for `rust-build-tool graph`, as Graphviz DOT or
+//! as a tree of each task's prerequisites, marking the tasks the
+//! current plan would run
+
+use super::plan::get_prerequisites;
+use super::{Config, Task};
+use std::collections::HashSet;
+
+/// The formats the graph can be drawn in
+pub const FORMATS: &[&str] = &["tree", "dot"];
+
+/// Draws the graph in a format, or None for an unknown format
+pub fn draw(config: &Config, planned: &HashSet<String>, format: &str) -> Option<String> {
+    match format {
+        "tree" => Some(to_tree(config, planned)),
+        "dot" => Some(to_dot(config, planned)),
+        _ => None,
+    }
+}
+
+/// A DOT graph with arrows from each task to the ones that run after it.
+/// `after` hooks are dashed and planned tasks are filled
+fn to_dot(config: &Config, planned: &HashSet<String>) -> String {
+    let mut dot = String::from("digraph tasks {\n    rankdir=LR;\n    node [shape=box];\n");
+
+    for task in &config.tasks {
+        let style = if planned.contains(&task.name) {
+            " [style=filled, fillcolor=lightblue]"
+        } else {
+            ""
+        };
+        dot += &format!("    {}{};\n", dot_id(&task.name), style);
+    }
+
+    for task in &config.tasks {
+        for prerequisite in get_prerequisites(task) {
+            dot += &format!("    {} -> {};\n", dot_id(prerequisite), dot_id(&task.name));
+        }
+
+        for hook in &task.after {
+            dot += &format!(
+                "    {} -> {} [style=dashed];\n",
+                dot_id(&task.name),
+                dot_id(hook)
+            );
+        }
+    }
+
+    dot + "}\n"
+}
+
+/// A tree under each task nothing else needs, showing what it needs
+fn to_tree(config: &Config, planned: &HashSet<String>) -> String {
+    let needed: HashSet<&str> = config
+        .tasks
+        .iter()
+        .flat_map(|task| get_prerequisites(task).map(|name| name.as_str()))
+        .collect();
+
+    let mut tree = String::new();
+
+    for task in config
+        .tasks
+        .iter()
+        .filter(|t| !needed.contains(t.name.as_str()))
+    {
+        tree += &format!("{}\n", get_label(&task.name, planned));
+        add_children(
+            &mut tree,
+            config,
+            task,
+            "",
+            planned,
+            &mut vec![task.name.as_str()],
+        );
+    }
+
+    if !planned.is_empty() {
+        tree += "\n* runs in the current plan\n";
+    }
+
+    tree
+}
+
+/// Adds a task's prerequisites below it, indented under `prefix`
+fn add_children<'a>(
+    tree: &mut String,
+    config: &'a Config,
+    task: &'a Task,
+    prefix: &str,
+    planned: &HashSet<String>,
+    path: &mut Vec<&'a str>,
+) {
+    let children: Vec<&str> = get_prerequisites(task).map(|name| name.as_str()).collect();
+
+    for (i, name) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        let (branch, indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+
+        // A cycle is shown once instead of forever
+        if path.contains(name) {
+            *tree += &format!("{}{}{} (cycle)\n", prefix, branch, name);
+            continue;
+        }
+
+        *tree += &format!("{}{}{}\n", prefix, branch, get_label(name, planned));
+
+        if let Some(child) = config.tasks.iter().find(|t| t.name == *name) {
+            path.push(name);
+            let prefix = format!("{}{}", prefix, indent);
+            add_children(tree, config, child, &prefix, planned, path);
+            path.pop();
+        }
+    }
+}
+
+/// A tree of a task's `depends`, for `--print-tree`. A task already in
+/// the tree is marked instead of shown again, so diamonds and cycles end
+pub fn to_dependency_tree(config: &Config, root: &Task) -> String {
+    let mut tree = format!("{}\n", root.name);
+    let mut shown = HashSet::new();
+    shown.insert(root.name.as_str());
+    add_dependencies(&mut tree, config, root, "", &mut shown);
+    tree
+}
+
+/// Adds a task's `depends` below it, indented under `prefix`
+fn add_dependencies<'a>(
+    tree: &mut String,
+    config: &'a Config,
+    task: &'a Task,
+    prefix: &str,
+    shown: &mut HashSet<&'a str>,
+) {
+    for (i, name) in task.depends.iter().enumerate() {
+        let (branch, indent) = if i + 1 == task.depends.len() {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+
+        if !shown.insert(name.as_str()) {
+            *tree += &format!("{}{}{} (already shown)\n", prefix, branch, name);
+            continue;
+        }
+
+        *tree += &format!("{}{}{}\n", prefix, branch, name);
+
+        if let Some(child) = config.tasks.iter().find(|t| t.name == *name) {
+            let prefix = format!("{}{}", prefix, indent);
+            add_dependencies(tree, config, child, &prefix, shown);
+        }
+    }
+}
+
+fn get_label(name: &str, planned: &HashSet<String>) -> String {
+    if planned.contains(name) {
+        format!("{} *", name)
+    } else {
+        String::from(name)
+    }
+}
+
+/// Quotes a task name as a DOT id
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_tree(config: &str, root: &str) -> String {
+        let config = Config::parse(config).unwrap();
+        to_dependency_tree(&config, config.task(root).unwrap())
+    }
+
+    #[test]
+    fn dependency_tree_marks_repeated_tasks() {
+        let config = "[fetch]\ncommand = echo fetch\n\
+                      [lib]\ndepends = fetch\ncommand = echo lib\n\
+                      [cli]\ndepends = fetch\ncommand = echo cli\n\
+                      [build]\ndepends = lib, cli\ncommand = echo build\n";
+
+        let expected = [
+            "build",
+            "├── lib",
+            "│   └── fetch",
+            "└── cli",
+            "    └── fetch (already shown)",
+            "",
+        ];
+        assert_eq!(get_tree(config, "build"), expected.join("\n"));
+        assert_eq!(get_tree(config, "lib"), "lib\n└── fetch\n");
+    }
+
+    #[test]
+    fn dependency_tree_stops_at_cycles() {
+        let config = "[a]\ndepends = b\ncommand = echo a\n\
+                      [b]\ndepends = a\ncommand = echo b\n";
+
+        assert_eq!(
+            get_tree(config, "a"),
+            "a\n└── b\n    └── a (already shown)\n"
+        );
+    }
+}
@@ -0,0 +1,3677 @@
+//! A build runner for `build.cfg` and `build.toml` configs. The
+//! `rust-build-tool` binary is a thin wrapper around [`run_cli`], and the
+//! same config parsing and task running can be embedded in other tools:
+//!
+//! ```no_run
+//! use rust_build_tool::{Config, Executor, Plan};
+//!
+//! let config = Config::parse("[build]\ncommand = cargo build\n").unwrap();
+//! let plan = Plan::new(&config, &["build"]).unwrap();
+//! assert_eq!(plan.tasks().collect::<Vec<_>>(), ["build"]);
+//!
+//! let code = Executor::new().tasks(&["build"]).run(config);
+//! std::process::exit(code);
+//! ```
+
+#[macro_use]
+mod log;
+mod args;
+mod artifacts;
+mod builtins;
+mod completions;
+mod condition;
+mod config;
+mod daemon;
+mod executor;
+mod export;
+mod functions;
+mod graph;
+mod makefile;
+mod parser;
+mod plan;
+mod progress;
+mod toml;
+
+use args::parse_args;
+use config::{
+    add_env_fallbacks, expand_matrices, get_execute_queue, get_exit_categories, get_groups,
+    get_line_key_value, get_named_queues, get_profiles, get_section_header, get_section_pairs,
+    get_setting, get_settings, get_user_tasks, get_variables_map, import_env_variables,
+    is_current_platform, load_config, parse_duration, parse_duration_setting, parse_list,
+    parse_setting, resolve_variable_references,
+};
+use executor::format_size;
+pub use executor::Executor;
+pub use plan::Plan;
+use plan::{get_random_seed, order_by_dependencies, resolve_task_names};
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    process::{Output, Stdio},
+};
+
+const BUILD_CONFIG: &str = "build.cfg";
+const BUILD_TOML: &str = "build.toml";
+const BUILD_CACHE: &str = ".buildcache";
+/// Locked while tasks run so two runs in one directory can't overlap
+const BUILD_LOCK: &str = ".build.lock";
+/// The hash of no bytes, which `hash_bytes` starts from
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const SIGINT: i32 = 2;
+const SIGKILL: i32 = 9;
+const SIGTERM: i32 = 15;
+
+/// The exit code for a timed out task, the same as `timeout` uses
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// The exit code after Ctrl-C, the same as shells use
+const INTERRUPT_EXIT_CODE: i32 = 130;
+
+const GREEN: &str = "32";
+const RED: &str = "31";
+const CYAN: &str = "36";
+const DIM: &str = "2";
+
+/// Whether the progress line of a running task is open on a terminal
+static LINE_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Set by SIGINT and SIGTERM, which stop the running tasks
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Where `--output json` events are written when stdout was moved to stderr
+static EVENTS: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// The `--log-file` every run's commands and output are appended to
+static TRANSCRIPT: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// A task section of a config
+#[derive(Clone)]
+pub struct Task {
+    name: String,
+    description: Option<String>,
+    /// Run one after another, stopping at the first that fails
+    commands: Vec<String>,
+    /// Whether `commands` came from `command.OS` lines for this platform
+    platform_commands: bool,
+    /// Whether the task has `command.OS` lines for other platforms
+    other_platform_commands: bool,
+    /// The platforms the task runs on, every platform if empty
+    platforms: Vec<String>,
+    filter: Option<String>,
+    retries: Option<u32>,
+    retry_delay: Option<Duration>,
+    clean: Vec<String>,
+    success_if: Option<String>,
+    max_memory: Option<u64>,
+    max_cpu: Option<u64>,
+    /// The nice value the task runs with, from -20 to 19
+    priority: Option<i32>,
+    /// The CPUs the task may run on
+    cpu_affinity: Vec<usize>,
+    /// Overrides `--timeout` for this task
+    timeout: Option<Duration>,
+    path: Option<String>,
+    fail_on_stderr: Option<bool>,
+    discard_stdout: bool,
+    discard_stderr: bool,
+    no_echo: bool,
+    command_file: Option<String>,
+    script: Option<String>,
+    log_file: Option<String>,
+    /// The variable the task's stdout is saved in, like `$GIT_SHA`
+    capture: Option<String>,
+    /// A condition like `exists(Cargo.lock)` the task only runs if true
+    when: Option<String>,
+    /// The `user@host` the command runs on with ssh
+    runs_on: Option<String>,
+    /// The docker image the command runs in
+    container: Option<String>,
+    depends: Vec<String>,
+    /// Other names the task can be run by, like `b` for `build`
+    aliases: Vec<String>,
+    /// Tasks run just before this one, like dependencies
+    before: Vec<String>,
+    /// Tasks run after this one even if it fails
+    after: Vec<String>,
+    shell: Option<bool>,
+    /// Expand globs like `src/*.c` in commands that don't use a shell
+    glob: bool,
+    cwd: Option<String>,
+    env: Vec<(String, String)>,
+    watch: Vec<String>,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    allow_failure: bool,
+    /// Use the console's stdin, stdout and stderr instead of capturing them
+    interactive: bool,
+    /// Run after the running tasks are stopped by Ctrl-C
+    on_interrupt: bool,
+    /// The directory of the config the task was defined in
+    base_dir: PathBuf,
+    /// The `matrix.KEY` values the task is expanded into a task for
+    /// each combination of
+    matrix: Vec<(String, Vec<String>)>,
+}
+
+/// A name given to a range of exit codes
+#[derive(Clone)]
+struct ExitCategory {
+    codes: RangeInclusive<i32>,
+    name: String,
+}
+
+/// Everything parsed from a build config
+#[derive(Clone)]
+pub struct Config {
+    variables: HashMap<String, String>,
+    tasks: Vec<Task>,
+    settings: HashMap<String, String>,
+    exit_categories: Vec<ExitCategory>,
+    queue: VecDeque<String>,
+    named_queues: HashMap<String, VecDeque<String>>,
+    /// The variables each `[profile.NAME]` section overrides
+    profiles: HashMap<String, HashMap<String, String>>,
+    /// The tasks each `[group.NAME]` section runs
+    groups: HashMap<String, VecDeque<String>>,
+    parallel: Option<bool>,
+}
+
+/// Which definition is used when a task is defined more than once
+#[derive(Clone, Copy)]
+enum MatchPolicy {
+    First,
+    Last,
+}
+
+impl Task {
+    /// The name of the task's section
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// The commands run one after another, before variables are replaced
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    /// The tasks that run before this one
+    pub fn depends(&self) -> &[String] {
+        &self.depends
+    }
+}
+
+impl Config {
+    /// Parses a `build.cfg` config. Malformed lines are returned as an
+    /// error with their line and column, and tasks defined twice use the
+    /// last definition. `extends` and `include` are not followed
+    pub fn parse(config: &str) -> Result<Config, String> {
+        let errors: Vec<String> = parser::check_config(config)
+            .iter()
+            .filter(|diagnostic| diagnostic.is_error)
+            .map(|diagnostic| diagnostic.to_string())
+            .collect();
+
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+
+        let config = parser::strip_comments(config);
+        Ok(Config::parse_with_policy(&config, MatchPolicy::Last))
+    }
+
+    /// Parses a `build.toml` config, see [`Config::parse`]
+    pub fn parse_toml(config: &str) -> Result<Config, String> {
+        Config::parse(&toml::to_cfg(config)?)
+    }
+
+    pub fn tasks(&self) -> &[Task] {
+        &self.tasks
+    }
+
+    pub fn task(&self, name: &str) -> Option<&Task> {
+        self.tasks.iter().find(|task| task.name == name)
+    }
+
+    /// The variables defined in the config, without the `$`
+    pub fn variables(&self) -> &HashMap<String, String> {
+        &self.variables
+    }
+
+    /// The tasks in the `[execute]` section
+    pub fn queue(&self) -> &VecDeque<String> {
+        &self.queue
+    }
+
+    /// Parses the variables, tasks, settings and execute queue of a config
+    fn parse_with_policy(config: &str, policy: MatchPolicy) -> Config {
+        let mut tasks = Vec::new();
+
+        let mut groups = get_groups(config);
+
+        // Duplicates in one file were already reported by the parser
+        for task in expand_matrices(get_user_tasks(config), &mut groups) {
+            match tasks.iter().position(|t: &Task| t.name == task.name) {
+                Some(_) if matches!(policy, MatchPolicy::First) => {}
+                Some(existing) => {
+                    tasks.remove(existing);
+                    tasks.push(task);
+                }
+                None => tasks.push(task),
+            }
+        }
+
+        Config {
+            variables: get_variables_map(config),
+            tasks,
+            settings: get_settings(config),
+            exit_categories: get_exit_categories(config),
+            queue: get_execute_queue(config),
+            named_queues: get_named_queues(config),
+            profiles: get_profiles(config),
+            groups,
+            parallel: get_section_pairs(config, "execute")
+                .iter()
+                .find(|(key, _)| key == "parallel")
+                .and_then(|(key, value)| parse_setting("execute", key, value)),
+        }
+    }
+
+    /// Applies `local` on top of this config. Variables and settings
+    /// defined in `local` win, tasks defined in both are picked by the
+    /// policy, and its execute queue replaces this one unless it is empty.
+    fn merge(mut self, local: Config, policy: MatchPolicy) -> Config {
+        self.variables.extend(local.variables);
+        self.settings.extend(local.settings);
+
+        for task in local.tasks {
+            add_task(&mut self.tasks, task, policy);
+        }
+
+        // The first matching category wins, so local ones go first
+        let mut exit_categories = local.exit_categories;
+        exit_categories.append(&mut self.exit_categories);
+        self.exit_categories = exit_categories;
+
+        if !local.queue.is_empty() {
+            self.queue = local.queue;
+        }
+
+        self.named_queues.extend(local.named_queues);
+
+        for (name, variables) in local.profiles {
+            self.profiles.entry(name).or_default().extend(variables);
+        }
+
+        self.groups.extend(local.groups);
+        self.parallel = local.parallel.or(self.parallel);
+
+        self
+    }
+}
+
+/// Adds a task, warning and applying the policy if one with the same
+/// name already exists
+fn add_task(tasks: &mut Vec<Task>, task: Task, policy: MatchPolicy) {
+    let existing = match tasks.iter().position(|t| t.name == task.name) {
+        Some(existing) => existing,
+        None => return tasks.push(task),
+    };
+
+    match policy {
+        MatchPolicy::First => {
+            warn!(
+                "task({}) is defined more than once, using the first definition",
+                task.name
+            );
+        }
+        MatchPolicy::Last => {
+            warn!(
+                "task({}) is defined more than once, using the last definition",
+                task.name
+            );
+            tasks.remove(existing);
+            tasks.push(task);
+        }
+    }
+}
+
+/// Opens or creates the build config. Then
+/// returns the contents as a `String`
+fn get_build_config(options: &Options) -> Result<String, String> {
+    let path = get_config_path(options);
+
+    match std::fs::read_to_string(path) {
+        Ok(config) => Ok(config),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && options.config.is_none() => {
+            Err(format!(
+                "no {} or {} found here or in a parent directory, run `rust-build-tool init` to create one",
+                BUILD_CONFIG, BUILD_TOML
+            ))
+        }
+        Err(e) => Err(format!("failed to read {}: {}", path, e)),
+    }
+}
+
+/// The config written by `init`
+const CONFIG_TEMPLATE: &str = "# Variables start with a $ and can be used in commands
+$output = build/app
+
+# Every other section is a task
+[build]
+description = \"Build the app\"
+command = echo building $output
+
+# The tasks to run, in order
+[execute]
+build
+";
+
+/// Writes a template config, refusing to overwrite an existing one
+fn init_config(options: &Options) -> Result<(), String> {
+    let path = get_config_path(options);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(CONFIG_TEMPLATE.as_bytes()))
+        .map_err(|e| format!("failed to create {}: {}", path, e))?;
+
+    info!("{} created!", path);
+    Ok(())
+}
+
+/// Converts a Makefile into a new build config, which is never
+/// overwritten
+fn import_makefile(makefile: &str, options: &Options) -> Result<(), String> {
+    let text = std::fs::read_to_string(makefile)
+        .map_err(|e| format!("failed to read {}: {}", makefile, e))?;
+
+    let path = get_config_path(options);
+    let config = format!(
+        "# Converted from {} by rust-build-tool import\n{}",
+        makefile,
+        makefile::to_cfg(&text)
+    );
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(config.as_bytes()))
+        .map_err(|e| format!("failed to create {}: {}", path, e))?;
+
+    info!("{} created from {}!", path, makefile);
+    Ok(())
+}
+
+/// The config file to load, `build.cfg` unless `--config` was given
+fn get_config_path(options: &Options) -> &str {
+    options.config.as_deref().unwrap_or(BUILD_CONFIG)
+}
+
+/// Changes into the directory of the config so paths in it are relative
+/// to it. Without `--config` the closest directory with a `build.cfg` or
+/// `build.toml` is used, starting from the current one, like git does.
+fn locate_config(options: &mut Options) -> Result<(), String> {
+    let path = match &options.config {
+        Some(path) => PathBuf::from(path),
+        None => match find_config_file() {
+            Some(path) => path,
+            None => return Ok(()),
+        },
+    };
+
+    let is_current = |dir: &Path| {
+        let current = std::env::current_dir().and_then(|current| current.canonicalize());
+        matches!((dir.canonicalize(), current), (Ok(dir), Ok(current)) if dir == current)
+    };
+
+    if let Some(dir) = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty() && !is_current(dir))
+    {
+        std::env::set_current_dir(dir)
+            .map_err(|e| format!("failed to change to {}: {}", dir.display(), e))?;
+
+        if !options.quiet_on_success {
+            info!("running in {}", dir.display());
+        }
+    }
+
+    options.config = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    Ok(())
+}
+
+/// Finds the closest `build.cfg`, or `build.toml`, in the current or a
+/// parent directory. One in the current directory is returned by name
+fn find_config_file() -> Option<PathBuf> {
+    let current = std::env::current_dir().ok()?;
+
+    for dir in current.ancestors() {
+        for name in [BUILD_CONFIG, BUILD_TOML] {
+            if dir.join(name).is_file() {
+                return Some(if dir == current {
+                    PathBuf::from(name)
+                } else {
+                    dir.join(name)
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// Matches `text` against a small regular expression supporting
+/// `^`, `$`, `.` and `*`. Anything else is matched literally.
+fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    if pattern.first() == Some(&'^') {
+        return matches_here(&pattern[1..], &text);
+    }
+
+    // Try every starting position so the pattern can match anywhere
+    (0..=text.len()).any(|start| matches_here(&pattern, &text[start..]))
+}
+
+/// Matches the pattern at the start of the text
+fn matches_here(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => true,
+        ['$'] => text.is_empty(),
+        [c, '*', rest @ ..] => matches_star(*c, rest, text),
+        [c, rest @ ..] => match text.first() {
+            Some(t) if *c == '.' || c == t => matches_here(rest, &text[1..]),
+            _ => false,
+        },
+    }
+}
+
+/// Matches zero or more of `c` followed by the rest of the pattern
+fn matches_star(c: char, pattern: &[char], text: &[char]) -> bool {
+    let mut i = 0;
+
+    loop {
+        if matches_here(pattern, &text[i..]) {
+            return true;
+        }
+
+        if i == text.len() || (c != '.' && text[i] != c) {
+            return false;
+        }
+
+        i += 1;
+    }
+}
+
+/// Decides if output is colored. `always` wins over `NO_COLOR`, which
+/// only turns off colors in `auto` mode along with redirected output
+fn should_use_color(choice: ColorChoice, no_color: bool, is_terminal: bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => !no_color && is_terminal,
+    }
+}
+
+/// Wraps text in an ANSI color code if colors are enabled
+fn paint(text: &str, color: &str, options: &Options) -> String {
+    if options.color {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        String::from(text)
+    }
+}
+
+/// Formats a duration like `12.4s`, or `2m 05s` from a minute on
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+
+    if seconds < 60.0 {
+        format!("{:.1}s", seconds)
+    } else {
+        let seconds = duration.as_secs();
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    }
+}
+
+/// Splits a command into arguments. Double quotes group words into one
+/// argument and support the `\n`, `\t`, `\"` and `\\` escapes. Single
+/// quotes group words without escapes, and outside quotes a backslash
+/// escapes a space or quote
+fn tokenize_command(command: &str) -> Vec<String> {
+    tokenize_command_quoting(command)
+        .into_iter()
+        .map(|(arg, _)| arg)
+        .collect()
+}
+
+/// Splits a command like `tokenize_command`, also telling whether each
+/// argument was quoted or escaped, since those aren't expanded as globs
+fn tokenize_command_quoting(command: &str) -> Vec<(String, bool)> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut in_quotes = false;
+    let mut quoted = false;
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                in_token = true;
+                quoted = true;
+            }
+            // Single quotes keep everything up to the closing quote as is
+            '\'' if !in_quotes => {
+                current.extend(chars.by_ref().take_while(|&c| c != '\''));
+                in_token = true;
+                quoted = true;
+            }
+            // Outside quotes a backslash only escapes spaces and quotes, so
+            // Windows paths like C:\dir\file keep their backslashes
+            '\\' if !in_quotes => {
+                match chars.next_if(|&next| next.is_whitespace() || next == '"' || next == '\'') {
+                    Some(escaped) => {
+                        current.push(escaped);
+                        quoted = true;
+                    }
+                    None => current.push('\\'),
+                }
+                in_token = true;
+            }
+            '\\' if in_quotes => match chars.next() {
+                Some('n') => current.push('\n'),
+                Some('t') => current.push('\t'),
+                Some(escaped @ ('"' | '\\')) => current.push(escaped),
+                Some(other) => {
+                    current.push('\\');
+                    current.push(other);
+                }
+                None => current.push('\\'),
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if in_token {
+                    args.push((std::mem::take(&mut current), quoted));
+                    in_token = false;
+                    quoted = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if in_token {
+        args.push((current, quoted));
+    }
+
+    args
+}
+
+/// Evaluates the `$(...)` variables a task references, replacing them
+/// with the command's output. Variables are only evaluated once, when
+/// a task that needs them is about to run.
+fn resolve_command_substitutions(task: &Task, variables: &mut HashMap<String, String>) {
+    let texts = get_task_texts(task);
+
+    let mut references: Vec<String> = variables
+        .keys()
+        .filter(|name| {
+            let name = name.trim_start_matches('$');
+            texts.iter().any(|text| references_variable(text, name))
+        })
+        .cloned()
+        .collect();
+    references.sort();
+
+    for name in references {
+        let inner = match variables.get(&name) {
+            Some(value) => match value.strip_prefix("$(").and_then(|v| v.strip_suffix(')')) {
+                Some(inner) => inner.to_string(),
+                None => continue,
+            },
+            None => continue,
+        };
+
+        let args = tokenize_command(&inner);
+        let output = match args.split_first() {
+            Some((program, args)) => Command::new(program).args(args).output(),
+            None => continue,
+        };
+
+        let value = match output {
+            Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+                .trim_end()
+                .to_string(),
+            Ok(output) => {
+                warn!("{} exited with {}", name, output.status);
+                String::new()
+            }
+            Err(e) => {
+                warn!("failed to evaluate {}\n{}", name, e);
+                String::new()
+            }
+        };
+
+        variables.insert(name, value);
+    }
+}
+
+/// Builds the command for a task, replacing variables in its arguments.
+/// Returns `None` if the task has no command, or if `--require-all-vars`
+/// is set and a variable is undefined
+fn build_command(
+    task: &Task,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> Option<Command> {
+    let mut command = match (&task.script, task.shell) {
+        (Some(script), _) => get_script_command(script, variables, options),
+        (None, Some(true)) => {
+            let lines: Vec<String> = task.commands.iter().map(|c| get_shell_line(c)).collect();
+            get_shell_command(&lines.join(" && "), variables, options)
+        }
+        (None, _) if task.commands.len() > 1 => get_chained_command(task, variables, options)?,
+        (None, _) => match task.commands.first() {
+            Some(line) => get_inline_command(task, line, variables, options)?,
+            None => {
+                error!(
+                    "task({}): no command for {}",
+                    task.name,
+                    std::env::consts::OS
+                );
+                return None;
+            }
+        },
+    };
+
+    // The process of a remote or container task is only the local ssh or
+    // docker client, so the limits would miss the task itself
+    if task.runs_on.is_some() || task.container.is_some() {
+        let ignored = get_limit_options(task);
+
+        if !ignored.is_empty() {
+            warn!(
+                "task({}): ignoring {}, it would only limit the local ssh or docker client",
+                task.name,
+                ignored.join(", ")
+            );
+        }
+    } else {
+        apply_resource_limits(&mut command, task);
+        apply_scheduling(&mut command, task);
+    }
+
+    if let Some(image) = &task.container {
+        let image = substitute_variables(image, variables);
+        command = get_container_command(task, &image, &command, variables)?;
+    }
+
+    // Remote and container tasks get their directory and environment there
+    if let Some(host) = &task.runs_on {
+        let host = substitute_variables(host, variables);
+        command = get_remote_command(task, &host, &command, variables);
+    } else if task.container.is_none() {
+        if let Some(cwd) = &task.cwd {
+            let dir = task.base_dir.join(substitute_variables(cwd, variables));
+
+            if !dir.is_dir() {
+                error!(
+                    "task({}): working directory {} does not exist",
+                    task.name,
+                    dir.display()
+                );
+                return None;
+            }
+
+            command.current_dir(dir);
+        }
+
+        if let Some(path) = &task.path {
+            command.env("PATH", get_search_path(path, variables));
+        }
+
+        for (key, value) in &task.env {
+            command.env(key, substitute_variables(value, variables));
+        }
+    }
+
+    if task.discard_stdout {
+        command.stdout(Stdio::null());
+    }
+
+    if task.discard_stderr {
+        command.stderr(Stdio::null());
+    }
+
+    Some(command)
+}
+
+/// Wraps a command to run in a new container of a docker image. The
+/// config's directory is mounted at the same path, so paths in the
+/// command work the same inside
+fn get_container_command(
+    task: &Task,
+    image: &str,
+    command: &Command,
+    variables: &HashMap<String, String>,
+) -> Option<Command> {
+    let base = Path::new(".").join(&task.base_dir);
+    let cwd = match &task.cwd {
+        Some(cwd) => base.join(substitute_variables(cwd, variables)),
+        None => base.clone(),
+    };
+
+    let (base, cwd) = match (base.canonicalize(), cwd.canonicalize()) {
+        (Ok(base), Ok(cwd)) => (base, cwd),
+        _ => {
+            error!(
+                "task({}): working directory {} does not exist",
+                task.name,
+                cwd.display()
+            );
+            return None;
+        }
+    };
+
+    let mut container = Command::new("docker");
+    container.arg("run").arg("--rm");
+
+    if task.interactive {
+        container.arg("-it");
+    }
+
+    let mount = format!("{}:{}", base.display(), base.display());
+    container.arg("-v").arg(mount).arg("-w").arg(&cwd);
+
+    for (key, value) in &task.env {
+        let value = substitute_variables(value, variables);
+        container.arg("-e").arg(format!("{}={}", key, value));
+    }
+
+    container.arg(image).arg(command.get_program());
+    container.args(command.get_args());
+    Some(container)
+}
+
+/// What went wrong for docker's own exit codes, which it uses instead
+/// of the command's when the command couldn't run
+fn get_container_error(code: i32) -> Option<&'static str> {
+    match code {
+        125 => Some("docker could not run the container"),
+        126 => Some("command can't be run in the container"),
+        127 => Some("command not found in the container"),
+        _ => None,
+    }
+}
+
+/// Wraps a command to run on another host with ssh, in the task's
+/// `cwd` there and with its environment variables. ssh fails instead
+/// of asking for a password, unless the task is interactive
+fn get_remote_command(
+    task: &Task,
+    host: &str,
+    command: &Command,
+    variables: &HashMap<String, String>,
+) -> Command {
+    let mut line = String::new();
+
+    if let Some(cwd) = &task.cwd {
+        line += &format!(
+            "cd {} && ",
+            shell_quote(&substitute_variables(cwd, variables))
+        );
+    }
+
+    for (key, value) in &task.env {
+        line += &format!(
+            "{}={} ",
+            key,
+            shell_quote(&substitute_variables(value, variables))
+        );
+    }
+
+    line += &get_echo_line(command);
+
+    let mut remote = Command::new("ssh");
+    if task.interactive {
+        remote.arg("-t");
+    } else {
+        remote.arg("-o").arg("BatchMode=yes");
+    }
+    remote.arg(host).arg(line);
+    remote
+}
+
+/// Builds the command from one of a task's `command` options
+fn get_inline_command(
+    task: &Task,
+    command_line: &str,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> Option<Command> {
+    let args = get_command_args(task, command_line, variables, options)?;
+    let mut split = args.iter();
+    let first = split.next()?;
+
+    let mut command = if !options.use_powershell || split_builtin(command_line).is_some() {
+        Command::new(first)
+    } else {
+        let mut command = Command::new("Powershell");
+        command.arg("-Command");
+        command.arg(first);
+        command
+    };
+
+    command.args(split);
+    Some(command)
+}
+
+/// Splits a command into its program and arguments with the variables
+/// replaced. Returns None if a variable is undefined with --require-all-vars
+fn get_command_args(
+    task: &Task,
+    command_line: &str,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> Option<Vec<String>> {
+    let (mut args, command_line) = match split_builtin(command_line) {
+        Some((name, rest)) => (get_builtin_args(name), rest),
+        None => (Vec::new(), command_line),
+    };
+    let glob_dir = match (&task.cwd, task.glob) {
+        (_, false) => None,
+        (Some(cwd), true) => Some(task.base_dir.join(substitute_variables(cwd, variables))),
+        (None, true) => Some(PathBuf::from(".")),
+    };
+
+    for (arg, quoted) in tokenize_command_quoting(command_line) {
+        if arg == "$ARGS" || arg == "${ARGS}" {
+            args.extend(options.task_args.iter().cloned());
+            continue;
+        }
+
+        let (arg, unresolved) = interpolate_variables(&arg, variables, true);
+
+        if let Some(name) = unresolved {
+            if options.require_all_vars {
+                error!("task({}) uses undefined variable {}", task.name, name);
+                return None;
+            }
+
+            warn!("task({}) uses undefined variable {}", task.name, name);
+        }
+
+        match &glob_dir {
+            Some(dir) if !quoted && arg.contains(['*', '?']) => {
+                args.extend(expand_command_glob(dir, arg))
+            }
+            _ => args.push(arg),
+        }
+    }
+
+    Some(args)
+}
+
+/// Splits a command starting with a builtin like `@copy` into the
+/// builtin's name and its arguments
+fn split_builtin(command_line: &str) -> Option<(&str, &str)> {
+    let command = command_line.strip_prefix('@')?;
+    let (name, rest) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+
+    if builtins::NAMES.contains(&name) {
+        Some((name, rest))
+    } else {
+        None
+    }
+}
+
+/// The program and arguments that run a builtin, which is this tool's
+/// own binary with `--builtin NAME`
+fn get_builtin_args(name: &str) -> Vec<String> {
+    let program = std::env::current_exe()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| String::from("rust-build-tool"));
+
+    vec![program, String::from("--builtin"), String::from(name)]
+}
+
+/// A command line for a task with `shell = true`, with builtins
+/// replaced by the command that runs them
+fn get_shell_line(command_line: &str) -> String {
+    match split_builtin(command_line) {
+        Some((name, rest)) => {
+            let args: Vec<String> = get_builtin_args(name)
+                .iter()
+                .map(|a| shell_quote(a))
+                .collect();
+            format!("{} {}", args.join(" "), rest)
+        }
+        None => String::from(command_line),
+    }
+}
+
+/// Replaces a glob in a command with the sorted files matching it,
+/// relative to the command's working directory. A glob that matches
+/// nothing is passed on as is, like shells do
+fn expand_command_glob(dir: &Path, pattern: String) -> Vec<String> {
+    let mut files: Vec<String> = expand_glob(dir, &pattern)
+        .iter()
+        .map(|file| {
+            let file = file.strip_prefix(dir).unwrap_or(file);
+            file.to_string_lossy().replace('\\', "/")
+        })
+        .collect();
+
+    if files.is_empty() {
+        return vec![pattern];
+    }
+
+    files.sort();
+    files
+}
+
+/// Adds `$ARGS` to the end of a task's last command, unless the task
+/// already decides where the arguments go
+fn append_task_args(task: &mut Task) {
+    let uses_args = |text: &str| text.contains("$ARGS") || text.contains("${ARGS}");
+
+    if task.commands.iter().any(|command| uses_args(command)) {
+        return;
+    }
+
+    if let Some(script) = &task.script {
+        if !uses_args(script) {
+            warn!(
+                "task({}): arguments after -- are only passed to a command_file that uses $ARGS",
+                task.name
+            );
+        }
+        return;
+    }
+
+    if let Some(command) = task.commands.last_mut() {
+        command.push_str(" $ARGS");
+    }
+}
+
+/// Builds the command for a task's `command_file`, which runs as a
+/// script with `sh -e` or with powershell. Unknown variables are left
+/// for the shell
+fn get_script_command(
+    script: &str,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> Command {
+    let script = substitute_variables(script, variables);
+
+    let mut command = if options.use_powershell || cfg!(windows) {
+        let mut command = Command::new("Powershell");
+        command.arg("-Command");
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-e").arg("-c");
+        command
+    };
+
+    command.arg(script);
+    command
+}
+
+/// Builds the command for a task with `shell = true`, which runs its
+/// whole command line with `sh -c`, or `cmd /C` on Windows, so pipes and
+/// redirections work
+fn get_shell_command(
+    command_line: &str,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> Command {
+    let command_line = substitute_variables(command_line, variables);
+
+    let mut command = get_shell(options);
+    command.arg(command_line);
+    command
+}
+
+/// The shell commands are passed to, waiting for the command line
+fn get_shell(options: &Options) -> Command {
+    let (program, flag) = if options.use_powershell {
+        ("Powershell", "-Command")
+    } else if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+
+    let mut command = Command::new(program);
+    command.arg(flag);
+    command
+}
+
+/// Builds a task with several commands as one shell command joining them
+/// with `&&`. Each command is split and quoted again first, so the shell
+/// sees the same arguments the commands would get on their own.
+fn get_chained_command(
+    task: &Task,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> Option<Command> {
+    let mut lines = Vec::new();
+
+    for command_line in &task.commands {
+        let args = get_command_args(task, command_line, variables, options)?;
+        let quoted: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+        lines.push(quoted.join(" "));
+    }
+
+    let mut command = get_shell(options);
+    command.arg(lines.join(" && "));
+    Some(command)
+}
+
+/// Replaces `$name` and `${name}` with the values of defined variables,
+/// and `$$` with `$`, anywhere in the text. Unknown variables are left
+/// for the shell
+fn substitute_variables(text: &str, variables: &HashMap<String, String>) -> String {
+    interpolate_variables(text, variables, true).0
+}
+
+/// Replaces `$name` and `${name}` anywhere in the text with the values
+/// of defined variables, and function calls like `${uppercase(name)}`
+/// with their results. `$$` becomes `$` when `unescape` is set. Also
+/// returns the first reference to an undefined variable, if any
+fn interpolate_variables(
+    text: &str,
+    variables: &HashMap<String, String>,
+    unescape: bool,
+) -> (String, Option<String>) {
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut result = String::new();
+    let mut unresolved = None;
+    let mut rest = text;
+
+    while let Some(index) = rest.find('$') {
+        result.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push_str(if unescape { "$" } else { "$$" });
+            rest = after;
+            continue;
+        }
+
+        let braced = rest.starts_with('{');
+        let (name, length) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = rest.find(|c| !is_identifier(c)).unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+
+        // A $ that isn't followed by a name is just a $
+        if !name.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic()) {
+            result.push('$');
+            continue;
+        }
+
+        let value = match variables.get(&format!("${}", name)) {
+            Some(value) => Some(value.clone()),
+            None if braced => functions::call(name, variables),
+            None => None,
+        };
+
+        match value {
+            Some(value) => result.push_str(&value),
+            None => {
+                unresolved.get_or_insert_with(|| format!("${}", &rest[..length]));
+                result.push('$');
+                result.push_str(&rest[..length]);
+            }
+        }
+
+        rest = &rest[length..];
+    }
+
+    result.push_str(rest);
+    (result, unresolved)
+}
+
+/// Builds the `PATH` for a task's `path` option. Each entry can be a
+/// variable, and `$PATH` is replaced with the inherited `PATH`
+fn get_search_path(path: &str, variables: &HashMap<String, String>) -> OsString {
+    let inherited = std::env::var_os("PATH").unwrap_or_default();
+    let mut dirs = Vec::new();
+
+    for dir in std::env::split_paths(path) {
+        let name = dir.to_string_lossy();
+
+        if name == "$PATH" {
+            dirs.extend(std::env::split_paths(&inherited));
+        } else if let Some(value) = variables.get(name.as_ref()) {
+            dirs.extend(std::env::split_paths(value));
+        } else {
+            dirs.push(dir);
+        }
+    }
+
+    std::env::join_paths(dirs).unwrap_or_else(|e| {
+        warn!("invalid path {}: {}", path, e);
+        inherited
+    })
+}
+
+/// Applies a task's `max_memory` and `max_cpu` limits to the child
+/// process. The OS kills the child if it goes over them.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn apply_resource_limits(command: &mut Command, task: &Task) {
+    use std::os::unix::process::CommandExt;
+
+    #[cfg(target_os = "linux")]
+    type RlimT = std::ffi::c_ulong;
+    #[cfg(target_os = "macos")]
+    type RlimT = u64;
+
+    #[repr(C)]
+    struct Rlimit {
+        current: RlimT,
+        max: RlimT,
+    }
+
+    extern "C" {
+        fn setrlimit(resource: i32, limit: *const Rlimit) -> i32;
+    }
+
+    const RLIMIT_CPU: i32 = 0;
+    #[cfg(target_os = "linux")]
+    const RLIMIT_AS: i32 = 9;
+    #[cfg(target_os = "macos")]
+    const RLIMIT_AS: i32 = 5;
+
+    let limits: Vec<(i32, RlimT)> = [(RLIMIT_AS, task.max_memory), (RLIMIT_CPU, task.max_cpu)]
+        .iter()
+        .filter_map(|&(resource, limit)| limit.map(|limit| (resource, limit as RlimT)))
+        .collect();
+
+    if limits.is_empty() {
+        return;
+    }
+
+    // Runs in the child just before exec, where only setrlimit is called
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in &limits {
+                let limit = Rlimit {
+                    current: *limit,
+                    max: *limit,
+                };
+
+                if setrlimit(*resource, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
+
+/// Resource limits aren't supported here, so they are ignored
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn apply_resource_limits(_command: &mut Command, task: &Task) {
+    if task.max_memory.is_some() || task.max_cpu.is_some() {
+        warn!(
+            "task({}): max_memory and max_cpu are not supported on this platform",
+            task.name
+        );
+    }
+}
+
+/// The resource limit and scheduling options a task sets
+fn get_limit_options(task: &Task) -> Vec<&'static str> {
+    let options = [
+        ("max_memory", task.max_memory.is_some()),
+        ("max_cpu", task.max_cpu.is_some()),
+        ("priority", task.priority.is_some()),
+        ("cpu_affinity", !task.cpu_affinity.is_empty()),
+    ];
+    options
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Applies a task's `priority` and `cpu_affinity` to the child process.
+/// Linux also derives the I/O priority from the nice value
+#[cfg(unix)]
+fn apply_scheduling(command: &mut Command, task: &Task) {
+    use std::os::unix::process::CommandExt;
+
+    extern "C" {
+        fn setpriority(which: i32, who: u32, priority: i32) -> i32;
+    }
+
+    const PRIO_PROCESS: i32 = 0;
+
+    if let Some(priority) = task.priority {
+        // Runs in the child just before exec, like the resource limits
+        unsafe {
+            command.pre_exec(move || {
+                if setpriority(PRIO_PROCESS, 0, priority) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                Ok(())
+            });
+        }
+    }
+
+    if !task.cpu_affinity.is_empty() {
+        set_cpu_affinity(command, task);
+    }
+}
+
+/// Windows has priority classes instead of nice values, so the closest
+/// one is used
+#[cfg(windows)]
+fn apply_scheduling(command: &mut Command, task: &Task) {
+    use std::os::windows::process::CommandExt;
+
+    const IDLE_PRIORITY_CLASS: u32 = 0x40;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x20;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x8000;
+    const HIGH_PRIORITY_CLASS: u32 = 0x80;
+
+    if let Some(priority) = task.priority {
+        let class = match priority {
+            15..=19 => IDLE_PRIORITY_CLASS,
+            1..=14 => BELOW_NORMAL_PRIORITY_CLASS,
+            0 => NORMAL_PRIORITY_CLASS,
+            -14..=-1 => ABOVE_NORMAL_PRIORITY_CLASS,
+            _ => HIGH_PRIORITY_CLASS,
+        };
+        command.creation_flags(class);
+    }
+
+    if !task.cpu_affinity.is_empty() {
+        warn!(
+            "task({}): cpu_affinity is only supported on Linux",
+            task.name
+        );
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn apply_scheduling(_command: &mut Command, task: &Task) {
+    if task.priority.is_some() || !task.cpu_affinity.is_empty() {
+        warn!(
+            "task({}): priority and cpu_affinity are not supported on this platform",
+            task.name
+        );
+    }
+}
+
+/// Pins the child process to the task's CPUs
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(command: &mut Command, task: &Task) {
+    use std::os::unix::process::CommandExt;
+
+    extern "C" {
+        fn sched_setaffinity(pid: i32, size: usize, mask: *const u64) -> i32;
+    }
+
+    // The size of a cpu_set_t, with a bit for each of 1024 CPUs
+    let mut mask = [0u64; 16];
+    for &cpu in &task.cpu_affinity {
+        mask[cpu / 64] |= 1 << (cpu % 64);
+    }
+
+    unsafe {
+        command.pre_exec(move || {
+            if sched_setaffinity(0, std::mem::size_of_val(&mask), mask.as_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_cpu_affinity(_command: &mut Command, task: &Task) {
+    warn!(
+        "task({}): cpu_affinity is only supported on Linux",
+        task.name
+    );
+}
+
+/// Quotes and escapes a string for JSON output
+fn json_string(value: &str) -> String {
+    let mut result = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+/// Formats task durations in seconds as a JSON object sorted by task name
+fn get_durations_json(durations: &BTreeMap<String, Duration>) -> String {
+    let entries: Vec<String> = durations
+        .iter()
+        .map(|(name, duration)| format!("{}: {:.3}", json_string(name), duration.as_secs_f64()))
+        .collect();
+
+    format!("{{{}}}", entries.join(", "))
+}
+
+/// Quotes an argument so a POSIX shell reads it back unchanged
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return String::from(arg);
+    }
+
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Formats a command as a shell command line
+fn get_command_line(command: &Command) -> String {
+    let mut parts = Vec::new();
+
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            parts.push(format!(
+                "{}={}",
+                key.to_string_lossy(),
+                shell_quote(&value.to_string_lossy())
+            ));
+        }
+    }
+
+    parts.push(get_echo_line(command));
+
+    // A subshell keeps the directory change from affecting later commands
+    match command.get_current_dir() {
+        Some(dir) => format!(
+            "(cd {} && {})",
+            shell_quote(&dir.to_string_lossy()),
+            parts.join(" ")
+        ),
+        None => parts.join(" "),
+    }
+}
+
+/// Prints the command a task runs and its working directory with `-v`,
+/// and the environment variables it sets with `-vv`
+fn print_command_details(task_name: &str, command: &Command) {
+    if !log::enabled(log::Level::Verbose) {
+        return;
+    }
+
+    verbose!("task({}): command: {}", task_name, get_echo_line(command));
+
+    let cwd = match command.get_current_dir() {
+        Some(dir) => dir.to_path_buf(),
+        None => std::env::current_dir().unwrap_or_default(),
+    };
+    verbose!("task({}): cwd: {}", task_name, cwd.display());
+
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            trace!(
+                "task({}): env: {}={}",
+                task_name,
+                key.to_string_lossy(),
+                value.to_string_lossy()
+            );
+        }
+    }
+}
+
+/// Formats just a command's program and arguments, shell quoted
+fn get_echo_line(command: &Command) -> String {
+    let mut parts = vec![shell_quote(&command.get_program().to_string_lossy())];
+
+    for arg in command.get_args() {
+        parts.push(shell_quote(&arg.to_string_lossy()));
+    }
+
+    parts.join(" ")
+}
+
+/// Creates a standalone shell script running the queue in order
+fn get_export_script(
+    queue: &VecDeque<String>,
+    tasks: &HashMap<String, Task>,
+    variables: &mut HashMap<String, String>,
+    options: &Options,
+) -> String {
+    let mut script = String::from("#!/bin/sh\nset -e\n");
+
+    for task_name in queue {
+        let task = match tasks.get(task_name) {
+            Some(task) => task,
+            None => continue,
+        };
+
+        resolve_command_substitutions(task, variables);
+
+        if let Some(command) = build_command(task, variables, options) {
+            script.push_str(&format!("\n# task({})\n", task_name));
+            script.push_str(&get_command_line(&command));
+            script.push('\n');
+        }
+    }
+
+    script
+}
+
+/// Finds a program the same way the OS would when spawning it, using
+/// the command's `PATH` if it sets one
+fn find_program(program: &str, command: &Command) -> Option<PathBuf> {
+    let path = Path::new(program);
+
+    // Programs given as a path aren't looked up in PATH
+    if program.contains('/') || program.contains('\\') {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+
+    let extensions: Vec<String> = if cfg!(windows) {
+        let pathext = std::env::var("PATHEXT").unwrap_or_else(|_| String::from(".EXE;.BAT;.CMD"));
+        std::iter::once(String::new())
+            .chain(pathext.split(';').map(String::from))
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+
+    let paths = command
+        .get_envs()
+        .find(|(key, _)| *key == "PATH")
+        .and_then(|(_, value)| value.map(OsString::from))
+        .or_else(|| std::env::var_os("PATH"))?;
+
+    for dir in std::env::split_paths(&paths) {
+        for extension in &extensions {
+            let candidate = dir.join(format!("{}{}", program, extension));
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+        }
+    }
+
+    None
+}
+
+/// Prints the commands the execute queue would run without running
+/// them. Returns false if `--check-programs` found a missing program.
+fn print_dry_run(
+    queue: &VecDeque<String>,
+    tasks: &HashMap<String, Task>,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> bool {
+    let mut all_found = true;
+
+    for task_name in queue {
+        let task = match tasks.get(task_name) {
+            Some(task) => task,
+            None => continue,
+        };
+
+        let command = match build_command(task, variables, options) {
+            Some(command) => command,
+            None => continue,
+        };
+
+        println!("task({}): {}", task_name, get_command_line(&command));
+
+        if !options.check_programs {
+            continue;
+        }
+
+        for program in get_programs(task, &command, variables, options) {
+            if find_program(&program, &command).is_none() {
+                warn!("task({}): program {} not found", task_name, program);
+                all_found = false;
+            }
+        }
+    }
+
+    all_found
+}
+
+/// The programs a task runs, which are the first word of each of its
+/// commands rather than the shell they are joined in. Scripts, remote
+/// and container tasks only need the program that runs them
+fn get_programs(
+    task: &Task,
+    command: &Command,
+    variables: &HashMap<String, String>,
+    options: &Options,
+) -> Vec<String> {
+    if task.script.is_some()
+        || task.runs_on.is_some()
+        || task.container.is_some()
+        || options.use_powershell
+    {
+        return vec![command.get_program().to_string_lossy().into_owned()];
+    }
+
+    let mut programs = Vec::new();
+
+    for command_line in &task.commands {
+        // Builtins are part of this tool
+        if split_builtin(command_line).is_some() {
+            continue;
+        }
+
+        let program = if task.shell == Some(true) {
+            tokenize_command(&substitute_variables(command_line, variables))
+                .into_iter()
+                .next()
+        } else {
+            get_command_args(task, command_line, variables, options)
+                .and_then(|args| args.into_iter().next())
+        };
+
+        if let Some(program) = program.filter(|p| !programs.contains(p)) {
+            programs.push(program);
+        }
+    }
+
+    programs
+}
+
+/// Prints every task with its description and expanded command, then
+/// the order the queue runs them in
+fn print_task_list(
+    queue: &VecDeque<String>,
+    tasks: &HashMap<String, Task>,
+    groups: &HashMap<String, VecDeque<String>>,
+    variables: &HashMap<String, String>,
+) {
+    let mut names: Vec<&String> = tasks.keys().collect();
+    names.sort();
+
+    println!("tasks:");
+
+    for name in names {
+        let task = &tasks[name];
+        let title = if task.aliases.is_empty() {
+            name.clone()
+        } else {
+            format!("{} ({})", name, task.aliases.join(", "))
+        };
+
+        match &task.description {
+            Some(description) => println!("  {}: {}", title, description),
+            None => println!("  {}", title),
+        }
+
+        match &task.command_file {
+            Some(file) => println!("    command_file = {}", file),
+            None => {
+                for command in &task.commands {
+                    println!("    {}", substitute_variables(command, variables));
+                }
+            }
+        }
+    }
+
+    let mut group_names: Vec<&String> = groups.keys().collect();
+    group_names.sort();
+
+    if !group_names.is_empty() {
+        println!("groups:");
+    }
+
+    for name in group_names {
+        let members: Vec<&str> = groups[name].iter().map(|name| name.as_str()).collect();
+        println!("  {}: {}", name, members.join(", "));
+    }
+
+    let order: Vec<&str> = queue.iter().map(|name| name.as_str()).collect();
+    println!("execute: {}", order.join(", "));
+}
+
+/// Locks `.build.lock` in the config's directory so another run there
+/// has to wait, unless `--no-lock` is used or nothing will be run
+fn lock_directory(options: &Options) -> Result<Option<File>, String> {
+    if options.no_lock || options.list || options.dry_run || options.export_script.is_some() {
+        return Ok(None);
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(BUILD_LOCK)
+        .map_err(|e| format!("failed to open {}: {}", BUILD_LOCK, e))?;
+
+    match file.try_lock() {
+        Ok(()) => return Ok(Some(file)),
+        Err(std::fs::TryLockError::WouldBlock) if options.wait_for_lock => {}
+        Err(std::fs::TryLockError::WouldBlock) => {
+            return Err(String::from(
+                "another run is using this directory, use --wait to wait for it or --no-lock to run anyway",
+            ))
+        }
+        Err(std::fs::TryLockError::Error(e)) => {
+            return Err(format!("failed to lock {}: {}", BUILD_LOCK, e))
+        }
+    }
+
+    info!("waiting for another run in this directory to finish...");
+    file.lock()
+        .map_err(|e| format!("failed to lock {}: {}", BUILD_LOCK, e))?;
+    Ok(Some(file))
+}
+
+/// The artifact cache directory, from `--cache-dir` or `[settings]
+/// cache_dir`. There is no cache unless one is set
+fn get_cache_dir(settings: &HashMap<String, String>, options: &Options) -> Option<PathBuf> {
+    options
+        .cache_dir
+        .as_ref()
+        .or_else(|| settings.get("cache_dir"))
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Runs `cache clear` or `cache stats` on the artifact cache
+fn run_cache_command(command: &str, config: &Config, options: &Options) -> i32 {
+    let dir = match get_cache_dir(&config.settings, options) {
+        Some(dir) => dir,
+        None => {
+            error!("no cache directory, set cache_dir in [settings] or pass --cache-dir");
+            return 1;
+        }
+    };
+
+    let result = match command {
+        "clear" => artifacts::clear(&dir).map(|removed| {
+            info!("removed {} cache entries from {}", removed, dir.display());
+        }),
+        "stats" => artifacts::stats(&dir).map(|(entries, size)| {
+            println!("{}", dir.display());
+            println!("  entries  {}", entries);
+            println!("  size     {}", format_size(size));
+        }),
+        _ => {
+            error!(
+                "unknown cache command `{}`, expected clear or stats",
+                command
+            );
+            return 2;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}: {}", dir.display(), e);
+            1
+        }
+    }
+}
+
+/// Draws the task graph with the tasks the named tasks, or the execute
+/// queue, would run marked
+fn print_graph(config: &Config, options: &Options) -> i32 {
+    let tasks: HashMap<String, Task> = config
+        .tasks
+        .iter()
+        .map(|task| (task.name.clone(), task.clone()))
+        .collect();
+
+    let queue = if options.tasks.is_empty() {
+        config.queue.clone()
+    } else {
+        options.tasks.iter().cloned().collect()
+    };
+    let queue = resolve_task_names(&queue, &tasks, &config.groups, &mut Vec::new());
+
+    // A graph with a cycle is still drawn, to show where it is
+    let planned: HashSet<String> = match order_by_dependencies(&queue, &tasks) {
+        Ok(plan) => plan.into_iter().collect(),
+        Err(e) => {
+            warn!("{}", e);
+            HashSet::new()
+        }
+    };
+
+    match graph::draw(config, &planned, &options.graph_format) {
+        Some(graph) => {
+            print!("{}", graph);
+            0
+        }
+        None => {
+            error!(
+                "unknown graph format `{}`, expected one of {}",
+                options.graph_format,
+                graph::FORMATS.join(", ")
+            );
+            2
+        }
+    }
+}
+
+/// Prints the tree of a task's dependencies, for `--print-tree`
+fn print_tree(config: &Config, name: &str) -> i32 {
+    let task = config
+        .tasks
+        .iter()
+        .find(|task| task.name == name || task.aliases.iter().any(|alias| alias == name));
+
+    match task {
+        Some(task) => {
+            print!("{}", graph::to_dependency_tree(config, task));
+            0
+        }
+        None => {
+            error!("unknown task {}", name);
+            2
+        }
+    }
+}
+
+/// Prints the names tasks can be run by, one per line
+fn print_task_names(config: &Config) {
+    let mut names: Vec<&String> = config
+        .tasks
+        .iter()
+        .flat_map(|task| std::iter::once(&task.name).chain(&task.aliases))
+        .chain(config.groups.keys())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    for name in names {
+        println!("{}", name);
+    }
+}
+
+/// Whether to ask which tasks to run, when nothing says what to run and
+/// someone is at the terminal to answer
+fn should_pick_tasks(config: &Config, options: &Options) -> bool {
+    config.queue.is_empty()
+        && !config.tasks.is_empty()
+        && options.tasks.is_empty()
+        && options.run.is_empty()
+        && options.pipe.is_empty()
+        && options.clean.is_none()
+        && !options.clean_all
+        && options.export_script.is_none()
+        && !options.list
+        && !options.watch
+        && !options.json_output
+        && std::io::stdin().is_terminal()
+        && std::io::stdout().is_terminal()
+}
+
+/// Shows a numbered menu of the tasks and runs the ones picked by number
+/// or name, until `q` or the end of input. Returns the last run's code
+fn pick_tasks(config: &Config, options: &Options) -> i32 {
+    let width = config
+        .tasks
+        .iter()
+        .map(|task| task.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut code = 0;
+
+    loop {
+        println!("tasks:");
+        for (i, task) in config.tasks.iter().enumerate() {
+            match &task.description {
+                Some(description) => println!(
+                    "  {:>2}) {:<width$}  {}",
+                    i + 1,
+                    task.name,
+                    description,
+                    width = width
+                ),
+                None => println!("  {:>2}) {}", i + 1, task.name),
+            }
+        }
+
+        print!("run (numbers or names, q to quit): ");
+        let _ = std::io::stdout().flush();
+
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+
+        let line = line.trim();
+        if line == "q" || line == "quit" {
+            break;
+        }
+
+        let mut names = Vec::new();
+        for choice in line.split(|c: char| c == ',' || c.is_whitespace()) {
+            match choice.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= config.tasks.len() => {
+                    names.push(config.tasks[n - 1].name.clone())
+                }
+                Ok(n) => warn!("there is no task {}", n),
+                Err(_) if !choice.is_empty() => names.push(String::from(choice)),
+                Err(_) => {}
+            }
+        }
+
+        if names.is_empty() {
+            continue;
+        }
+
+        let picked = Options {
+            tasks: names,
+            ..options.clone()
+        };
+        code = run_config(config.clone(), &picked);
+
+        if is_interrupted() {
+            return INTERRUPT_EXIT_CODE;
+        }
+    }
+
+    code
+}
+
+/// Writes a script and makes it executable
+fn write_script(path: &str, script: &str) -> std::io::Result<()> {
+    std::fs::write(path, script)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the files and directories a task declared with `clean`,
+/// relative to the task's config. Paths that don't exist are ignored.
+fn clean_task(task: &Task, variables: &HashMap<String, String>) {
+    for path in &task.clean {
+        let name = substitute_variables(path, variables);
+        let path = task.base_dir.join(&name);
+
+        let result = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else if path.exists() {
+            std::fs::remove_file(&path)
+        } else {
+            continue;
+        };
+
+        match result {
+            Ok(_) => println!("task({}): removed {}", task.name, name),
+            Err(e) => println!("task({}): failed to remove {}\n{}", task.name, name, e),
+        }
+    }
+}
+
+/// Checks if `text` references the variable as `$name` or `${name}`
+fn references_variable(text: &str, name: &str) -> bool {
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    if text.contains(&format!("${{{}}}", name)) {
+        return true;
+    }
+
+    let pattern = format!("${}", name);
+
+    text.match_indices(&pattern).any(|(index, _)| {
+        let rest = &text[index + pattern.len()..];
+        !rest.starts_with(is_identifier)
+    })
+}
+
+/// The options of a task that variables are replaced in, and its
+/// `command_file` script
+fn get_task_texts(task: &Task) -> Vec<&str> {
+    let mut texts: Vec<&str> = task
+        .commands
+        .iter()
+        .chain(&task.clean)
+        .chain(&task.inputs)
+        .chain(&task.outputs)
+        .map(|text| text.as_str())
+        .collect();
+
+    texts.extend(task.env.iter().map(|(_, value)| value.as_str()));
+
+    for text in [
+        &task.cwd,
+        &task.path,
+        &task.when,
+        &task.log_file,
+        &task.runs_on,
+        &task.container,
+        &task.script,
+    ] {
+        texts.extend(text.as_deref());
+    }
+
+    texts
+}
+
+/// Finds the variables no task, setting or other variable references
+fn get_unused_variables(
+    variables: &HashMap<String, String>,
+    tasks: &HashMap<String, Task>,
+    settings: &HashMap<String, String>,
+) -> Vec<String> {
+    let mut texts: Vec<&str> = settings.values().map(|v| v.as_str()).collect();
+
+    for task in tasks.values() {
+        texts.extend(get_task_texts(task));
+    }
+
+    let mut unused: Vec<String> = variables
+        .keys()
+        .filter(|key| {
+            let name = key.trim_start_matches('$');
+
+            // A variable used by another variable counts as used
+            let in_variables = variables
+                .iter()
+                .any(|(other, value)| other != *key && references_variable(value, name));
+
+            !in_variables && !texts.iter().any(|text| references_variable(text, name))
+        })
+        .cloned()
+        .collect();
+
+    unused.sort();
+    unused
+}
+
+/// Returns today's date in UTC as `YYYY-MM-DD`
+fn get_date() -> String {
+    format_date(get_unix_time())
+}
+
+/// Returns the current time in UTC as `YYYY-MM-DD HH:MM:SS`
+fn get_timestamp() -> String {
+    let seconds = get_unix_time();
+    let time = seconds % 86400;
+
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        format_date(seconds),
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn get_unix_time() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Formats seconds since 1970-01-01 as a `YYYY-MM-DD` date
+fn format_date(seconds: u64) -> String {
+    let (year, month, day) = get_calendar_date(seconds);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// The UTC year, month and day of seconds since 1970-01-01
+fn get_calendar_date(seconds: u64) -> (i64, i64, i64) {
+    // Converts days since 1970-01-01 to a calendar date
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// Parses a signal name like `TERM`, `SIGTERM` or a number
+fn parse_signal(name: &str) -> Option<i32> {
+    let name = name.trim_start_matches("SIG");
+
+    match name {
+        "HUP" => Some(1),
+        "INT" => Some(SIGINT),
+        "QUIT" => Some(3),
+        "KILL" => Some(SIGKILL),
+        "TERM" => Some(SIGTERM),
+        _ => name.parse().ok(),
+    }
+}
+
+/// Stops the running tasks on SIGINT and SIGTERM instead of exiting, so
+/// they aren't left running and `on_interrupt` tasks can run
+#[cfg(unix)]
+fn install_interrupt_handler() {
+    extern "C" {
+        fn signal(signal: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    extern "C" fn on_signal(_signal: i32) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        signal(SIGINT, on_signal);
+        signal(SIGTERM, on_signal);
+    }
+}
+
+/// Ctrl-C is left to the console on other platforms
+#[cfg(not(unix))]
+fn install_interrupt_handler() {}
+
+fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// When to color the output, from `--color`
+#[derive(Clone, Copy)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Flags passed on the command line
+#[derive(Clone)]
+struct Options {
+    use_powershell: bool,
+    is_async: bool,
+    shuffle_seed: Option<u64>,
+    /// The git revision `--affected` compares against
+    affected: Option<String>,
+    env_prefix: Option<String>,
+    print_duration_json: bool,
+    tail_on_failure: Option<usize>,
+    quiet_on_success: bool,
+    jobs: Option<usize>,
+    export_script: Option<String>,
+    clean: Option<String>,
+    clean_all: bool,
+    report_unused_vars: bool,
+    strict: bool,
+    pipe: Vec<String>,
+    dry_run: bool,
+    check_programs: bool,
+    match_policy: MatchPolicy,
+    require_all_vars: bool,
+    run: Vec<String>,
+    watch: bool,
+    timeout: Option<Duration>,
+    timeout_signal: i32,
+    timeout_grace: Duration,
+    color: bool,
+    keep_temp: bool,
+    merge_streams: bool,
+    fail_on_stderr: bool,
+    config_dir: Option<String>,
+    echo: bool,
+    profile_memory: bool,
+    tasks: Vec<String>,
+    stream_output: bool,
+    explicit_env: bool,
+    force: bool,
+    /// Where `--log-file` appends a record of every run
+    transcript: Option<String>,
+    /// Wait for another run to finish instead of stopping
+    wait_for_lock: bool,
+    no_lock: bool,
+    list: bool,
+    /// Just the task, alias and group names, for completion scripts
+    list_names: bool,
+    config: Option<String>,
+    init: bool,
+    /// The shell to print a completion script for
+    completions: Option<String>,
+    /// The Makefile `import` converts
+    import: Option<String>,
+    /// The format `export` writes the config in
+    export: Option<String>,
+    graph: bool,
+    /// The format `graph` draws in, from `--format`
+    graph_format: String,
+    /// The task `--print-tree` shows the dependencies of
+    print_tree: Option<String>,
+    /// `clear` or `stats` for the `cache` subcommand
+    cache_command: Option<String>,
+    /// The artifact cache from `--cache-dir`
+    cache_dir: Option<String>,
+    /// `start` or `stop` for the `daemon` subcommand
+    daemon: Option<String>,
+    /// Send the command line to the daemon instead of running it
+    client: bool,
+    keep_going: bool,
+    overrides: Vec<(String, String)>,
+    json_output: bool,
+    log_level: log::Level,
+    /// The arguments after `--`, for `$ARGS`
+    task_args: Vec<String>,
+    profile: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            use_powershell: false,
+            is_async: false,
+            shuffle_seed: None,
+            affected: None,
+            env_prefix: None,
+            print_duration_json: false,
+            tail_on_failure: None,
+            quiet_on_success: false,
+            jobs: None,
+            export_script: None,
+            clean: None,
+            clean_all: false,
+            report_unused_vars: false,
+            strict: false,
+            pipe: Vec::new(),
+            dry_run: false,
+            check_programs: false,
+            match_policy: MatchPolicy::Last,
+            require_all_vars: false,
+            run: Vec::new(),
+            watch: false,
+            timeout: None,
+            timeout_signal: SIGKILL,
+            timeout_grace: Duration::from_secs(5),
+            color: false,
+            keep_temp: false,
+            merge_streams: false,
+            fail_on_stderr: false,
+            config_dir: None,
+            echo: false,
+            profile_memory: false,
+            tasks: Vec::new(),
+            stream_output: false,
+            explicit_env: false,
+            force: false,
+            transcript: None,
+            wait_for_lock: false,
+            no_lock: false,
+            list: false,
+            list_names: false,
+            config: None,
+            init: false,
+            completions: None,
+            import: None,
+            export: None,
+            graph: false,
+            graph_format: String::from("tree"),
+            print_tree: None,
+            cache_command: None,
+            cache_dir: None,
+            daemon: None,
+            client: false,
+            keep_going: false,
+            overrides: Vec::new(),
+            json_output: false,
+            log_level: log::Level::Normal,
+            task_args: Vec::new(),
+            profile: None,
+        }
+    }
+}
+
+/// Loads the root config and everything it extends. Also returns every
+/// file that was loaded so they can be watched
+fn load_root_config(config: &str, options: &Options) -> Result<(Config, Vec<PathBuf>), String> {
+    let root = Path::new(get_config_path(options));
+    let mut chain: Vec<PathBuf> = root.canonicalize().into_iter().collect();
+    let stack = chain.clone();
+
+    let config = load_config(root, config, &mut chain, &stack, options.match_policy)?;
+    Ok((config, chain))
+}
+
+/// Loads every `.cfg` file in a directory in name order and merges them,
+/// with later files overriding earlier ones like an extended config
+fn load_config_dir(dir: &Path, options: &Options) -> Result<(Config, Vec<PathBuf>), String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("failed to read {}: {}", dir.display(), e))?;
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|ext| ext == "cfg" || ext == "toml")
+        })
+        .collect();
+    paths.sort();
+
+    if paths.is_empty() {
+        return Err(format!("no .cfg files found in {}", dir.display()));
+    }
+
+    // The directory is watched too so added and removed files are noticed
+    let mut watched: Vec<PathBuf> = dir.canonicalize().into_iter().collect();
+    let mut merged = Config::parse_with_policy("", options.match_policy);
+
+    for path in paths {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+
+        // Each file has its own chain, so two files can extend the same base
+        let mut chain: Vec<PathBuf> = path.canonicalize().into_iter().collect();
+        let stack = chain.clone();
+        let config = load_config(&path, &text, &mut chain, &stack, options.match_policy)?;
+        watched.append(&mut chain);
+
+        merged = merged.merge(config, options.match_policy);
+    }
+
+    Ok((merged, watched))
+}
+
+/// Returns when each file was last modified
+fn get_modified_times(paths: &[PathBuf]) -> Vec<Option<std::time::SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Matches a path against a glob where `*` and `?` match within one
+/// directory and `**` matches any number of directories
+fn matches_glob(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    matches_glob_parts(&pattern, &path)
+}
+
+/// Matches the glob's directories against the path's
+fn matches_glob_parts(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ["**", rest @ ..] => (0..=path.len()).any(|i| matches_glob_parts(rest, &path[i..])),
+        [part, rest @ ..] => match path.first() {
+            Some(name) => {
+                let part: Vec<char> = part.chars().collect();
+                let name: Vec<char> = name.chars().collect();
+                matches_wildcard(&part, &name) && matches_glob_parts(rest, &path[1..])
+            }
+            None => false,
+        },
+    }
+}
+
+/// Matches one file name against a pattern with `*` and `?`
+fn matches_wildcard(pattern: &[char], name: &[char]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        ['*', rest @ ..] => (0..=name.len()).any(|i| matches_wildcard(rest, &name[i..])),
+        ['?', rest @ ..] => !name.is_empty() && matches_wildcard(rest, &name[1..]),
+        [c, rest @ ..] => name.first() == Some(c) && matches_wildcard(rest, &name[1..]),
+    }
+}
+
+/// Finds the files matching a glob, relative to `base`. A glob without
+/// wildcards is returned as is so it can be watched before it exists.
+fn expand_glob(base: &Path, pattern: &str) -> Vec<PathBuf> {
+    let parts: Vec<&str> = pattern.split('/').collect();
+    let literal = parts
+        .iter()
+        .take_while(|part| !part.contains(['*', '?']))
+        .count();
+
+    if literal == parts.len() {
+        return vec![base.join(pattern)];
+    }
+
+    // Only the directory before the first wildcard has to be searched
+    let prefix = parts[..literal].join("/");
+    let mut files = Vec::new();
+    collect_glob_matches(&base.join(&prefix), &prefix, pattern, &mut files);
+    files
+}
+
+/// Walks a directory adding the files whose relative path matches the glob
+fn collect_glob_matches(dir: &Path, relative: &str, pattern: &str, files: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let relative = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+
+        // Symlinked directories aren't followed so links can't loop
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => {
+                collect_glob_matches(&entry.path(), &relative, pattern, files)
+            }
+            Ok(_) if matches_glob(pattern, &relative) => files.push(entry.path()),
+            _ => {}
+        }
+    }
+}
+
+type WatchedFiles = HashMap<String, BTreeMap<PathBuf, Option<std::time::SystemTime>>>;
+
+/// Reads the modified time of every file the tasks' watch globs match
+fn get_watched_files(tasks: &[Task]) -> WatchedFiles {
+    let mut watched = HashMap::new();
+
+    for task in tasks.iter().filter(|task| !task.watch.is_empty()) {
+        let files = task
+            .watch
+            .iter()
+            .flat_map(|pattern| expand_glob(&task.base_dir, pattern))
+            .map(|path| {
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                (path, modified)
+            })
+            .collect();
+
+        watched.insert(task.name.clone(), files);
+    }
+
+    watched
+}
+
+/// Re-runs the config whenever it or a config it extends changes. If the
+/// changed config can't be loaded the previous one keeps being used.
+/// Tasks with a `watch` option are re-run on their own when their files change.
+fn watch_config(mut config: Config, mut chain: Vec<PathBuf>, options: &Options) -> ! {
+    let mut modified = get_modified_times(&chain);
+    let mut watched = get_watched_files(&config.tasks);
+    let watched_count: usize = watched.values().map(|files| files.len()).sum();
+
+    let count = chain.len() + watched_count;
+    info!("watching {} file(s) for changes", count);
+
+    loop {
+        std::thread::sleep(Duration::from_millis(500));
+
+        if is_interrupted() {
+            std::process::exit(INTERRUPT_EXIT_CODE);
+        }
+
+        let current = get_modified_times(&chain);
+        if current == modified {
+            let files = get_watched_files(&config.tasks);
+            if files != watched {
+                watched = rerun_changed_tasks(&config, &watched, files, options);
+            }
+
+            continue;
+        }
+
+        modified = current;
+
+        match reload_config(options) {
+            Ok((new_config, new_chain)) => {
+                config = new_config;
+                chain = new_chain;
+                modified = get_modified_times(&chain);
+            }
+            Err(e) => warn!("{}, keeping the previous config", e),
+        }
+
+        watched = get_watched_files(&config.tasks);
+        run_config(config.clone(), options);
+    }
+}
+
+/// Loads the config again after it changed, with every file it loaded
+fn reload_config(options: &Options) -> Result<(Config, Vec<PathBuf>), String> {
+    match &options.config_dir {
+        Some(dir) => {
+            info!("{} changed, reloading...", dir);
+            load_config_dir(Path::new(dir), options)
+        }
+        None => {
+            let path = get_config_path(options);
+            info!("{} changed, reloading...", path);
+            std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path, e))
+                .and_then(|text| load_root_config(&text, options))
+        }
+    }
+}
+
+/// Waits for the watched files to stop changing, then runs the tasks whose
+/// files changed. Returns the files as they were before the run.
+fn rerun_changed_tasks(
+    config: &Config,
+    previous: &WatchedFiles,
+    mut files: WatchedFiles,
+    options: &Options,
+) -> WatchedFiles {
+    // Saving several files at once should only cause one run
+    loop {
+        std::thread::sleep(Duration::from_millis(200));
+
+        let settled = get_watched_files(&config.tasks);
+        if settled == files {
+            break;
+        }
+
+        files = settled;
+    }
+
+    let changed: Vec<String> = config
+        .tasks
+        .iter()
+        .filter(|task| files.get(&task.name) != previous.get(&task.name))
+        .map(|task| task.name.clone())
+        .collect();
+
+    info!("files changed, re-running {}", changed.join(", "));
+
+    let mut options = options.clone();
+    options.tasks = changed;
+    run_config(config.clone(), &options);
+
+    files
+}
+
+/// Reads the command line flags, finds and loads the config and runs it.
+/// Returns the exit code
+pub fn run_cli() -> i32 {
+    // Tasks run builtins like @copy through this binary
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let [flag, name, args @ ..] = args.as_slice() {
+        if flag == "--builtin" {
+            return builtins::run(name, args);
+        }
+    }
+
+    let mut options = parse_args(args.clone());
+    log::set_level(options.log_level);
+    log::set_color(options.color);
+    install_interrupt_handler();
+
+    if options.json_output {
+        redirect_stdout_for_events();
+    }
+
+    if options.init {
+        if let Err(e) = init_config(&options) {
+            error!("{}", e);
+            return 1;
+        }
+        return 0;
+    }
+
+    if let Some(makefile) = &options.import {
+        if let Err(e) = import_makefile(makefile, &options) {
+            error!("{}", e);
+            return 1;
+        }
+        return 0;
+    }
+
+    if let Some(shell) = &options.completions {
+        return match completions::get_script(shell) {
+            Some(script) => {
+                print!("{}", script);
+                0
+            }
+            None => {
+                error!(
+                    "unknown shell `{}`, expected one of {}",
+                    shell,
+                    completions::SHELLS.join(", ")
+                );
+                2
+            }
+        };
+    }
+
+    // Relative paths are sent before changing to the config's directory
+    let request = options.client.then(|| daemon::get_request(&args));
+
+    if options.config_dir.is_none() {
+        if let Err(e) = locate_config(&mut options) {
+            // Completing outside a project just completes nothing
+            if !options.list_names {
+                error!("{}", e);
+            }
+            return 1;
+        }
+    }
+
+    if let Some(action) = &options.daemon {
+        return daemon::run(action, &options);
+    }
+
+    // Everything but --client is for the daemon to run
+    if let Some(request) = request {
+        return daemon::send("run", &request);
+    }
+
+    match load_cli_config(&options) {
+        Ok(Some((config, chain))) => run_loaded_config(config, chain, &options),
+        Ok(None) => 0,
+        Err(e) => {
+            error!("{}", e);
+            1
+        }
+    }
+}
+
+/// Reads the config the options point to, with the files it was read
+/// from. An empty config is None
+fn load_cli_config(options: &Options) -> Result<Option<(Config, Vec<PathBuf>)>, String> {
+    let quiet = options.quiet_on_success;
+
+    match &options.config_dir {
+        Some(dir) => {
+            if !quiet {
+                info!("reading {}...", dir);
+            }
+
+            load_config_dir(Path::new(dir), options).map(Some)
+        }
+        None => {
+            if !quiet {
+                info!("reading {}...", get_config_path(options));
+            }
+
+            let config = get_build_config(options)?;
+
+            if config.is_empty() {
+                return Ok(None);
+            }
+
+            load_root_config(&config, options).map(Some)
+        }
+    }
+}
+
+/// Does what the command line asks for with a loaded config, like
+/// running its tasks or drawing its graph. Returns the exit code
+fn run_loaded_config(config: Config, chain: Vec<PathBuf>, options: &Options) -> i32 {
+    if options.list_names {
+        print_task_names(&config);
+        return 0;
+    }
+
+    if options.graph {
+        return print_graph(&config, options);
+    }
+
+    if let Some(name) = &options.print_tree {
+        return print_tree(&config, name);
+    }
+
+    if let Some(command) = &options.cache_command {
+        return run_cache_command(command, &config, options);
+    }
+
+    if let Some(format) = &options.export {
+        return match export::export(&config, format) {
+            Some(Ok(text)) => {
+                print!("{}", text);
+                0
+            }
+            Some(Err(e)) => {
+                error!("{}", e);
+                1
+            }
+            None => {
+                error!(
+                    "unknown format `{}`, expected one of {}",
+                    format,
+                    export::FORMATS.join(", ")
+                );
+                2
+            }
+        };
+    }
+
+    // The lock is held until the run ends
+    let _lock = match lock_directory(options) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
+
+    if should_pick_tasks(&config, options) {
+        return pick_tasks(&config, options);
+    }
+
+    if !options.watch {
+        let code = run_config(config, options);
+        return if is_interrupted() {
+            INTERRUPT_EXIT_CODE
+        } else {
+            code
+        };
+    }
+
+    run_config(config.clone(), options);
+    watch_config(config, chain, options)
+}
+
+/// Runs a loaded config with the given options. Returns the exit code:
+/// 1 for config errors, 2 for unknown tasks and the first failed task's
+/// code when a task fails
+fn run_config(config: Config, options: &Options) -> i32 {
+    let quiet = options.quiet_on_success;
+
+    let Config {
+        mut variables,
+        tasks,
+        settings,
+        exit_categories,
+        queue,
+        named_queues,
+        profiles,
+        groups,
+        parallel,
+    } = config;
+
+    // `parallel = true` in [execute] runs the tasks like -async
+    let parallel_options;
+    let options = if parallel == Some(true) && !options.is_async {
+        parallel_options = Options {
+            is_async: true,
+            ..options.clone()
+        };
+        &parallel_options
+    } else {
+        options
+    };
+
+    if let Some(prefix) = &options.env_prefix {
+        import_env_variables(&mut variables, std::env::vars(), prefix);
+    }
+
+    if let Err(code) = apply_profile(&mut variables, &profiles, &settings, options) {
+        return code;
+    }
+
+    // Variables set on the command line win over everything else
+    variables.extend(options.overrides.iter().cloned());
+
+    let transcript = options.transcript.clone().or_else(|| {
+        settings
+            .get("transcript")
+            .filter(|p| !p.is_empty())
+            .cloned()
+    });
+
+    if let Some(path) = transcript {
+        if let Err(e) = open_transcript(&path) {
+            warn!("failed to open {}: {}", path, e);
+        }
+    }
+
+    if !quiet {
+        let found = format!(
+            "found {} var(s) and {} task(s)",
+            variables.len(),
+            tasks.len()
+        );
+        info!("{}", found);
+    }
+
+    let interrupt_tasks: Vec<String> = tasks
+        .iter()
+        .filter(|task| task.on_interrupt)
+        .map(|task| task.name.clone())
+        .collect();
+
+    let mut tasks = match prepare_tasks(tasks, &settings, options) {
+        Ok(tasks) => tasks,
+        Err(code) => return code,
+    };
+
+    if options.report_unused_vars {
+        return report_unused_variables(&variables, &tasks, &settings, options);
+    }
+
+    // Shell commands get the arguments quoted, `$ARGS` on its own in other
+    // commands is replaced by each argument
+    let task_args: Vec<String> = options.task_args.iter().map(|a| shell_quote(a)).collect();
+    variables.insert(String::from("$ARGS"), task_args.join(" "));
+
+    let from_env = add_env_fallbacks(&mut variables, !options.explicit_env);
+    resolve_variable_references(&mut variables, &from_env);
+
+    if !options.pipe.is_empty() {
+        for name in &options.pipe {
+            match tasks.get(name) {
+                Some(task) => resolve_command_substitutions(task, &mut variables),
+                None => {
+                    error!("unknown task {}", name);
+                    return 2;
+                }
+            }
+        }
+
+        return if executor::run_pipeline(&options.pipe, &tasks, &variables, options) {
+            0
+        } else {
+            1
+        };
+    }
+
+    if options.clean_all || options.clean.is_some() {
+        return clean_tasks(&tasks, &mut variables, options);
+    }
+
+    let queue = match plan::build_queue(queue, &named_queues, &groups, &mut tasks, options) {
+        Ok(queue) => queue,
+        Err(code) => return code,
+    };
+
+    // Tasks are still listed when nothing would run
+    if queue.is_empty() && !options.list {
+        if !quiet {
+            info!("execute task is empty");
+        }
+        return 0;
+    }
+
+    if options.list {
+        print_task_list(&queue, &tasks, &groups, &variables);
+        return 0;
+    }
+
+    if let Some(path) = &options.export_script {
+        let script = get_export_script(&queue, &tasks, &mut variables, options);
+
+        return match write_script(path, &script) {
+            Ok(_) => {
+                info!("wrote {}", path);
+                0
+            }
+            Err(e) => {
+                error!("failed to write {}: {}", path, e);
+                1
+            }
+        };
+    }
+
+    if options.dry_run {
+        return if print_dry_run(&queue, &tasks, &variables, options) {
+            0
+        } else {
+            1
+        };
+    }
+
+    let run = executor::Run::new(&tasks, variables, &settings, &exit_categories, options);
+    run.execute(queue, &interrupt_tasks)
+}
+
+/// Adds the variables of the profile picked on the command line, or the
+/// default one in `[settings]`. An unknown profile returns the exit code
+fn apply_profile(
+    variables: &mut HashMap<String, String>,
+    profiles: &HashMap<String, HashMap<String, String>>,
+    settings: &HashMap<String, String>,
+    options: &Options,
+) -> Result<(), i32> {
+    // A profile picked on the command line wins over the default in [settings]
+    let profile = options
+        .profile
+        .clone()
+        .or_else(|| settings.get("profile").filter(|p| !p.is_empty()).cloned());
+
+    let profile = match profile {
+        Some(profile) => profile,
+        None => return Ok(()),
+    };
+
+    match profiles.get(&profile) {
+        Some(overrides) => {
+            variables.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
+            variables.entry(String::from("$PROFILE")).or_insert(profile);
+            Ok(())
+        }
+        None => {
+            error!("unknown profile {}", profile);
+
+            let mut known: Vec<&str> = profiles.keys().map(|name| name.as_str()).collect();
+            known.sort();
+            if !known.is_empty() {
+                info!("known profiles: {}", known.join(", "));
+            }
+            Err(2)
+        }
+    }
+}
+
+/// Fills in the task options that default to `[settings]` or the command
+/// line and reads each `command_file`, keyed by task name. A file that
+/// can't be read returns the exit code
+fn prepare_tasks(
+    tasks: Vec<Task>,
+    settings: &HashMap<String, String>,
+    options: &Options,
+) -> Result<HashMap<String, Task>, i32> {
+    let mut prepared = HashMap::new();
+
+    for mut task in tasks {
+        // A task's own path overrides the one in [settings]
+        if task.path.is_none() {
+            task.path = settings.get("path").filter(|p| !p.is_empty()).cloned();
+        }
+
+        if task.shell.is_none() {
+            task.shell = get_setting(settings, "shell");
+        }
+
+        if task.log_file.is_none() {
+            task.log_file = settings.get("log_file").filter(|p| !p.is_empty()).cloned();
+        }
+
+        if options.fail_on_stderr && task.fail_on_stderr.is_none() {
+            task.fail_on_stderr = Some(true);
+        }
+
+        // Like cwd, a command_file is relative to the config it's in
+        if let Some(file) = &task.command_file {
+            let path = task.base_dir.join(file);
+
+            match std::fs::read_to_string(&path) {
+                Ok(script) => task.script = Some(script),
+                Err(e) => {
+                    error!(
+                        "task({}): failed to read {}: {}",
+                        task.name,
+                        path.display(),
+                        e
+                    );
+                    return Err(1);
+                }
+            }
+        }
+
+        prepared.insert(task.name.clone(), task);
+    }
+
+    Ok(prepared)
+}
+
+/// Warns about the variables nothing uses, for `--report-unused-vars`.
+/// Returns 1 with `--strict` if there are any
+fn report_unused_variables(
+    variables: &HashMap<String, String>,
+    tasks: &HashMap<String, Task>,
+    settings: &HashMap<String, String>,
+    options: &Options,
+) -> i32 {
+    let unused = get_unused_variables(variables, tasks, settings);
+
+    for name in &unused {
+        warn!("variable {} is never used", name);
+    }
+
+    if unused.is_empty() && !options.quiet_on_success {
+        info!("every variable is used");
+    }
+
+    if options.strict && !unused.is_empty() {
+        return 1;
+    }
+
+    0
+}
+
+/// Removes the `clean` paths of the task given to `--clean`, or of every
+/// task with `--clean-all`. Returns 2 for an unknown task
+fn clean_tasks(
+    tasks: &HashMap<String, Task>,
+    variables: &mut HashMap<String, String>,
+    options: &Options,
+) -> i32 {
+    let mut names: Vec<&String> = match &options.clean {
+        Some(name) if !options.clean_all => vec![name],
+        _ => tasks.keys().collect(),
+    };
+    names.sort();
+    let mut code = 0;
+
+    for name in names {
+        match tasks.get(name) {
+            Some(task) => {
+                resolve_command_substitutions(task, variables);
+                clean_task(task, variables);
+            }
+            None => {
+                error!("unknown task {}", name);
+                code = 2;
+            }
+        }
+    }
+
+    code
+}
+
+/// Opens the file every run is appended to, creating its directory
+fn open_transcript(path: &str) -> std::io::Result<()> {
+    if TRANSCRIPT.get().is_some() {
+        return Ok(());
+    }
+
+    if let Some(dir) = Path::new(path)
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let _ = TRANSCRIPT.set(Mutex::new(file));
+
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let args: Vec<String> = std::env::args().map(|arg| shell_quote(&arg)).collect();
+    write_transcript(&format!(
+        "run started in {}: {}",
+        cwd.display(),
+        args.join(" ")
+    ));
+    Ok(())
+}
+
+/// Appends an entry to the `--log-file`, if there is one, with the time
+fn write_transcript(entry: &str) {
+    if let Some(transcript) = TRANSCRIPT.get() {
+        if let Ok(mut transcript) = transcript.lock() {
+            let _ = writeln!(transcript, "[{}] {}", get_timestamp(), entry);
+        }
+    }
+}
+
+/// The `--log-file` entry for a command about to run, with its working
+/// directory and the environment variables it sets
+fn get_transcript_command(task_name: &str, command: &Command) -> String {
+    let mut entry = format!("task({}): running {}", task_name, get_echo_line(command));
+
+    if let Some(dir) = command.get_current_dir() {
+        entry += &format!("\n  cwd: {}", dir.display());
+    }
+
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            entry += &format!(
+                "\n  env: {}={}",
+                key.to_string_lossy(),
+                value.to_string_lossy()
+            );
+        }
+    }
+
+    entry
+}
+
+/// The `--log-file` entry for a finished command, with all of its output
+fn get_transcript_output(task: &Task, output: &Output, duration: Duration) -> String {
+    let code = match output.status.code() {
+        Some(code) => code.to_string(),
+        None => String::from("no exit code"),
+    };
+    let mut entry = format!(
+        "task({}): exited with {} after {}",
+        task.name,
+        code,
+        format_duration(duration)
+    );
+
+    for (name, text) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+        let text = String::from_utf8_lossy(text);
+        if !text.trim().is_empty() {
+            entry += &format!("\n--- {} ---\n{}", name, text.trim_end());
+        }
+    }
+
+    entry
+}
+
+/// Prints one `--output json` event line
+fn print_event(event: &str) {
+    match EVENTS.get() {
+        Some(events) => {
+            if let Ok(mut events) = events.lock() {
+                let _ = writeln!(events, "{}", event);
+            }
+        }
+        None => println!("{}", event),
+    }
+}
+
+/// Moves everything printed to stdout over to stderr for `--output json`,
+/// so the events are the only thing left on stdout
+#[cfg(unix)]
+fn redirect_stdout_for_events() {
+    use std::os::unix::io::FromRawFd;
+
+    extern "C" {
+        fn dup(fd: i32) -> i32;
+        fn dup2(fd: i32, new_fd: i32) -> i32;
+    }
+
+    let _ = std::io::stdout().flush();
+
+    let events = unsafe { dup(1) };
+    if events < 0 {
+        return;
+    }
+
+    if unsafe { dup2(2, 1) } < 0 {
+        warn!("failed to move output to stderr for --output json");
+        return;
+    }
+
+    let _ = EVENTS.set(Mutex::new(unsafe { File::from_raw_fd(events) }));
+}
+
+/// Other output stays on stdout next to the events
+#[cfg(not(unix))]
+fn redirect_stdout_for_events() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_variables(variables: &[(&str, &str)]) -> HashMap<String, String> {
+        variables
+            .iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect()
+    }
+
+    fn get_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+
+    #[test]
+    fn tokenize_splits_on_whitespace() {
+        assert_eq!(
+            tokenize_command("  cargo   build\t--release "),
+            get_args(&["cargo", "build", "--release"])
+        );
+        assert!(tokenize_command("   ").is_empty());
+    }
+
+    #[test]
+    fn tokenize_keeps_quoted_args_together() {
+        assert_eq!(
+            tokenize_command(r#"git commit -m "first commit" --author='A B'"#),
+            get_args(&["git", "commit", "-m", "first commit", "--author=A B"])
+        );
+        assert_eq!(
+            tokenize_command(r#"echo 'no \n escapes' "a\tb""#),
+            get_args(&["echo", r"no \n escapes", "a\tb"])
+        );
+    }
+
+    #[test]
+    fn tokenize_keeps_empty_args() {
+        assert_eq!(
+            tokenize_command(r#"prog "" '' x"#),
+            get_args(&["prog", "", "", "x"])
+        );
+    }
+
+    #[test]
+    fn tokenize_handles_escaped_quotes() {
+        assert_eq!(
+            tokenize_command(r#"echo "say \"hi\"" \"bare\" a\ b"#),
+            get_args(&["echo", "say \"hi\"", "\"bare\"", "a b"])
+        );
+    }
+
+    fn get_task(config: &str) -> Task {
+        Config::parse(config).unwrap().tasks.remove(0)
+    }
+
+    fn get_tasks(config: &Config) -> HashMap<String, Task> {
+        config
+            .tasks
+            .iter()
+            .map(|task| (task.name.clone(), task.clone()))
+            .collect()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn quoted_newline_escapes_reach_the_program() {
+        let task = get_task("[t]\ncommand = printf %s \"line one\\nline two\"\n");
+        let mut command = build_command(&task, &HashMap::new(), &Options::default()).unwrap();
+
+        let output = command.output().unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "line one\nline two"
+        );
+    }
+
+    #[test]
+    fn durations_json_maps_tasks_to_seconds() {
+        let mut durations = BTreeMap::new();
+        durations.insert(String::from("test"), Duration::from_millis(2500));
+        durations.insert(String::from("build \"a\""), Duration::from_millis(125));
+
+        assert_eq!(
+            get_durations_json(&durations),
+            r#"{"build \"a\"": 0.125, "test": 2.500}"#
+        );
+        assert_eq!(get_durations_json(&BTreeMap::new()), "{}");
+    }
+
+    /// Writes files to a new directory under the system temp directory
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rbt-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (path, text) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+
+        dir
+    }
+
+    fn load_file(path: &Path, policy: MatchPolicy) -> Config {
+        let text = std::fs::read_to_string(path).unwrap();
+        let mut chain = vec![path.canonicalize().unwrap()];
+        let stack = chain.clone();
+        load_config(path, &text, &mut chain, &stack, policy).unwrap()
+    }
+
+    fn get_commands(config: &Config, name: &str) -> Vec<String> {
+        config.task(name).unwrap().commands().to_vec()
+    }
+
+    #[test]
+    fn export_script_runs_the_queue_in_order() {
+        // A task's directory has to exist, so this one is the crate's
+        let config = Config::parse(
+            "$target = release\n\
+             [prep]\ncommand = mkdir -p out\n\
+             [build]\ncwd = src\nenv.CC = clang\ncommand = make \"$target build\"\n",
+        )
+        .unwrap();
+        let tasks = get_tasks(&config);
+        let mut variables = config.variables;
+        let queue = VecDeque::from(get_args(&["prep", "build"]));
+
+        let script = get_export_script(&queue, &tasks, &mut variables, &Options::default());
+
+        assert_eq!(
+            script,
+            "#!/bin/sh\nset -e\n\
+             \n# task(prep)\nmkdir -p out\n\
+             \n# task(build)\n(cd src && CC=clang make 'release build')\n"
+        );
+    }
+
+    /// Parses a config whose tasks run in `dir`, like one loaded from it
+    fn parse_in(dir: &Path, config: &str) -> Config {
+        let mut config = Config::parse(config).unwrap();
+
+        for task in &mut config.tasks {
+            task.base_dir = dir.to_path_buf();
+            task.cwd = Some(String::from("."));
+        }
+
+        config
+    }
+
+    fn run_tasks(config: Config, names: &[&str]) -> i32 {
+        let options = Options {
+            tasks: get_args(names),
+            ..Options::default()
+        };
+        run_config(config, &options)
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn clean_removes_paths_that_the_next_run_recreates() {
+        let dir = write_files("clean", &[]);
+        let config = parse_in(
+            &dir,
+            "$log = build.log\n\
+             [build]\nshell = true\nclean = out, $log\n\
+             command = mkdir -p out/bin && touch out/bin/app build.log\n",
+        );
+        let tasks = get_tasks(&config);
+
+        assert_eq!(run_tasks(config.clone(), &["build"]), 0);
+        assert!(dir.join("out/bin/app").is_file() && dir.join("build.log").is_file());
+
+        let options = Options {
+            clean: Some(String::from("build")),
+            ..Options::default()
+        };
+        assert_eq!(
+            clean_tasks(&tasks, &mut config.variables.clone(), &options),
+            0
+        );
+        assert!(!dir.join("out").exists() && !dir.join("build.log").exists());
+
+        assert_eq!(run_tasks(config, &["build"]), 0);
+        assert!(dir.join("out/bin/app").is_file() && dir.join("build.log").is_file());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn command_file_runs_as_a_script_with_variables() {
+        let dir = write_files(
+            "command-file",
+            &[
+                (
+                    "ci/scripts/build.sh",
+                    "mkdir -p out\necho $greeting > out/hello.txt\nfalse\necho not reached > out/late.txt\n",
+                ),
+                (
+                    "ci/build.cfg",
+                    "$greeting = hello\n[build]\ncwd = .\ncommand_file = scripts/build.sh\n",
+                ),
+                ("missing.cfg", "[build]\ncwd = .\ncommand_file = scripts/build.sh\n"),
+            ],
+        );
+
+        // The script is found next to the config, not in the current directory
+        let config = load_file(&dir.join("ci/build.cfg"), MatchPolicy::Last);
+        assert_eq!(run_tasks(config, &["build"]), 1);
+        let hello = std::fs::read_to_string(dir.join("ci/out/hello.txt")).unwrap();
+        assert_eq!(hello, "hello\n");
+        assert!(!dir.join("ci/out/late.txt").exists());
+
+        let missing = load_file(&dir.join("missing.cfg"), MatchPolicy::Last);
+        assert_eq!(run_tasks(missing, &["build"]), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn command_variables_only_run_once_when_used() {
+        let dir = write_files("lazy", &[]);
+        let mut variables = get_variables(&[
+            ("$unused", &format!("$(touch {}/unused)", dir.display())),
+            (
+                "$used",
+                &format!(
+                    "$(sh -c \"echo run >> {}/runs; echo value\")",
+                    dir.display()
+                ),
+            ),
+        ]);
+        let task = get_task("[t]\ncommand = echo $used\n");
+
+        resolve_command_substitutions(&task, &mut variables);
+        resolve_command_substitutions(&task, &mut variables);
+
+        assert_eq!(variables["$used"], "value");
+        assert_eq!(std::fs::read_to_string(dir.join("runs")).unwrap(), "run\n");
+        assert!(!dir.join("unused").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn command_variables_run_when_used_inside_arguments_and_options() {
+        let mut variables = get_variables(&[
+            ("$sha", "$(echo abc123)"),
+            ("$rev", "$(echo def456)"),
+            ("$dir", "$(echo src)"),
+        ]);
+        let task = get_task(
+            "[t]\nenv.REV = $rev\ncwd = ${dir}\n\
+             command = sh -c \"echo sha=$sha ${sha} $$REV; pwd\"\n",
+        );
+
+        resolve_command_substitutions(&task, &mut variables);
+        assert_eq!(variables["$sha"], "abc123");
+        assert_eq!(variables["$rev"], "def456");
+        assert_eq!(variables["$dir"], "src");
+
+        let mut command = build_command(&task, &variables, &Options::default()).unwrap();
+        let output = String::from_utf8(command.output().unwrap().stdout).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "sha=abc123 abc123 def456");
+        assert!(lines[1].ends_with("/src"));
+    }
+
+    #[test]
+    fn unused_variables_are_reported() {
+        let config = Config::parse(
+            "$out = bin\n$app = $out/app\n$orphan = nothing\n$jobs = 4\n\
+             [settings]\nretries = $jobs\n\
+             [build]\ncommand = cc -o $app main.c\n",
+        )
+        .unwrap();
+
+        let unused = get_unused_variables(&config.variables, &get_tasks(&config), &config.settings);
+
+        assert_eq!(unused, vec!["$orphan"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn check_programs_flags_missing_programs() {
+        let config = Config::parse(
+            "[found]\ncommand = sh -c true\n\
+             [missing]\ncommand = rbt-no-such-program --fast\n\
+             [second]\ncommand = true\ncommand = rbt-no-such-program | cat\n",
+        )
+        .unwrap();
+        let tasks = get_tasks(&config);
+        let options = Options {
+            dry_run: true,
+            check_programs: true,
+            ..Options::default()
+        };
+        let check = |names: &[&str]| {
+            let queue = VecDeque::from(get_args(names));
+            print_dry_run(&queue, &tasks, &config.variables, &options)
+        };
+
+        assert!(check(&["found"]));
+        assert!(!check(&["found", "missing"]));
+        assert!(!check(&["second"]));
+    }
+
+    #[test]
+    fn watched_configs_reload_with_their_changes() {
+        let dir = write_files(
+            "watch",
+            &[
+                ("base.cfg", "[lint]\ncommand = echo lint\n"),
+                (
+                    "build.cfg",
+                    "extends = base.cfg\n[build]\ncommand = echo old\n[execute]\nbuild\n",
+                ),
+            ],
+        );
+        let options = Options {
+            config: Some(dir.join("build.cfg").display().to_string()),
+            ..Options::default()
+        };
+        let (config, chain) = reload_config(&options).unwrap();
+        let modified = get_modified_times(&chain);
+        assert_eq!(chain.len(), 2);
+        assert_eq!(config.queue, VecDeque::from(get_args(&["build"])));
+
+        let edited = "extends = base.cfg\n[build]\ncommand = echo new\n[execute]\nlint\nbuild\n";
+        std::fs::write(dir.join("build.cfg"), edited).unwrap();
+        let later = std::time::SystemTime::now() + Duration::from_secs(5);
+        File::options()
+            .write(true)
+            .open(dir.join("build.cfg"))
+            .and_then(|file| file.set_modified(later))
+            .unwrap();
+
+        assert_ne!(get_modified_times(&chain), modified);
+        let (config, _) = reload_config(&options).unwrap();
+        assert_eq!(config.queue, VecDeque::from(get_args(&["lint", "build"])));
+        assert_eq!(get_commands(&config, "build"), vec!["echo new"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn undefined_variables_only_stop_tasks_when_required() {
+        let dir = write_files("require", &[]);
+        let config = parse_in(&dir, "[t]\ncommand = sh -c \"touch ran # $undefined\"\n");
+        let strict = Options {
+            tasks: get_args(&["t"]),
+            require_all_vars: true,
+            ..Options::default()
+        };
+
+        assert_ne!(run_config(config.clone(), &strict), 0);
+        assert!(!dir.join("ran").exists());
+
+        assert_eq!(run_tasks(config, &["t"]), 0);
+        assert!(dir.join("ran").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn color_choice_beats_no_color_and_the_terminal() {
+        for no_color in [false, true] {
+            for is_terminal in [false, true] {
+                assert!(should_use_color(ColorChoice::Always, no_color, is_terminal));
+                assert!(!should_use_color(ColorChoice::Never, no_color, is_terminal));
+                assert_eq!(
+                    should_use_color(ColorChoice::Auto, no_color, is_terminal),
+                    is_terminal && !no_color
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tasks_over_their_memory_limit_fail() {
+        let hog = "shell = true\ncommand = x=$(head -c 100000000 /dev/zero | tr '\\0' a)\n";
+        let run = |config: &str| {
+            let task = get_task(config);
+            let mut command = build_command(&task, &HashMap::new(), &Options::default()).unwrap();
+            command.output().unwrap().status.success()
+        };
+
+        assert!(run(&format!("[hog]\n{}", hog)));
+        assert!(!run(&format!("[hog]\nmax_memory = 64M\n{}", hog)));
+        assert!(run("[small]\nmax_memory = 64M\ncommand = sh -c true\n"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn tasks_get_a_temp_dir_removed_unless_kept() {
+        let dir = write_files("temp", &[]);
+        let config = parse_in(
+            &dir,
+            "[t]\nshell = true\n\
+             command = test -d $TMPDIR_TASK && test $TMPDIR_TASK = $$TMPDIR_TASK && echo $TMPDIR_TASK > seen\n",
+        );
+        let seen = || PathBuf::from(std::fs::read_to_string(dir.join("seen")).unwrap().trim());
+
+        assert_eq!(run_tasks(config.clone(), &["t"]), 0);
+        let temp = seen();
+        assert!(temp.starts_with(std::env::temp_dir()));
+        assert!(!temp.exists());
+
+        let keep = Options {
+            tasks: get_args(&["t"]),
+            keep_temp: true,
+            ..Options::default()
+        };
+        assert_eq!(run_config(config, &keep), 0);
+        let temp = seen();
+        assert!(temp.is_dir());
+        std::fs::remove_dir_all(temp).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn programs_are_found_in_the_task_path() {
+        // A link instead of a new script, which can't run while another
+        // test's child still holds it open for writing
+        let dir = write_files("path", &[]);
+        let program = dir.join("bin/rbt-only-here");
+        std::fs::create_dir(dir.join("bin")).unwrap();
+        std::os::unix::fs::symlink("/bin/echo", &program).unwrap();
+        let task = get_task(&format!(
+            "[t]\npath = {}/bin:$PATH\ncommand = rbt-only-here found\n",
+            dir.display()
+        ));
+
+        let mut command = build_command(&task, &HashMap::new(), &Options::default()).unwrap();
+        let output = command.output().unwrap();
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "found\n");
+        assert_eq!(find_program("rbt-only-here", &command), Some(program));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fail_on_stderr_fails_tasks_that_write_to_stderr() {
+        let config = Config::parse(
+            "[warns]\ncommand = sh -c \"echo careful >&2\"\n\
+             [blank]\ncommand = sh -c \"echo >&2\"\n\
+             [allowed]\nfail_on_stderr = false\ncommand = sh -c \"echo careful >&2\"\n",
+        )
+        .unwrap();
+        let run = |name: &str, fail_on_stderr| {
+            let options = Options {
+                tasks: get_args(&[name]),
+                fail_on_stderr,
+                ..Options::default()
+            };
+            run_config(config.clone(), &options)
+        };
+
+        assert_eq!(run("warns", false), 0);
+        assert_ne!(run("warns", true), 0);
+        assert_eq!(run("blank", true), 0);
+        assert_eq!(run("allowed", true), 0);
+    }
+
+    #[test]
+    fn config_dirs_merge_their_files_in_name_order() {
+        let dir = write_files(
+            "config-dir",
+            &[
+                (
+                    "20-ci.cfg",
+                    "[build]\ncommand = echo ci\n[deploy]\ncommand = echo deploy\n",
+                ),
+                (
+                    "10-base.cfg",
+                    "$mode = debug\n[build]\ncommand = echo base\n[lint]\ncommand = echo lint\n",
+                ),
+                ("notes.txt", "[ignored]\ncommand = echo no\n"),
+            ],
+        );
+
+        let (config, watched) = load_config_dir(&dir, &Options::default()).unwrap();
+
+        assert_eq!(get_commands(&config, "build"), vec!["echo ci"]);
+        assert_eq!(get_commands(&config, "lint"), vec!["echo lint"]);
+        assert_eq!(get_commands(&config, "deploy"), vec!["echo deploy"]);
+        assert!(config.task("ignored").is_none());
+        assert_eq!(config.variables["$mode"], "debug");
+        assert_eq!(watched.len(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tasks_without_a_command_for_this_platform_build_nothing() {
+        let task = get_task("[a]\nplatform = linux\ncommand.windows = x.bat\n");
+
+        assert!(task.commands.is_empty());
+        assert!(build_command(&task, &HashMap::new(), &Options::default()).is_none());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn limits_are_not_applied_to_ssh_or_docker() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stand-ins for the clients that print the limits they got
+        let script = "#!/bin/sh\nulimit -v\nnice\n";
+        let dir = write_files("client-limits", &[("ssh", script), ("docker", script)]);
+        for client in ["ssh", "docker"] {
+            let permissions = std::fs::Permissions::from_mode(0o755);
+            std::fs::set_permissions(dir.join(client), permissions).unwrap();
+        }
+        let unlimited = Command::new(dir.join("ssh")).output().unwrap().stdout;
+
+        for place in ["runs_on = deploy@host", "container = rust:1.78"] {
+            let task = get_task(&format!(
+                "[t]\n{}\nmax_memory = 64M\npriority = low\ncommand = true\n",
+                place
+            ));
+            assert_eq!(get_limit_options(&task), vec!["max_memory", "priority"]);
+
+            let mut command = build_command(&task, &HashMap::new(), &Options::default()).unwrap();
+            command.env("PATH", format!("{}:/usr/bin:/bin", dir.display()));
+
+            assert_eq!(command.output().unwrap().stdout, unlimited, "{}", place);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+//! Prints the tool's own messages, like `info:` and `warn:` lines, at the
+//! level picked with `-q`, `-v` or `-vv`
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// How much the tool prints about itself
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    /// Only warnings and errors, from `-q`
+    Quiet,
+    Normal,
+    /// Also each task's command and working directory, from `-v`
+    Verbose,
+    /// Also each task's environment, from `-vv`
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Dims `info:` and `debug:` lines so task results stand out
+const DIM: &str = "2";
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+pub fn set_color(color: bool) {
+    COLOR.store(color, Ordering::SeqCst);
+}
+
+/// Whether messages of this level are printed
+pub fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::SeqCst) >= level as u8
+}
+
+fn print(prefix: &str, message: &str, dim: bool) {
+    if dim && COLOR.load(Ordering::SeqCst) {
+        println!("\x1b[{}m{}: {}\x1b[0m", DIM, prefix, message);
+    } else {
+        println!("{}: {}", prefix, message);
+    }
+}
+
+pub fn info(message: &str) {
+    if enabled(Level::Normal) {
+        print("info", message, true);
+    }
+}
+
+pub fn warn(message: &str) {
+    print("warn", message, false);
+}
+
+pub fn error(message: &str) {
+    print("error", message, false);
+}
+
+/// Prints a `debug:` line with `-v`, or with `-vv` for `Level::Debug`
+pub fn debug(level: Level, message: &str) {
+    if enabled(level) {
+        print("debug", message, true);
+    }
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::info(&format!($($arg)*))
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::warn(&format!($($arg)*))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::error(&format!($($arg)*))
+    };
+}
+
+/// A `debug:` line shown with `-v`
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        $crate::log::debug($crate::log::Level::Verbose, &format!($($arg)*))
+    };
+}
+
+/// A `debug:` line shown with `-vv`
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        $crate::log::debug($crate::log::Level::Debug, &format!($($arg)*))
+    };
+}
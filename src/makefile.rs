@@ -0,0 +1,283 @@
+//! Converts simple Makefiles into the `.cfg` format for `rust-build-tool
+//! import`. Variables, rules with prerequisites and recipes are
+//! converted. Anything else, like conditionals and pattern rules, is
+//! kept as a comment to convert by hand.
+
+use std::collections::HashSet;
+
+/// A rule read from the Makefile
+struct Rule {
+    target: String,
+    prerequisites: Vec<String>,
+    recipe: Vec<String>,
+}
+
+/// Converts a Makefile into a build config. The first target becomes
+/// the execute queue, like make's default goal
+pub fn to_cfg(text: &str) -> String {
+    let mut variables = String::new();
+    let mut skipped = String::new();
+    let mut rules: Vec<Rule> = Vec::new();
+    let mut phony = HashSet::new();
+    // The targets of the rule whose recipe is being read
+    let mut current: Vec<String> = Vec::new();
+
+    for line in join_continued_lines(text) {
+        // Recipe lines start with a tab and belong to the rules above them
+        if let Some(command) = line.strip_prefix('\t') {
+            let command = command.trim().trim_start_matches(['@', '-', '+']).trim();
+
+            if command.is_empty() || command.starts_with('#') {
+                continue;
+            }
+
+            // Every target of a rule like `a b: c` gets the recipe
+            for rule in rules
+                .iter_mut()
+                .filter(|rule| current.contains(&rule.target))
+            {
+                rule.recipe.push(String::from(command));
+            }
+            continue;
+        }
+
+        let line = remove_comment(&line);
+        let trimmed = line.trim();
+
+        if trimmed.is_empty() {
+            continue;
+        }
+
+        current.clear();
+
+        if let Some((name, value)) = get_assignment(trimmed) {
+            let value = convert_references(value);
+
+            // Make functions like $(wildcard *.c) would run as commands
+            if value.contains("$(") {
+                skipped += &format!("# not converted: {}\n", trimmed);
+            } else {
+                variables += &format!("${} = {}\n", name, value);
+            }
+            continue;
+        }
+
+        let (targets, prerequisites) = match trimmed.split_once(':') {
+            Some((targets, rest)) if !rest.starts_with('=') => (targets, rest),
+            _ => {
+                skipped += &format!("# not converted: {}\n", trimmed);
+                continue;
+            }
+        };
+
+        let (prerequisites, inline) = match prerequisites.split_once(';') {
+            Some((prerequisites, recipe)) => (prerequisites, Some(recipe.trim())),
+            None => (prerequisites, None),
+        };
+        // Prerequisites after a | only have to exist, which tasks can't say
+        let prerequisites = prerequisites.split('|').next().unwrap_or("");
+        let prerequisites: Vec<String> = prerequisites
+            .split_whitespace()
+            .map(convert_references)
+            .collect();
+
+        let targets: Vec<&str> = targets.split_whitespace().collect();
+
+        if targets == [".PHONY"] {
+            phony.extend(prerequisites);
+            continue;
+        }
+
+        if targets
+            .iter()
+            .any(|t| t.starts_with('.') || t.contains('%'))
+        {
+            skipped += &format!("# not converted: {}\n", trimmed);
+            continue;
+        }
+
+        for target in targets {
+            let target = convert_references(target);
+            let mut rule = Rule {
+                target: target.clone(),
+                prerequisites: prerequisites.clone(),
+                recipe: inline.into_iter().map(String::from).collect(),
+            };
+
+            // A target listed again adds to the rule that is already there
+            match rules.iter_mut().find(|r| r.target == target) {
+                Some(existing) => {
+                    existing.prerequisites.append(&mut rule.prerequisites);
+                    existing.recipe.append(&mut rule.recipe);
+                }
+                None => rules.push(rule),
+            }
+
+            current.push(target);
+        }
+    }
+
+    let mut cfg = String::new();
+    cfg += &skipped;
+    cfg += &variables;
+
+    if let Some(first) = rules.first() {
+        cfg += &format!("\n[execute]\n{}\n", first.target);
+    }
+
+    let targets: HashSet<&str> = rules.iter().map(|rule| rule.target.as_str()).collect();
+
+    for rule in &rules {
+        cfg += &rule_to_cfg(rule, &targets, phony.contains(&rule.target));
+    }
+
+    cfg
+}
+
+/// Writes one rule as a task. Prerequisites that are other targets
+/// become dependencies and the rest are input files
+fn rule_to_cfg(rule: &Rule, targets: &HashSet<&str>, is_phony: bool) -> String {
+    let mut section = format!("\n[{}]\n", rule.target);
+
+    let (depends, inputs): (Vec<&String>, Vec<&String>) = rule
+        .prerequisites
+        .iter()
+        .partition(|name| targets.contains(name.as_str()));
+
+    if rule.recipe.is_empty() {
+        // Tasks need a command, so a rule that only groups others prints
+        section += &format!("command = echo {}\n", rule.target);
+    }
+
+    for command in &rule.recipe {
+        section += &format!("command = {}\n", expand_automatic(command, rule));
+    }
+
+    // Recipes are shell commands, so the shell runs them like make does
+    if rule.recipe.iter().any(|command| needs_shell(command)) {
+        section += "shell = true\n";
+    }
+
+    if !depends.is_empty() {
+        let names: Vec<&str> = depends.iter().map(|name| name.as_str()).collect();
+        section += &format!("depends = {}\n", names.join(", "));
+    }
+
+    // Like make, a file target is only rebuilt when its inputs change
+    if !is_phony && !inputs.is_empty() {
+        let files: Vec<&str> = inputs.iter().map(|name| name.as_str()).collect();
+        section += &format!("inputs = {}\n", files.join(", "));
+        section += &format!("outputs = {}\n", rule.target);
+    }
+
+    section
+}
+
+/// Joins lines ending with a backslash with the line after them
+fn join_continued_lines(text: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+
+    for line in text.lines() {
+        match line.strip_suffix('\\') {
+            Some(start) => {
+                current += start.trim_end();
+                current.push(' ');
+            }
+            None => {
+                current += line;
+                lines.push(std::mem::take(&mut current));
+            }
+        }
+    }
+
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    lines
+}
+
+/// Cuts a line at a `#` comment
+fn remove_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    }
+}
+
+/// Splits a variable assignment like `CC = gcc`, `CC := gcc` or
+/// `CC ?= gcc` into the name and value
+fn get_assignment(line: &str) -> Option<(&str, &str)> {
+    let equals = line.find('=')?;
+    let colon = line.find(':');
+
+    // `a: b=c` is a rule, not an assignment
+    if colon.is_some_and(|colon| colon + 1 < equals) {
+        return None;
+    }
+
+    let name = line[..equals].trim_end_matches([':', '?', '+', '!']).trim();
+    let is_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+
+    if !is_name {
+        return None;
+    }
+
+    Some((name, line[equals + 1..].trim()))
+}
+
+/// Replaces `$(NAME)` with `${NAME}`, and `$$` with `$`
+fn convert_references(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(i) = rest.find('$') {
+        result += &rest[..i];
+        rest = &rest[i + 1..];
+
+        let close = match rest.chars().next() {
+            Some('(') => ')',
+            Some('{') => '}',
+            Some('$') => {
+                result.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+            _ => {
+                result.push('$');
+                continue;
+            }
+        };
+
+        match rest.find(close) {
+            // Functions like $(wildcard *.c) are left for the shell
+            Some(end) if !rest[1..end].contains(' ') => {
+                result += &format!("${{{}}}", &rest[1..end]);
+                rest = &rest[end + 1..];
+            }
+            _ => result.push('$'),
+        }
+    }
+
+    result + rest
+}
+
+/// Replaces make's automatic variables `$@`, `$<` and `$^` in a recipe
+fn expand_automatic(command: &str, rule: &Rule) -> String {
+    let command = convert_references(command);
+    let first = rule.prerequisites.first().map_or("", String::as_str);
+
+    command
+        .replace("$@", &rule.target)
+        .replace("$<", first)
+        .replace("$^", &rule.prerequisites.join(" "))
+}
+
+/// Whether a recipe line uses the shell, like pipes or `&&`
+fn needs_shell(command: &str) -> bool {
+    command.contains(['|', '>', '<', ';', '&', '`']) || command.contains("$(")
+}
@@ -0,0 +1,302 @@
+//! Checks a build config line by line, reporting malformed lines with
+//! their position instead of silently ignoring them
+
+use super::{get_line_key_value, get_section_header, is_current_platform};
+use std::collections::HashMap;
+use std::fmt;
+
+/// The options a task section understands. `env.NAME`, `matrix.NAME`
+/// and `command.OS` are accepted as well
+const TASK_KEYS: &[&str] = &[
+    "command",
+    "command_file",
+    "description",
+    "platform",
+    "filter",
+    "retries",
+    "retry_delay",
+    "clean",
+    "depends",
+    "alias",
+    "before",
+    "after",
+    "watch",
+    "inputs",
+    "outputs",
+    "allow_failure",
+    "interactive",
+    "on_interrupt",
+    "shell",
+    "glob",
+    "cwd",
+    "max_memory",
+    "max_cpu",
+    "timeout",
+    "success_if",
+    "path",
+    "log_file",
+    "capture",
+    "when",
+    "runs_on",
+    "container",
+    "priority",
+    "cpu_affinity",
+    "fail_on_stderr",
+    "stdout",
+    "stderr",
+    "silent",
+];
+
+/// A problem found at a line and column of a config
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub is_error: bool,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// The kind of section a line is in
+#[derive(PartialEq)]
+enum Section {
+    Top,
+    Task,
+    Execute,
+    Pairs,
+    /// `[profile.NAME]`, whose variables only override others
+    Profile,
+}
+
+/// The task section being checked, to report it if it has no command
+struct OpenTask<'a> {
+    name: &'a str,
+    line: usize,
+    has_command: bool,
+    platforms: Vec<&'a str>,
+}
+
+/// Finds malformed lines, unknown task options, duplicate variables and
+/// tasks, and tasks without a command
+pub fn check_config(config: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut section = Section::Top;
+    let mut current: Option<OpenTask> = None;
+    let mut variables: HashMap<&str, usize> = HashMap::new();
+    let mut tasks: HashMap<&str, usize> = HashMap::new();
+    let mut execute_ended = false;
+
+    let mut report = |line: usize, column: usize, message: String, is_error: bool| {
+        diagnostics.push(Diagnostic {
+            line,
+            column,
+            message,
+            is_error,
+        })
+    };
+
+    for (index, raw) in config.lines().enumerate() {
+        let line = index + 1;
+        let text = remove_trailing_comment(raw);
+        let trimmed = text.trim();
+        let column = text.len() - text.trim_start().len() + 1;
+
+        if trimmed.starts_with('#') {
+            continue;
+        }
+
+        // The execute queue ends at the first empty line
+        if trimmed.is_empty() {
+            execute_ended = section == Section::Execute;
+            continue;
+        }
+
+        if trimmed.starts_with('[') {
+            if let Some(task) = current.take() {
+                check_task_command(&task, &mut report);
+            }
+
+            let name = match get_section_header(trimmed) {
+                Some(name) => name,
+                None => {
+                    let message = String::from("expected `]` to close the section header");
+                    report(line, column + trimmed.len(), message, true);
+                    continue;
+                }
+            };
+
+            execute_ended = false;
+            section = match name {
+                "execute" => Section::Execute,
+                "settings" | "exit_codes" => Section::Pairs,
+                _ if name.starts_with("execute:") || name.starts_with("group.") => Section::Execute,
+                _ if name.starts_with("profile.") => Section::Profile,
+                _ => Section::Task,
+            };
+
+            if section == Section::Task {
+                if let Some(first) = tasks.insert(name, line) {
+                    let message = format!("task({}) is already defined on line {}", name, first);
+                    report(line, column, message, false);
+                }
+
+                current = Some(OpenTask {
+                    name,
+                    line,
+                    has_command: false,
+                    platforms: Vec::new(),
+                });
+            }
+
+            continue;
+        }
+
+        // Variables can be defined in any section. Profiles only override
+        // them, so they aren't duplicates
+        if trimmed.starts_with('$') && section != Section::Profile {
+            match get_line_key_value(trimmed) {
+                Some((name, _)) => {
+                    if let Some(first) = variables.insert(name, line) {
+                        let message =
+                            format!("variable {} is already defined on line {}", name, first);
+                        report(line, column, message, false);
+                    }
+                }
+                None => {
+                    let (offset, message) = expected_equals(trimmed);
+                    report(line, column + offset, message, true);
+                }
+            }
+
+            continue;
+        }
+
+        match section {
+            Section::Top => match get_line_key_value(trimmed) {
+                Some(("extends", _)) | Some(("include", _)) => {}
+                _ if trimmed.starts_with("@include ") => {}
+                _ => {
+                    let message = String::from("expected a variable like `$name = value`");
+                    report(line, column, message, true);
+                }
+            },
+            Section::Execute if execute_ended => {
+                let message = String::from(
+                    "this line is ignored, the execute section ends at the empty line before it",
+                );
+                report(line, column, message, false);
+            }
+            Section::Execute => match get_line_key_value(trimmed) {
+                Some(("parallel", _)) | None => {}
+                Some((key, _)) => report(line, column, format!("unknown option `{}`", key), false),
+            },
+            Section::Pairs | Section::Profile => {
+                if get_line_key_value(trimmed).is_none() {
+                    let (offset, message) = expected_equals(trimmed);
+                    report(line, column + offset, message, true);
+                }
+            }
+            Section::Task => {
+                let key = match get_line_key_value(trimmed) {
+                    Some((key, _)) => key,
+                    None => {
+                        let (offset, message) = expected_equals(trimmed);
+                        report(line, column + offset, message, true);
+                        continue;
+                    }
+                };
+
+                let task = match current.as_mut() {
+                    Some(task) => task,
+                    None => continue,
+                };
+
+                if key == "command" || key == "command_file" || key.starts_with("command.") {
+                    task.has_command = true;
+                } else if key == "platform" {
+                    task.platforms = text.split_once('=').map_or(Vec::new(), |(_, value)| {
+                        value
+                            .split(',')
+                            .map(|p| p.trim().trim_matches('"'))
+                            .collect()
+                    });
+                } else if !TASK_KEYS.contains(&key)
+                    && !key.starts_with("env.")
+                    && !key.starts_with("matrix.")
+                {
+                    report(
+                        line,
+                        column,
+                        format!("unknown task option `{}`", key),
+                        false,
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(task) = current.take() {
+        check_task_command(&task, &mut report);
+    }
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+    diagnostics
+}
+
+/// Reports a task section that doesn't run anything on this platform
+fn check_task_command(task: &OpenTask, report: &mut impl FnMut(usize, usize, String, bool)) {
+    let other_platform =
+        !task.platforms.is_empty() && !task.platforms.iter().any(|p| is_current_platform(p));
+
+    if !task.has_command && !other_platform {
+        report(
+            task.line,
+            1,
+            format!("task({}) has no command", task.name),
+            false,
+        );
+    }
+}
+
+/// The message for a line that should have been a `key = value` pair,
+/// and how far into the line the `=` was expected
+fn expected_equals(line: &str) -> (usize, String) {
+    let key = line.split_whitespace().next().unwrap_or(line);
+    (key.len(), format!("expected `=` after key `{}`", key))
+}
+
+/// Removes comments, which start with a # at the start of a line or
+/// after whitespace. Lines that only hold a comment are removed.
+pub fn strip_comments(config: &str) -> String {
+    config
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .map(|line| format!("{}\n", remove_trailing_comment(line)))
+        .collect()
+}
+
+/// Cuts a line at a ` #` outside of quotes, so `"#1"` and `a#b` are kept
+fn remove_trailing_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' => quote = Some(c),
+            // An apostrophe inside a word, like in don't, isn't a quote
+            None if c == '\'' && (previous.is_whitespace() || previous == '=') => quote = Some(c),
+            None if c == '#' && previous.is_whitespace() => return line[..i].trim_end(),
+            None => {}
+        }
+
+        previous = c;
+    }
+
+    line
+}
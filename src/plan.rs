@@ -0,0 +1,628 @@
+//! Plans a run: which tasks run and in what order. Names on the command
+//! line, aliases and groups become task names, and every task gets the
+//! tasks it depends on before it and its `after` hooks behind it.
+
+use super::{append_task_args, is_current_platform, matches_glob, Config, Options, Task};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::process::Command;
+
+/// Builds the queue a run of the command line executes: the named
+/// `[execute:NAME]` sections or tasks asked for instead of the execute
+/// queue, shuffled with `--shuffle` and ordered by dependencies, without
+/// the tasks for other platforms or, with `--affected`, the tasks that
+/// no changed file affects. Arguments after `--` are added to the
+/// tasks asked for. Errors are reported and returned as the exit code
+pub fn build_queue(
+    mut queue: VecDeque<String>,
+    named_queues: &HashMap<String, VecDeque<String>>,
+    groups: &HashMap<String, VecDeque<String>>,
+    tasks: &mut HashMap<String, Task>,
+    options: &Options,
+) -> Result<VecDeque<String>, i32> {
+    // Named sections run back to back instead of the [execute] section
+    if !options.run.is_empty() {
+        queue.clear();
+
+        for name in &options.run {
+            match named_queues.get(name) {
+                Some(named) => queue.extend(named.iter().cloned()),
+                None => {
+                    error!("unknown execute section {}", name);
+                    return Err(2);
+                }
+            }
+        }
+    }
+
+    // Aliases and groups can be used anywhere a task name can
+    queue = resolve_task_names(&queue, tasks, groups, &mut Vec::new());
+
+    // Tasks named on the command line run instead of the execute queue
+    if !options.tasks.is_empty() {
+        queue = resolve_task_names(&options.tasks, tasks, groups, &mut Vec::new());
+
+        if let Some(unknown) = queue.iter().find(|name| !tasks.contains_key(*name)) {
+            error!("unknown task {}", unknown);
+            print_task_suggestions(unknown, tasks);
+            return Err(2);
+        }
+    } else if let Some(unknown) = queue.iter().find(|name| !tasks.contains_key(*name)) {
+        error!("the execute queue has unknown task {}", unknown);
+        print_task_suggestions(unknown, tasks);
+        return Err(1);
+    }
+
+    // Arguments after -- only go to the tasks that were asked for, not
+    // to their dependencies
+    if !options.task_args.is_empty() {
+        for name in &queue {
+            if let Some(task) = tasks.get_mut(name) {
+                append_task_args(task);
+            }
+        }
+    }
+
+    if queue.is_empty() {
+        return Ok(queue);
+    }
+
+    if let Some(seed) = options.shuffle_seed {
+        // The seed is always printed so the order can be reproduced
+        info!("shuffling execute queue with seed {}", seed);
+        shuffle_queue(&mut queue, seed);
+    }
+
+    // Dependencies are added after shuffling so they still run first
+    queue = order_by_dependencies(&queue, tasks).map_err(|e| {
+        error!("{}", e);
+        1
+    })?;
+
+    if let Some(rev) = &options.affected {
+        let changed = get_changed_files(rev).map_err(|e| {
+            error!("couldn't list the files changed since {}: {}", rev, e);
+            1
+        })?;
+
+        let total = queue.len();
+        queue = get_affected_tasks(&queue, tasks, &changed);
+        info!(
+            "{} of {} task(s) affected by changes since {}",
+            queue.len(),
+            total,
+            rev
+        );
+    }
+
+    verbose!("running {}", Vec::from(queue.clone()).join(", "));
+
+    // Tasks for other platforms, or with only other platforms' commands,
+    // are left out
+    queue.retain(|name| {
+        let task = match tasks.get(name) {
+            Some(task) => task,
+            None => return true,
+        };
+
+        let skipped = if !task.platforms.is_empty()
+            && !task.platforms.iter().any(|p| is_current_platform(p))
+        {
+            format!("only runs on {}", task.platforms.join(", "))
+        } else if task.commands.is_empty() && task.command_file.is_none() {
+            format!("no command for {}", std::env::consts::OS)
+        } else {
+            return true;
+        };
+
+        if !options.quiet_on_success {
+            println!("task({}): skipped, {}", name, skipped);
+        }
+        false
+    });
+
+    Ok(queue)
+}
+
+/// The files changed since a git revision, including uncommitted and
+/// untracked ones, relative to the current directory
+fn get_changed_files(rev: &str) -> Result<Vec<String>, String> {
+    let mut files = Vec::new();
+
+    let commands = vec![
+        vec!["diff", "--name-only", "--relative", rev, "--"],
+        vec!["ls-files", "--others", "--exclude-standard"],
+    ];
+
+    for args in commands {
+        let output = Command::new("git")
+            .args(&args)
+            .output()
+            .map_err(|e| e.to_string())?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(stderr.lines().next().unwrap_or_default().to_string());
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        files.extend(stdout.lines().map(String::from));
+    }
+
+    Ok(files)
+}
+
+/// Keeps the tasks of an ordered queue that read a changed file, the
+/// tasks that need them, directly or not, and their `after` hooks
+pub fn get_affected_tasks(
+    queue: &VecDeque<String>,
+    tasks: &HashMap<String, Task>,
+    changed: &[String],
+) -> VecDeque<String> {
+    let mut affected: HashSet<&str> = HashSet::new();
+
+    // Dependencies come first in the queue, so one pass is enough
+    for name in queue {
+        let task = match tasks.get(name) {
+            Some(task) => task,
+            None => continue,
+        };
+
+        let hooked = tasks
+            .values()
+            .any(|other| other.after.contains(name) && affected.contains(other.name.as_str()));
+
+        if reads_changed_file(task, changed)
+            || hooked
+            || get_prerequisites(task).any(|dependency| affected.contains(dependency.as_str()))
+        {
+            affected.insert(name);
+        }
+    }
+
+    queue
+        .iter()
+        .filter(|name| affected.contains(name.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Whether one of the changed files matches the task's `inputs`
+fn reads_changed_file(task: &Task, changed: &[String]) -> bool {
+    task.inputs.iter().any(|input| {
+        let pattern = task.base_dir.join(input);
+        let pattern = pattern.to_string_lossy();
+        let pattern = pattern.trim_start_matches("./");
+        changed.iter().any(|file| matches_glob(pattern, file))
+    })
+}
+
+/// Replaces aliases with the names of their tasks and groups with their
+/// tasks. Unknown names are kept so they can be reported
+pub fn resolve_task_names<'a>(
+    names: impl IntoIterator<Item = &'a String>,
+    tasks: &HashMap<String, Task>,
+    groups: &HashMap<String, VecDeque<String>>,
+    expanding: &mut Vec<String>,
+) -> VecDeque<String> {
+    let mut resolved = VecDeque::new();
+
+    for name in names {
+        if tasks.contains_key(name) {
+            resolved.push_back(name.clone());
+        } else if let Some(task) = tasks.values().find(|task| task.aliases.contains(name)) {
+            resolved.push_back(task.name.clone());
+        } else if let Some(members) = groups.get(name) {
+            // A group listing itself, directly or not, adds nothing more
+            if expanding.contains(name) {
+                continue;
+            }
+
+            expanding.push(name.clone());
+            resolved.extend(resolve_task_names(members, tasks, groups, expanding));
+            expanding.pop();
+        } else {
+            resolved.push_back(name.clone());
+        }
+    }
+
+    resolved
+}
+
+/// Creates a seed from the current time for shuffling
+pub fn get_random_seed() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_nanos() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Orders the queue so every task runs after the tasks it depends on and
+/// its `before` tasks, and is followed by its `after` tasks. These are
+/// added to the queue, and each task only runs once
+pub fn order_by_dependencies(
+    queue: &VecDeque<String>,
+    tasks: &HashMap<String, Task>,
+) -> Result<VecDeque<String>, String> {
+    let mut ordered = VecDeque::new();
+    let mut visiting = Vec::new();
+
+    for name in queue {
+        visit_dependencies(name, tasks, &mut visiting, &mut ordered)?;
+    }
+
+    Ok(ordered)
+}
+
+/// Adds a task's dependencies and then the task itself to `ordered`.
+/// `visiting` holds the path to the current task, to find cycles
+fn visit_dependencies(
+    name: &str,
+    tasks: &HashMap<String, Task>,
+    visiting: &mut Vec<String>,
+    ordered: &mut VecDeque<String>,
+) -> Result<(), String> {
+    if ordered.iter().any(|done| done == name) {
+        return Ok(());
+    }
+
+    if let Some(start) = visiting.iter().position(|v| v == name) {
+        let mut cycle = visiting[start..].to_vec();
+        cycle.push(String::from(name));
+        return Err(format!("dependency cycle: {}", cycle.join(" -> ")));
+    }
+
+    let task = match tasks.get(name) {
+        Some(task) => task,
+        None => return Err(format!("unknown task {}", name)),
+    };
+
+    visiting.push(String::from(name));
+
+    for dependency in get_prerequisites(task) {
+        if !tasks.contains_key(dependency) {
+            return Err(format!(
+                "task({}) depends on unknown task {}",
+                name, dependency
+            ));
+        }
+
+        visit_dependencies(dependency, tasks, visiting, ordered)?;
+    }
+
+    visiting.pop();
+    ordered.push_back(String::from(name));
+
+    for hook in &task.after {
+        if !tasks.contains_key(hook) {
+            return Err(format!("task({}) has unknown after task {}", name, hook));
+        }
+
+        visit_dependencies(hook, tasks, visiting, ordered)?;
+    }
+
+    Ok(())
+}
+
+/// The tasks that have to succeed before a task runs
+pub fn get_prerequisites(task: &Task) -> impl Iterator<Item = &String> {
+    task.depends.iter().chain(&task.before)
+}
+
+/// Whether a task has to wait for another one to finish first
+pub fn waits_for(task: &Task, other: &Task) -> bool {
+    get_prerequisites(task).any(|name| *name == other.name) || other.after.contains(&task.name)
+}
+
+/// Prints the defined tasks with a name close to an unknown one, or all
+/// of them if none are close
+fn print_task_suggestions(unknown: &str, tasks: &HashMap<String, Task>) {
+    let mut known: Vec<&str> = tasks.keys().map(|name| name.as_str()).collect();
+    known.sort();
+
+    // Allow about one typo for every three letters
+    let limit = (unknown.chars().count() / 3).max(1);
+    let mut close: Vec<(usize, &str)> = known
+        .iter()
+        .map(|name| (get_edit_distance(unknown, name), *name))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    close.sort();
+
+    if close.is_empty() {
+        info!("known tasks: {}", known.join(", "));
+    } else {
+        let names: Vec<&str> = close.iter().take(3).map(|(_, name)| *name).collect();
+        info!("did you mean {}?", names.join(", "));
+    }
+}
+
+/// The number of single character edits that turn `a` into `b`, where
+/// swapping two neighbouring characters counts as one edit
+fn get_edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+
+            distances[i][j] = distance;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+/// Shuffles the execute queue. The same seed always produces the same
+/// order, so a failing order can be reproduced with `--shuffle <seed>`
+fn shuffle_queue(queue: &mut VecDeque<String>, seed: u64) {
+    let mut state = seed;
+
+    // splitmix64, which is good enough for ordering tasks
+    let mut next_random = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+
+    // Fisher-Yates shuffle
+    for i in (1..queue.len()).rev() {
+        let j = (next_random() % (i as u64 + 1)) as usize;
+        queue.swap(i, j);
+    }
+}
+
+/// The tasks to run and the order they run in, with dependencies and
+/// `before` and `after` hooks added
+pub struct Plan {
+    queue: VecDeque<String>,
+}
+
+impl Plan {
+    /// Orders the given tasks, or the execute queue if none are given
+    pub fn new(config: &Config, names: &[&str]) -> Result<Plan, String> {
+        let tasks: HashMap<String, Task> = config
+            .tasks
+            .iter()
+            .map(|task| (task.name.clone(), task.clone()))
+            .collect();
+
+        let queue = if names.is_empty() {
+            config.queue.clone()
+        } else {
+            names.iter().map(|name| String::from(*name)).collect()
+        };
+
+        let queue = resolve_task_names(&queue, &tasks, &config.groups, &mut Vec::new());
+        let queue = order_by_dependencies(&queue, &tasks)?;
+        Ok(Plan { queue })
+    }
+
+    /// The task names in the order they run
+    pub fn tasks(&self) -> impl Iterator<Item = &str> {
+        self.queue.iter().map(|name| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_tasks(config: &str) -> HashMap<String, Task> {
+        get_tasks_of(&Config::parse(config).unwrap())
+    }
+
+    fn get_tasks_of(config: &Config) -> HashMap<String, Task> {
+        config
+            .tasks
+            .iter()
+            .map(|task| (task.name.clone(), task.clone()))
+            .collect()
+    }
+
+    fn get_queue(names: &[&str]) -> VecDeque<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    #[test]
+    fn root_task_runs_its_whole_graph_in_order() {
+        let mut tasks = get_tasks(
+            "[fetch]\ncommand = echo fetch\n\
+             [lint]\ncommand = echo lint\n\
+             [compile]\ndepends = fetch\ncommand = echo compile\n\
+             [build]\ndepends = compile\ncommand = echo build\n\
+             [test]\ndepends = build, fetch\ncommand = echo test\n\
+             [all]\ndepends = lint, test, build\ncommand = echo done\n",
+        );
+        let groups = HashMap::new();
+        let options = Options {
+            tasks: vec![String::from("all")],
+            ..Options::default()
+        };
+
+        let queue = build_queue(
+            VecDeque::new(),
+            &HashMap::new(),
+            &groups,
+            &mut tasks,
+            &options,
+        );
+
+        assert_eq!(
+            queue,
+            Ok(get_queue(&[
+                "lint", "fetch", "compile", "build", "test", "all"
+            ]))
+        );
+    }
+
+    #[test]
+    fn named_sections_run_back_to_back() {
+        let config = Config::parse(
+            "[lint]\ncommand = echo lint\n[build]\ncommand = echo build\n\
+             [test]\ndepends = build\ncommand = echo test\n[deploy]\ncommand = echo deploy\n\
+             [execute]\ndeploy\n[execute:check]\nlint\ntest\n[execute:ship]\nbuild\ndeploy\n",
+        )
+        .unwrap();
+        let mut tasks = get_tasks_of(&config);
+        let options = Options {
+            run: vec![String::from("check"), String::from("ship")],
+            ..Options::default()
+        };
+
+        let queue = build_queue(
+            config.queue.clone(),
+            &config.named_queues,
+            &config.groups,
+            &mut tasks,
+            &options,
+        );
+        assert_eq!(queue, Ok(get_queue(&["lint", "build", "test", "deploy"])));
+
+        let unknown = Options {
+            run: vec![String::from("nope")],
+            ..Options::default()
+        };
+        let queue = build_queue(
+            config.queue.clone(),
+            &config.named_queues,
+            &config.groups,
+            &mut tasks,
+            &unknown,
+        );
+        assert_eq!(queue, Err(2));
+    }
+
+    #[test]
+    fn shuffle_is_the_same_for_a_seed() {
+        let queue = get_queue(&["a", "b", "c", "d", "e", "f", "g", "h"]);
+        let shuffle = |seed| {
+            let mut queue = queue.clone();
+            shuffle_queue(&mut queue, seed);
+            queue
+        };
+
+        assert_eq!(shuffle(42), shuffle(42));
+        assert_ne!(shuffle(42), queue);
+        assert_ne!(shuffle(42), shuffle(7));
+
+        let mut sorted = Vec::from(shuffle(42));
+        sorted.sort();
+        assert_eq!(VecDeque::from(sorted), queue);
+    }
+
+    #[test]
+    fn shuffled_tasks_still_run_after_their_dependencies() {
+        let tasks = get_tasks(
+            "[a]\ncommand = echo a\n[b]\ndepends = a\ncommand = echo b\n\
+             [c]\ndepends = b\ncommand = echo c\n[d]\ncommand = echo d\n\
+             [e]\ndepends = a\ncommand = echo e\n[f]\ncommand = echo f\n",
+        );
+
+        for seed in 0..20 {
+            let mut queue = get_queue(&["c", "f", "e", "d", "b", "a"]);
+            shuffle_queue(&mut queue, seed);
+            let queue = order_by_dependencies(&queue, &tasks).unwrap();
+            let position = |name| queue.iter().position(|n| n == name).unwrap();
+
+            assert_eq!(queue.len(), 6);
+            assert!(position("a") < position("b"));
+            assert!(position("b") < position("c"));
+            assert!(position("a") < position("e"));
+        }
+    }
+
+    const CONFIG: &str = "[gen]\ninputs = schema/*.json\ncommand = echo gen\n\
+                          [lib]\ndepends = gen\ninputs = src/**\ncommand = echo lib\n\
+                          [app]\ndepends = lib\ncommand = echo app\n\
+                          [docs]\ninputs = docs/*.md\ncommand = echo docs\n\
+                          [all]\ndepends = app, docs\ncommand = echo all\n";
+
+    fn get_affected(changed: &[&str]) -> VecDeque<String> {
+        let tasks = get_tasks(CONFIG);
+        let queue = order_by_dependencies(&get_queue(&["all"]), &tasks).unwrap();
+        let changed: Vec<String> = changed.iter().map(|file| String::from(*file)).collect();
+        get_affected_tasks(&queue, &tasks, &changed)
+    }
+
+    #[test]
+    fn changed_inputs_pull_in_dependents() {
+        assert_eq!(
+            get_affected(&["src/net/tcp.rs"]),
+            get_queue(&["lib", "app", "all"])
+        );
+        assert_eq!(
+            get_affected(&["schema/user.json"]),
+            get_queue(&["gen", "lib", "app", "all"])
+        );
+        assert_eq!(
+            get_affected(&["docs/intro.md"]),
+            get_queue(&["docs", "all"])
+        );
+    }
+
+    #[test]
+    fn unrelated_changes_affect_nothing() {
+        assert!(get_affected(&["README.md", "docs/img/logo.png"]).is_empty());
+        assert!(get_affected(&[]).is_empty());
+    }
+
+    #[test]
+    fn affected_tasks_bring_their_after_hooks() {
+        let tasks = get_tasks(
+            "[build]\ninputs = src/*\ncommand = echo build\nafter = notify\n\
+             [notify]\ncommand = echo notify\n",
+        );
+        let queue = order_by_dependencies(&get_queue(&["build"]), &tasks).unwrap();
+        let changed = vec![String::from("src/main.c")];
+
+        assert_eq!(
+            get_affected_tasks(&queue, &tasks, &changed),
+            get_queue(&["build", "notify"])
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tasks_without_a_command_for_this_platform_are_skipped() {
+        for config in [
+            "[a]\nplatform = linux\ncommand.windows = x.bat\n[b]\ncommand = echo b\n",
+            "[a]\ncommand.windows = x.bat\n[b]\ncommand = echo b\n",
+        ] {
+            let mut tasks = get_tasks(config);
+            let options = Options {
+                tasks: vec![String::from("a"), String::from("b")],
+                ..Options::default()
+            };
+
+            let queue = build_queue(
+                VecDeque::new(),
+                &HashMap::new(),
+                &HashMap::new(),
+                &mut tasks,
+                &options,
+            );
+            assert_eq!(queue, Ok(get_queue(&["b"])));
+        }
+    }
+}
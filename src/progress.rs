@@ -0,0 +1,86 @@
+//! The line shown on a terminal while a task runs, like
+//! `⠹ [3/7] task(link): running (4.2s)`. The spinner and time update in
+//! place, output the task prints goes above it, and the task's result
+//! replaces it when it ends.
+
+use super::LINE_OPEN;
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+const FRAMES: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Erases the line the cursor is on
+pub const CLEAR_LINE: &str = "\r\x1b[K";
+
+/// The running task's label, like `[3/7] task(link)`, and when it started
+static STATUS: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+/// Held while the line is drawn, so output and redraws don't mix
+static DRAW: Mutex<()> = Mutex::new(());
+static TICKER: Once = Once::new();
+
+/// Shows the line for a task that just started. It stays until the next
+/// line printed with `line_start` replaces it
+pub fn start(label: String) {
+    if let Ok(mut status) = STATUS.lock() {
+        *status = Some((label, Instant::now()));
+    }
+
+    LINE_OPEN.store(true, Ordering::SeqCst);
+    redraw();
+
+    TICKER.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(Duration::from_millis(100));
+            redraw();
+        });
+    });
+}
+
+/// Prints a line of a task's output above the progress line. Returns
+/// false, printing nothing, when the progress line isn't shown
+pub fn print_above(print_line: impl FnOnce()) -> bool {
+    let _draw = DRAW.lock();
+
+    if !LINE_OPEN.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    // Flushed first, since stderr lines don't go through stdout
+    let mut stdout = std::io::stdout();
+    let _ = write!(stdout, "{}", CLEAR_LINE);
+    let _ = stdout.flush();
+    print_line();
+    draw();
+    true
+}
+
+fn redraw() {
+    let _draw = DRAW.lock();
+    draw();
+}
+
+/// Draws the line while it is still open. Stdout is locked before
+/// checking, so a result printed at the same time always comes after
+fn draw() {
+    let mut stdout = std::io::stdout().lock();
+
+    if !LINE_OPEN.load(Ordering::SeqCst) {
+        return;
+    }
+
+    if let Ok(Some((label, start))) = STATUS.lock().as_deref() {
+        let elapsed = start.elapsed();
+        let frame = FRAMES[(elapsed.as_millis() / 100) as usize % FRAMES.len()];
+        let _ = write!(
+            stdout,
+            "{}{} {}: running ({:.1}s)",
+            CLEAR_LINE,
+            frame,
+            label,
+            elapsed.as_secs_f64()
+        );
+        let _ = stdout.flush();
+    }
+}
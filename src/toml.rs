@@ -0,0 +1,509 @@
+//! Reads `build.toml` configs by turning them into the `.cfg` format.
+//! Only the parts of TOML a build config needs are supported: tables,
+//! dotted keys, strings, numbers, booleans, arrays and inline tables.
+
+/// A parsed TOML value
+enum Value {
+    String(String),
+    /// Numbers and booleans, kept as they were written
+    Literal(String),
+    Array(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+
+/// A table and the keys set in it, in the order they were written
+struct Table {
+    path: Vec<String>,
+    pairs: Vec<(String, Value)>,
+}
+
+/// Converts a TOML config into the `.cfg` format. Errors have the line
+/// they were found on
+pub fn to_cfg(text: &str) -> Result<String, String> {
+    let tables = Parser::new(text).parse()?;
+    let mut variables = String::new();
+    let mut sections = String::new();
+    let mut top = String::new();
+    let mut execute = String::new();
+
+    for table in &tables {
+        let path: Vec<&str> = table.path.iter().map(|p| p.as_str()).collect();
+
+        match path.as_slice() {
+            [] => {
+                for (key, value) in &table.pairs {
+                    match (key.as_str(), value) {
+                        ("extends", value) => top += &format!("extends = {}\n", to_text(value)?),
+                        ("include", Value::Array(paths)) => {
+                            for path in paths {
+                                top += &format!("include = {}\n", to_text(path)?);
+                            }
+                        }
+                        ("include", value) => top += &format!("include = {}\n", to_text(value)?),
+                        ("parallel", value) => {
+                            execute += &format!("parallel = {}\n", to_text(value)?)
+                        }
+                        ("execute", Value::Array(names)) => {
+                            for name in names {
+                                execute += &format!("{}\n", to_text(name)?);
+                            }
+                        }
+                        (key, _) => return Err(format!("unknown key `{}`", key)),
+                    }
+                }
+            }
+            ["variables"] => {
+                for (key, value) in &table.pairs {
+                    let name = key.trim_start_matches('$');
+                    variables += &format!("${} = {}\n", name, to_text(value)?);
+                }
+            }
+            ["settings"] | ["exit_codes"] => {
+                sections += &format!("[{}]\n", path[0]);
+                for (key, value) in &table.pairs {
+                    sections += &format!("{} = {}\n", key, to_text(value)?);
+                }
+            }
+            ["profile"] => {
+                for (name, value) in &table.pairs {
+                    let pairs = match value {
+                        Value::Table(pairs) => pairs,
+                        _ => return Err(format!("profile.{} has to be a table", name)),
+                    };
+
+                    sections += &format!("[profile.{}]\n", name);
+                    for (key, value) in pairs {
+                        let key = key.trim_start_matches('$');
+                        sections += &format!("${} = {}\n", key, to_text(value)?);
+                    }
+                }
+            }
+            ["group"] => {
+                for (name, value) in &table.pairs {
+                    let members = match value {
+                        Value::Array(members) => members,
+                        _ => return Err(format!("group.{} has to be an array", name)),
+                    };
+
+                    // The empty line ends the list of tasks
+                    sections += &format!("[group.{}]\n", name);
+                    for member in members {
+                        sections += &format!("{}\n", to_text(member)?);
+                    }
+                    sections += "\n";
+                }
+            }
+            ["tasks"] => {
+                for (name, value) in &table.pairs {
+                    match value {
+                        Value::Table(pairs) => sections += &task_to_cfg(name, pairs)?,
+                        _ => return Err(format!("tasks.{} has to be a table", name)),
+                    }
+                }
+            }
+            _ => return Err(format!("unknown table [{}]", table.path.join("."))),
+        }
+    }
+
+    let mut cfg = top + &variables + &sections;
+    if !execute.is_empty() {
+        cfg += &format!("[execute]\n{}", execute);
+    }
+
+    Ok(cfg)
+}
+
+/// Writes one task as a `.cfg` section. Tables inside the task, like
+/// `env`, become dotted keys like `env.CC`
+fn task_to_cfg(name: &str, pairs: &[(String, Value)]) -> Result<String, String> {
+    let mut section = format!("[{}]\n", name);
+
+    for (key, value) in pairs {
+        match (key.as_str(), value) {
+            ("command" | "commands", Value::Array(commands)) => {
+                for command in commands {
+                    section += &format!("command = {}\n", to_text(command)?);
+                }
+            }
+            ("commands", value) => section += &format!("command = {}\n", to_text(value)?),
+            (_, Value::Table(inner)) => {
+                for (inner_key, value) in inner {
+                    section += &format!("{}.{} = {}\n", key, inner_key, to_text(value)?);
+                }
+            }
+            _ => section += &format!("{} = {}\n", key, to_text(value)?),
+        }
+    }
+
+    Ok(section)
+}
+
+/// Writes a value the way the `.cfg` format expects it. Arrays become
+/// comma separated lists
+fn to_text(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(text) if text.contains('\n') => {
+            Err(String::from("values can't have more than one line"))
+        }
+        Value::String(text) | Value::Literal(text) => Ok(text.clone()),
+        Value::Array(values) => {
+            let items: Result<Vec<String>, String> = values.iter().map(to_text).collect();
+            Ok(items?.join(", "))
+        }
+        Value::Table(_) => Err(String::from("a table can't be used here")),
+    }
+}
+
+/// Reads TOML text one character at a time, keeping track of the line
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Parser {
+        Parser {
+            chars: text.chars().collect(),
+            position: 0,
+            line: 1,
+        }
+    }
+
+    /// Reads every table in the document, merging keys set in the same one
+    fn parse(mut self) -> Result<Vec<Table>, String> {
+        self.parse_tables()
+            .map_err(|e| format!("line {}: {}", self.line, e))
+    }
+
+    fn parse_tables(&mut self) -> Result<Vec<Table>, String> {
+        let mut tables = vec![Table {
+            path: Vec::new(),
+            pairs: Vec::new(),
+        }];
+        let mut current = Vec::new();
+
+        loop {
+            self.skip_blank_lines();
+
+            match self.peek() {
+                None => return Ok(tables),
+                Some('[') if self.peek_at(1) == Some('[') => {
+                    return Err(String::from("arrays of tables aren't supported"));
+                }
+                Some('[') => {
+                    self.position += 1;
+                    current = self.parse_key()?;
+                    self.expect(']')?;
+                    self.expect_line_end()?;
+                }
+                Some(_) => {
+                    let mut key = self.parse_key()?;
+                    self.expect('=')?;
+                    let value = self.parse_value()?;
+                    self.expect_line_end()?;
+
+                    // `a.b = 1` in [t] sets b in the table [t.a]
+                    let name = key.pop().unwrap_or_default();
+                    let mut path = current.clone();
+                    path.extend(key);
+                    insert_value(&mut tables, path, name, value)?;
+                }
+            }
+        }
+    }
+
+    /// Reads a dotted key like `tasks.build` or `"my task".env`
+    fn parse_key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+
+        loop {
+            self.skip_spaces();
+
+            let part = match self.peek() {
+                Some('"') => self.parse_basic_string()?,
+                Some('\'') => self.parse_literal_string()?,
+                _ => {
+                    let start = self.position;
+                    while self.peek().is_some_and(|c| {
+                        c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '$'
+                    }) {
+                        self.position += 1;
+                    }
+
+                    if start == self.position {
+                        return Err(String::from("expected a key"));
+                    }
+
+                    self.chars[start..self.position].iter().collect()
+                }
+            };
+
+            parts.push(part);
+            self.skip_spaces();
+
+            if self.peek() != Some('.') {
+                return Ok(parts);
+            }
+
+            self.position += 1;
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        self.skip_spaces();
+
+        match self.peek() {
+            Some('"') if self.peek_at(1) == Some('"') && self.peek_at(2) == Some('"') => {
+                Err(String::from("multi-line strings aren't supported"))
+            }
+            Some('"') => Ok(Value::String(self.parse_basic_string()?)),
+            Some('\'') => Ok(Value::String(self.parse_literal_string()?)),
+            Some('[') => self.parse_array(),
+            Some('{') => self.parse_inline_table(),
+            _ => {
+                let start = self.position;
+                while self
+                    .peek()
+                    .is_some_and(|c| !c.is_whitespace() && !",]}#".contains(c))
+                {
+                    self.position += 1;
+                }
+
+                let literal: String = self.chars[start..self.position].iter().collect();
+                let is_number = literal
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_digit() || c == '+' || c == '-');
+
+                if literal == "true" || literal == "false" || is_number {
+                    Ok(Value::Literal(literal))
+                } else {
+                    Err(format!("expected a value, found `{}`", literal))
+                }
+            }
+        }
+    }
+
+    /// Reads `[a, b]`, which can span lines and end with a comma
+    fn parse_array(&mut self) -> Result<Value, String> {
+        self.position += 1;
+        let mut values = Vec::new();
+
+        loop {
+            self.skip_blank_lines();
+
+            if self.peek() == Some(']') {
+                self.position += 1;
+                return Ok(Value::Array(values));
+            }
+
+            values.push(self.parse_value()?);
+            self.skip_blank_lines();
+
+            match self.peek() {
+                Some(',') => self.position += 1,
+                Some(']') => {}
+                _ => return Err(String::from("expected `,` or `]` in the array")),
+            }
+        }
+    }
+
+    /// Reads `{ a = 1, b = 2 }` on one line
+    fn parse_inline_table(&mut self) -> Result<Value, String> {
+        self.position += 1;
+        let mut pairs = Vec::new();
+
+        loop {
+            self.skip_spaces();
+
+            if self.peek() == Some('}') {
+                self.position += 1;
+                return Ok(Value::Table(pairs));
+            }
+
+            let key = self.parse_key()?.join(".");
+            self.expect('=')?;
+            pairs.push((key, self.parse_value()?));
+            self.skip_spaces();
+
+            match self.peek() {
+                Some(',') => self.position += 1,
+                Some('}') => {}
+                _ => return Err(String::from("expected `,` or `}` in the inline table")),
+            }
+        }
+    }
+
+    /// Reads a string in double quotes, with backslash escapes
+    fn parse_basic_string(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut text = String::new();
+
+        loop {
+            match self.next() {
+                Some('"') => return Ok(text),
+                Some('\\') => {
+                    let escaped = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => self.parse_unicode_escape()?,
+                        _ => return Err(String::from("unknown escape in string")),
+                    };
+                    text.push(escaped);
+                }
+                Some('\n') | None => return Err(String::from("unterminated string")),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    /// Reads the four hex digits of a `\uXXXX` escape
+    fn parse_unicode_escape(&mut self) -> Result<char, String> {
+        let digits: String = (0..4).filter_map(|_| self.next()).collect();
+
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| format!("invalid unicode escape \\u{}", digits))
+    }
+
+    /// Reads a string in single quotes, which has no escapes
+    fn parse_literal_string(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut text = String::new();
+
+        loop {
+            match self.next() {
+                Some('\'') => return Ok(text),
+                Some('\n') | None => return Err(String::from("unterminated string")),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_spaces();
+
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(format!("expected `{}`", expected))
+        }
+    }
+
+    /// Only a comment can follow a value or header on its line
+    fn expect_line_end(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+
+        match self.peek() {
+            None | Some('\n') | Some('#') => Ok(()),
+            Some(c) => Err(format!("unexpected `{}` after the value", c)),
+        }
+    }
+
+    /// Skips spaces and tabs, but not newlines
+    fn skip_spaces(&mut self) {
+        while self
+            .peek()
+            .is_some_and(|c| c == ' ' || c == '\t' || c == '\r')
+        {
+            self.position += 1;
+        }
+    }
+
+    /// Skips whitespace, newlines and comments
+    fn skip_blank_lines(&mut self) {
+        loop {
+            match self.peek() {
+                Some('#') => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.position += 1;
+                    }
+                }
+                Some(c) if c.is_whitespace() => {
+                    self.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+
+        if c == '\n' {
+            self.line += 1;
+        }
+
+        Some(c)
+    }
+}
+
+/// Sets a key in the table at `path`, creating the table if needed.
+/// Tables below the first level, like [tasks.build], are stored as table
+/// values of their parent so they keep their order
+fn insert_value(
+    tables: &mut Vec<Table>,
+    mut path: Vec<String>,
+    name: String,
+    value: Value,
+) -> Result<(), String> {
+    let inner: Vec<String> = if path.is_empty() {
+        Vec::new()
+    } else {
+        path.split_off(1)
+    };
+
+    let table = match tables.iter().position(|t| t.path == path) {
+        Some(index) => &mut tables[index],
+        None => {
+            tables.push(Table {
+                path,
+                pairs: Vec::new(),
+            });
+            tables.last_mut().unwrap()
+        }
+    };
+
+    let mut pairs = &mut table.pairs;
+
+    for part in inner {
+        let index = match pairs.iter().position(|(key, _)| *key == part) {
+            Some(index) => index,
+            None => {
+                pairs.push((part, Value::Table(Vec::new())));
+                pairs.len() - 1
+            }
+        };
+
+        if !matches!(pairs[index].1, Value::Table(_)) {
+            return Err(format!("{} is already set to a value", pairs[index].0));
+        }
+
+        pairs = match &mut pairs[index].1 {
+            Value::Table(inner) => inner,
+            _ => unreachable!(),
+        };
+    }
+
+    if pairs.iter().any(|(key, _)| *key == name) {
+        return Err(format!("{} is defined more than once", name));
+    }
+
+    pairs.push((name, value));
+    Ok(())
+}
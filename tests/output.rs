@@ -0,0 +1,105 @@
+//! Runs the binary with its output redirected, the way CI and log files
+//! see it, and checks what it prints
+
+#![cfg(unix)]
+
+use std::process::Command;
+
+/// Writes `config` to the build.cfg of a new directory and runs the tool
+/// there with `args`. Returns the exit code and everything printed to
+/// stdout and stderr
+fn run(name: &str, config: &str, args: &[&str]) -> (i32, String, String) {
+    let dir = std::env::temp_dir().join(format!("rbt-output-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("build.cfg"), config).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust-build-tool"))
+        .args(args)
+        .current_dir(&dir)
+        .env_remove("NO_COLOR")
+        .output()
+        .unwrap();
+
+    std::fs::remove_dir_all(dir).unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.code().unwrap(), stdout, stderr)
+}
+
+#[test]
+fn redirected_status_lines_are_plain() {
+    let config = "[fetch]\ncommand = echo fetched\n\
+                  [build]\ncommand = sleep 0.3\n\
+                  [test]\ncommand = sh -c \"echo oops >&2; exit 3\"\n\
+                  [execute]\nfetch\nbuild\ntest\n";
+
+    for args in [&[][..], &["--keep-going", "-async"][..]] {
+        let (code, stdout, _) = run("plain", config, args);
+
+        assert_eq!(code, 3);
+        assert!(!stdout.contains('\r'), "{:?}", stdout);
+        assert!(!stdout.contains("\x1b["), "{:?}", stdout);
+
+        let lines: Vec<&str> = stdout.lines().collect();
+        for name in ["fetch", "build", "test"] {
+            assert!(lines.contains(&format!("task({}): started", name).as_str()));
+        }
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("task(build): finished in ")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("task(test): failed after ")));
+    }
+}
+
+#[test]
+fn quiet_runs_print_nothing_when_every_task_passes() {
+    let config = "[fetch]\ncommand = echo fetched\n\
+                  [build]\ncommand = sh -c \"echo warning >&2\"\n\
+                  [execute]\nfetch\nbuild\n";
+
+    for args in [
+        &["--quiet-on-success"][..],
+        &["--quiet-on-success", "-async"][..],
+    ] {
+        let (code, stdout, stderr) = run("quiet", config, args);
+
+        assert_eq!(code, 0);
+        assert_eq!(stdout, "");
+        assert_eq!(stderr, "");
+    }
+}
+
+#[test]
+fn silent_tasks_print_their_status_but_no_output() {
+    let config = "[noisy]\nsilent = true\ncommand = sh -c \"echo chatter; echo grumble >&2\"\n\
+                  [broken]\nsilent = true\ncommand = sh -c \"echo chatter; echo grumble >&2; exit 4\"\n\
+                  [execute]\nnoisy\nbroken\n";
+
+    let (code, stdout, stderr) = run("silent", config, &["--keep-going"]);
+    let output = stdout.clone() + &stderr;
+
+    assert_eq!(code, 4);
+    assert!(
+        !output.contains("chatter") && !output.contains("grumble"),
+        "{}",
+        output
+    );
+
+    let lines: Vec<&str> = stdout.lines().collect();
+    for name in ["noisy", "broken"] {
+        assert!(lines.contains(&format!("task({}): started", name).as_str()));
+    }
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("task(noisy): finished in ")));
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("task(broken): failed after ")));
+    assert!(lines.contains(&"summary:"));
+    assert!(lines
+        .iter()
+        .any(|line| line.trim().starts_with("broken  failed")));
+}